@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Creating a million accounts in a database file, then looking accounts up
+//! by number. PINs are hashed once up front: Argon2 is slow on purpose, and
+//! this measures the database. Run with `cargo bench`; `BANK_BENCH_ACCOUNTS`
+//! changes the number of accounts.
+
+use banking_system::database::Bank;
+use banking_system::error::{BankError, BankResult};
+use banking_system::luhn::AccountNumber;
+use banking_system::pin;
+use banking_system::storage::Storage;
+use rand::seq::SliceRandom;
+use std::time::Instant;
+
+/// Accounts created per transaction
+const BATCH: usize = 10_000;
+const LOOKUPS: usize = 100_000;
+
+fn main() -> BankResult<()> {
+    let accounts: usize = std::env::var("BANK_BENCH_ACCOUNTS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1_000_000);
+    let dir = std::env::temp_dir().join(format!("bank-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("cannot create the bench directory");
+    let mut bank = Bank::open(dir.join("bank.s3db"))?;
+    let pin_hash = pin::hash("591732");
+
+    let start = Instant::now();
+    let mut numbers = Vec::with_capacity(accounts);
+    let mut collisions = 0;
+    while numbers.len() < accounts {
+        let storage = bank.storage_mut();
+        storage.begin()?;
+        for _ in 0..BATCH.min(accounts - numbers.len()) {
+            let number = loop {
+                let number = AccountNumber::default().to_string();
+                match storage.insert_account(&number, &pin_hash, 0) {
+                    Err(BankError::AccountExists) => collisions += 1,
+                    result => break result.map(|_| number)?,
+                }
+            };
+            numbers.push(number);
+        }
+        storage.commit()?;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "created {} accounts in {:.2?} ({:.0}/s), {} number collisions",
+        accounts,
+        elapsed,
+        accounts as f64 / elapsed.as_secs_f64(),
+        collisions
+    );
+
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    for _ in 0..LOOKUPS {
+        let number = numbers.choose(&mut rng).expect("no accounts");
+        bank.fetch_account(number)?;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "looked up {} accounts in {:.2?} ({:.1} µs each)",
+        LOOKUPS,
+        elapsed,
+        elapsed.as_secs_f64() * 1e6 / LOOKUPS as f64
+    );
+
+    drop(bank);
+    std::fs::remove_dir_all(&dir).expect("cannot remove the bench directory");
+    Ok(())
+}
@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: Unlicense
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generating the gRPC service from `proto/bank.proto`, with the protoc
+/// shipped as a crate unless `PROTOC` points to another one
+#[cfg(feature = "grpc")]
+fn grpc() {
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is bundled for this platform");
+        std::env::set_var("PROTOC", protoc);
+    }
+    tonic_prost_build::compile_protos("proto/bank.proto").expect("proto/bank.proto compiles");
+}
@@ -0,0 +1,72 @@
+/* SPDX-License-Identifier: Unlicense */
+
+/*
+ * A teller session through the C API: opens two accounts in the database
+ * given as argument, pays in, transfers, and shows how refusals come back.
+ *
+ *     cargo build
+ *     cc -I include examples/teller.c -L target/debug -lbanking_system -o teller
+ *     LD_LIBRARY_PATH=target/debug ./teller teller.s3db
+ */
+
+#include <stdio.h>
+
+#include "bank.h"
+
+/* Reports a failed call and tells whether there was one */
+static int failed(const char *what, BankCode code) {
+    if (code == BANK_CODE_OK) {
+        return 0;
+    }
+    fprintf(stderr, "%s: %s (%s)\n", what, bank_code_name(code), bank_last_error());
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s <database>\n", argv[0]);
+        return 2;
+    }
+
+    BankHandle *bank = NULL;
+    if (failed("open", bank_open(argv[1], &bank))) {
+        return 1;
+    }
+
+    char customer[BANK_ACCOUNT_CAPACITY];
+    char payee[BANK_ACCOUNT_CAPACITY];
+    uint64_t balance = 0;
+    int status = 1;
+    if (failed("create", bank_create_account(bank, "591732", customer, sizeof customer)) ||
+        failed("create", bank_create_account(bank, "382915", payee, sizeof payee))) {
+        goto done;
+    }
+    printf("opened %s and %s\n", customer, payee);
+
+    if (failed("deposit", bank_deposit(bank, customer, 150, "591732", &balance))) {
+        goto done;
+    }
+    printf("balance after deposit: %llu\n", (unsigned long long)balance);
+
+    if (failed("transfer", bank_transfer(bank, customer, payee, 40, "591732", NULL, &balance))) {
+        goto done;
+    }
+    printf("balance after transfer: %llu\n", (unsigned long long)balance);
+
+    BankCode code = bank_withdraw(bank, customer, 500, "591732", &balance);
+    if (code != BANK_CODE_INSUFFICIENT_FUNDS) {
+        failed("withdraw", code);
+        goto done;
+    }
+    printf("withdrawal refused: %s\n", bank_code_name(code));
+
+    if (failed("balance", bank_balance(bank, payee, "382915", NULL, &balance))) {
+        goto done;
+    }
+    printf("payee balance: %llu\n", (unsigned long long)balance);
+    status = 0;
+
+done:
+    bank_free(bank);
+    return status;
+}
@@ -0,0 +1,187 @@
+/* SPDX-License-Identifier: Unlicense */
+
+#ifndef BANK_H
+#define BANK_H
+
+/* Generated from src/ffi.rs; run `UPDATE_HEADER=1 cargo test --test ffi` after changing it. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Bytes a buffer needs for any account number and its NUL
+ */
+#define BANK_ACCOUNT_CAPACITY 16
+
+/**
+ * Outcome of a call. The values are part of the API and never change;
+ * new codes are only ever added.
+ */
+typedef enum BankCode {
+  BANK_CODE_OK = 0,
+  /**
+   * A pointer that has to be set was NULL
+   */
+  BANK_CODE_NULL_ARGUMENT = 1,
+  /**
+   * A string was not UTF-8
+   */
+  BANK_CODE_INVALID_UTF8 = 2,
+  /**
+   * The buffer given for the result is too small
+   */
+  BANK_CODE_BUFFER_TOO_SMALL = 3,
+  /**
+   * The bank failed in a way it did not foresee
+   */
+  BANK_CODE_PANIC = 4,
+  BANK_CODE_WRONG_PIN = 10,
+  BANK_CODE_WRONG_CODE = 11,
+  BANK_CODE_SECOND_FACTOR_REQUIRED = 12,
+  BANK_CODE_INVALID_TOKEN = 13,
+  BANK_CODE_LOCKED = 14,
+  BANK_CODE_INSUFFICIENT_FUNDS = 15,
+  BANK_CODE_LIMIT_EXCEEDED = 16,
+  BANK_CODE_ACCOUNT_NOT_FOUND = 17,
+  BANK_CODE_ACCOUNT_EXISTS = 18,
+  BANK_CODE_INVALID_AMOUNT = 19,
+  BANK_CODE_UNAVAILABLE = 20,
+  BANK_CODE_SAME_ACCOUNT = 21,
+  BANK_CODE_INVALID_RECIPIENT = 22,
+  BANK_CODE_BALANCE_REMAINING = 23,
+  BANK_CODE_WEAK_PIN = 24,
+  BANK_CODE_PIN_UNCHANGED = 25,
+  BANK_CODE_TOTP_ALREADY_ENABLED = 26,
+  BANK_CODE_TOTP_NOT_ENABLED = 27,
+  BANK_CODE_STATUS_CHANGE_NOT_ALLOWED = 28,
+  BANK_CODE_SCHEMA_TOO_NEW = 29,
+  BANK_CODE_INVALID_MIGRATION_TARGET = 30,
+  BANK_CODE_STORAGE = 31,
+  BANK_CODE_RECIPIENT_NOT_FOUND = 32,
+} BankCode;
+
+/**
+ * An open bank database, owned by the caller until `bank_free`
+ */
+typedef struct BankHandle BankHandle;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Opens the bank database at `path`, creating it if needed, and stores
+ * the handle in `*out`.
+ *
+ * # Safety
+ *
+ * `path` is a NUL-terminated string and `out` is valid for writes.
+ */
+enum BankCode bank_open(const char *path, struct BankHandle **out);
+
+/**
+ * Closes a bank from `bank_open`. NULL is ignored.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open` and is not used afterwards.
+ */
+void bank_free(struct BankHandle *bank);
+
+/**
+ * Opens an empty account with the customer's `pin` and writes its number
+ * to `account`, a buffer of `capacity` bytes; `BANK_ACCOUNT_CAPACITY` is
+ * always enough.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open`, `pin` is a NUL-terminated string, and
+ * `account` is valid for writes of `capacity` bytes.
+ */
+enum BankCode bank_create_account(struct BankHandle *bank,
+                                  const char *pin,
+                                  char *account,
+                                  size_t capacity);
+
+/**
+ * Writes the balance of `account` to `*balance`. `code` is the
+ * authenticator or recovery code of accounts enrolled in TOTP, or NULL.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open`, the strings are NUL-terminated or NULL
+ * where allowed, and `balance` is valid for writes.
+ */
+enum BankCode bank_balance(struct BankHandle *bank,
+                           const char *account,
+                           const char *pin,
+                           const char *code,
+                           uint64_t *balance);
+
+/**
+ * Pays `amount` into `account` and writes the new balance to `*balance`.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open`, the strings are NUL-terminated, and
+ * `balance` is valid for writes.
+ */
+enum BankCode bank_deposit(struct BankHandle *bank,
+                           const char *account,
+                           uint64_t amount,
+                           const char *pin,
+                           uint64_t *balance);
+
+/**
+ * Pays `amount` out of `account` and writes the new balance to `*balance`.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open`, the strings are NUL-terminated, and
+ * `balance` is valid for writes.
+ */
+enum BankCode bank_withdraw(struct BankHandle *bank,
+                            const char *account,
+                            uint64_t amount,
+                            const char *pin,
+                            uint64_t *balance);
+
+/**
+ * Moves `amount` from `from` to `to` and writes the new balance of `from`
+ * to `*balance`. `code` is needed from the TOTP threshold up on accounts
+ * enrolled in TOTP, and may be NULL otherwise.
+ *
+ * # Safety
+ *
+ * `bank` comes from `bank_open`, the strings are NUL-terminated or NULL
+ * where allowed, and `balance` is valid for writes.
+ */
+enum BankCode bank_transfer(struct BankHandle *bank,
+                            const char *from,
+                            const char *to,
+                            uint64_t amount,
+                            const char *pin,
+                            const char *code,
+                            uint64_t *balance);
+
+/**
+ * Stable name of `code`, such as `insufficient_funds`. The string is
+ * static and must not be freed.
+ */
+const char *bank_code_name(enum BankCode code);
+
+/**
+ * Message of the last failed call on this thread, or NULL after a call
+ * that went through. The string lives until the next call on the thread
+ * and must not be freed.
+ */
+const char *bank_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BANK_H */
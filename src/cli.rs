@@ -1,35 +1,235 @@
 // SPDX-License-Identifier: Unlicense
 
+use crate::database::AccountStatus;
+pub use crate::output::OutputFormat;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(name = "bank", version)]
 pub struct Opts {
+    /// Database file, instead of the one in the profile
+    #[arg(long, global = true, env = "BANK_DB")]
+    pub db: Option<PathBuf>,
+    /// Config file [default: bank.toml, if there is one]
+    #[arg(long, global = true, env = "BANK_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Profile of the config file, such as `prod` or `training`
+    #[arg(long, global = true, env = "BANK_PROFILE")]
+    pub profile: Option<String>,
+    /// Unix socket of the bank daemon, instead of the one in the profile
+    #[cfg(unix)]
+    #[arg(long, global = true, env = "BANK_SOCKET")]
+    pub socket: Option<PathBuf>,
+    /// How results and errors are printed
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
     /// Account subcommand
     #[command(subcommand)]
-    pub account: AccountOpts,
+    Account(AccountOpts),
+    /// Admin subcommand for bank staff
+    #[command(subcommand)]
+    Admin(AdminOpts),
+    /// Run the bank as a daemon that owns the database and answers the other
+    /// commands over the Unix socket given with --socket or in the profile
+    #[cfg(unix)]
+    Daemon,
+    /// Full-screen front end for branch staff, with forms instead of the numbered menu
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Serve the accounts as a JSON and GraphQL API over HTTP on localhost, and over gRPC if asked
+    #[cfg(feature = "rest")]
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080", help = "Loopback address and port to listen on")]
+        listen: std::net::SocketAddr,
+        #[arg(long, default_value_t = 15, help = "Minutes a login token stays valid")]
+        token_minutes: u64,
+        #[cfg(feature = "grpc")]
+        #[arg(long, help = "Also serve the gRPC BankService on this loopback address and port, such as 127.0.0.1:50051")]
+        grpc: Option<std::net::SocketAddr>,
+        #[cfg(feature = "graphql")]
+        #[arg(long, help = "File holding the key that lets GraphQL queries read every account")]
+        staff_key_file: Option<PathBuf>,
+    },
 }
 
+/// PINs are never taken as arguments, where they would end up in the shell
+/// history and in `ps` output. They are typed at a hidden prompt, or read one
+/// per line from the file descriptor given with `--pin-fd`.
 #[derive(Subcommand, Debug)]
-#[command(name = "account", about = "A simple bank system in Rust")]
+#[command(
+    name = "account",
+    about = "A simple bank system in Rust",
+    after_help = "Exit codes: 0 done, 1 database or other failure, 2 usage error, 3 wrong PIN or code, \
+        4 account locked, 5 insufficient funds, 6 no such account, 7 account status does not allow it, \
+        8 refused otherwise"
+)]
 pub enum AccountOpts {
     /// Login into account with account number. This will get you to the Main Menu.
     #[command(name = "login")]
     Login {
         #[arg(help = "Account number of user")]
         account: String,
-        #[arg(help = "PIN of the account")]
-        pin: String,
+        #[arg(long, help = "Read the PIN from this file descriptor instead of the terminal")]
+        pin_fd: Option<u32>,
+        #[arg(
+            long,
+            conflicts_with = "pin_fd",
+            help = "Replay the PIN, any authenticator code and the menu answers from this file, one per line"
+        )]
+        script: Option<PathBuf>,
+    },
+    /// Show the balance of an account.
+    #[command(name = "balance")]
+    Balance {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(long, help = "Read the PIN and any authenticator code from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
+    },
+    /// Deposit money into an account. Prints the new balance.
+    #[command(name = "deposit")]
+    Deposit {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(help = "Amount to deposit")]
+        amount: u64,
+        #[arg(long, help = "Read the PIN from this file descriptor instead of the terminal")]
+        pin_fd: Option<u32>,
+    },
+    /// Withdraw money from an account. Prints the new balance.
+    #[command(name = "withdraw")]
+    Withdraw {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(help = "Amount to withdraw")]
+        amount: u64,
+        #[arg(long, help = "Read the PIN from this file descriptor instead of the terminal")]
+        pin_fd: Option<u32>,
+    },
+    /// Transfer money to another account. Prints the new balance.
+    #[command(name = "transfer")]
+    Transfer {
+        #[arg(help = "Account number to transfer from")]
+        account: String,
+        #[arg(help = "Account number to transfer to")]
+        to: String,
+        #[arg(help = "Amount to transfer")]
+        amount: u64,
+        #[arg(long, help = "Read the PIN and any authenticator code from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
+    },
+    /// Show the latest postings of an account, newest first.
+    #[command(name = "history")]
+    History {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(long, default_value_t = 20, help = "Number of postings to show")]
+        limit: usize,
+        #[arg(long, help = "Read the PIN and any authenticator code from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
+    },
+    /// Close account with account number. The account and its history are kept, but it can no longer be used.
+    #[command(name = "close", alias = "delete")]
+    Close {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(long, help = "Account number to pay the remaining balance out to")]
+        payout: Option<String>,
+        #[arg(long, help = "Read the PIN from this file descriptor instead of the terminal")]
+        pin_fd: Option<u32>,
     },
-    /// Delete into account with account number. Your account will be gone forever.
-    #[command(name = "delete")]
-    Delete {
+    /// Change the PIN of an account. The old PIN is needed, then the new one.
+    #[command(name = "change-pin")]
+    ChangePin {
         #[arg(help = "Account number of user")]
         account: String,
-        #[arg(help = "PIN of the account.")]
-        pin: String,
+        #[arg(long, help = "Read the old and new PIN from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
     },
-    /// Create new account. This will have a randomly generated PIN.
+    /// Set up an authenticator app (TOTP). Logins and large transfers will then need its codes.
+    #[command(name = "enable-totp")]
+    EnableTotp {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(long, help = "Read the PIN and the first code from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
+    },
+    /// Remove the authenticator app from an account. Needs the PIN and a code.
+    #[command(name = "disable-totp")]
+    DisableTotp {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(long, help = "Read the PIN and the code from this file descriptor, one per line")]
+        pin_fd: Option<u32>,
+    },
+    /// Create new account. This will have a randomly generated PIN unless one is chosen.
     #[command(name = "create")]
-    Create,
-}
\ No newline at end of file
+    Create {
+        #[arg(long, help = "Choose the PIN. Repeated digits, sequences and birth years are refused.")]
+        choose_pin: bool,
+        #[arg(long, help = "Read the chosen PIN from this file descriptor instead of the terminal")]
+        pin_fd: Option<u32>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+#[command(name = "admin", about = "Back office operations of the bank")]
+pub enum AdminOpts {
+    /// Change the status of an account. The reason is kept in the status history.
+    #[command(name = "set-status")]
+    SetStatus {
+        #[arg(help = "Account number of user")]
+        account: String,
+        #[arg(help = "active, frozen, dormant, blocked-debit or blocked-credit")]
+        status: AccountStatus,
+        #[arg(long, help = "Why the status is changed")]
+        reason: String,
+    },
+    /// Unlock an account that was locked after too many wrong PINs.
+    #[command(name = "unlock")]
+    Unlock {
+        #[arg(help = "Account number of user")]
+        account: String,
+    },
+    /// Mark accounts without activity for a number of months as dormant.
+    #[command(name = "mark-dormant")]
+    MarkDormant {
+        #[arg(long, default_value_t = 12, help = "Months without activity")]
+        months: u32,
+    },
+    /// Upgrade the database schema. Other commands do this on their own, to the latest version.
+    #[command(name = "migrate")]
+    Migrate {
+        #[arg(long, help = "Only show the schema version and the pending migrations")]
+        status: bool,
+        #[arg(long, conflicts_with = "status", help = "Schema version to migrate to [default: latest]")]
+        to: Option<u32>,
+    },
+    /// Copy the database while the bank keeps running.
+    #[command(name = "backup")]
+    Backup {
+        #[arg(help = "File to write, or a directory for a timestamped copy")]
+        path: PathBuf,
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help = "Keep only this many timestamped copies in the directory")]
+        keep: Option<u64>,
+    },
+    /// Replace the database with a backup, once the backup passed `verify-backup`.
+    #[command(name = "restore")]
+    Restore {
+        #[arg(help = "Backup to restore")]
+        path: PathBuf,
+    },
+    /// Check the integrity, schema version and ledger of a backup.
+    #[command(name = "verify-backup")]
+    VerifyBackup {
+        #[arg(help = "Backup to check")]
+        path: PathBuf,
+    },
+}
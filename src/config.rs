@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Settings of a bank, read from a TOML file with named profiles:
+//!
+//! ```toml
+//! default_profile = "prod"
+//!
+//! [profiles.prod]
+//! db = "/var/lib/bank/bank.s3db"
+//!
+//! [profiles.training]
+//! db = "training.s3db"
+//! fees = { withdrawal = 0, transfer = 0 }
+//! lockout = { max_attempts = 10 }
+//! ```
+//!
+//! Anything a profile leaves out keeps its default. Reading the file needs
+//! the `cli` feature; the settings themselves are always there.
+
+use crate::database::LockoutPolicy;
+use crate::pin::{self, PinPolicy};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "cli")]
+mod file;
+
+#[cfg(feature = "cli")]
+pub use file::{load, ConfigError, ConfigFile, DEFAULT_CONFIG_FILE};
+
+/// Longest a login to the menu or the TUI lasts, however busy, since its
+/// token expires then
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(8 * 60 * 60);
+
+/// Caps on single operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct Limits {
+    /// Largest single withdrawal, unlimited if unset
+    pub max_withdrawal: Option<u64>,
+    /// Largest single transfer, unlimited if unset
+    pub max_transfer: Option<u64>,
+    /// Amount from which transfers need the second factor on accounts
+    /// that enrolled in TOTP
+    pub totp_transfer_threshold: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_withdrawal: None,
+            max_transfer: None,
+            totp_transfer_threshold: 1000,
+        }
+    }
+}
+
+/// Fees charged to the account money leaves, on top of the amount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct Fees {
+    pub withdrawal: u64,
+    pub transfer: u64,
+}
+
+/// One profile of the config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct Settings {
+    /// Database file
+    pub db: PathBuf,
+    /// Unix socket of a `bank daemon`. When set, commands go through the
+    /// daemon instead of opening the database themselves.
+    pub socket: Option<PathBuf>,
+    /// Inactivity after which the menu logs out
+    pub session_timeout_secs: u64,
+    pub pin_policy: PinPolicy,
+    pub lockout: LockoutPolicy,
+    pub limits: Limits,
+    pub fees: Fees,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            db: PathBuf::from("bank.s3db"),
+            socket: None,
+            session_timeout_secs: 120,
+            pin_policy: PinPolicy::default(),
+            lockout: LockoutPolicy::default(),
+            limits: Limits::default(),
+            fees: Fees::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout_secs)
+    }
+
+    /// Refusing settings the bank cannot work with, saying which and why
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pin_policy.length < pin::MIN_LENGTH {
+            return Err(format!(
+                "pin_policy.length is {}, but PINs need at least {} digits",
+                self.pin_policy.length,
+                pin::MIN_LENGTH
+            ));
+        }
+        Ok(())
+    }
+
+    /// Overriding settings from `BANK_MAX_PIN_ATTEMPTS`, `BANK_LOCKOUT_MINUTES`,
+    /// `BANK_SESSION_TIMEOUT_SECS` and `BANK_TOTP_TRANSFER_THRESHOLD`. A value
+    /// that does not parse is refused, naming the variable.
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        fn read<T: std::str::FromStr>(name: &str, setting: &mut T) -> Result<(), String>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match std::env::var(name) {
+                Ok(value) => {
+                    *setting = value.parse().map_err(|e| format!("{} is `{}`: {}", name, value, e))?;
+                    Ok(())
+                }
+                Err(std::env::VarError::NotPresent) => Ok(()),
+                Err(e) => Err(format!("{}: {}", name, e)),
+            }
+        }
+        read("BANK_MAX_PIN_ATTEMPTS", &mut self.lockout.max_attempts)?;
+        read("BANK_LOCKOUT_MINUTES", &mut self.lockout.lock_minutes)?;
+        read("BANK_SESSION_TIMEOUT_SECS", &mut self.session_timeout_secs)?;
+        read("BANK_TOTP_TRANSFER_THRESHOLD", &mut self.limits.totp_transfer_threshold)
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Reading the settings from the config file
+
+use super::Settings;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+
+/// Config file read from the current directory when none is given
+pub const DEFAULT_CONFIG_FILE: &str = "bank.toml";
+
+/// Contents of a config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile used when none is asked for
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Settings>,
+}
+
+impl ConfigFile {
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Parse)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        Self::parse(&text)
+    }
+
+    /// Settings of the profile called `name`, or else of the default profile.
+    /// Without either, the built-in defaults.
+    pub fn profile(&self, name: Option<&str>) -> Result<Settings, ConfigError> {
+        let settings = match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_string()))?,
+            None => Settings::default(),
+        };
+        settings.validate().map_err(ConfigError::Invalid)?;
+        Ok(settings)
+    }
+}
+
+/// Loading the settings of a profile from `path`, or from `bank.toml` in the
+/// current directory if there is one, and applying the environment overrides
+pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Settings, ConfigError> {
+    let file = match path {
+        Some(path) => ConfigFile::read(path)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            ConfigFile::read(Path::new(DEFAULT_CONFIG_FILE))?
+        }
+        None => ConfigFile::default(),
+    };
+    let mut settings = file.profile(profile)?;
+    settings.apply_env().map_err(ConfigError::Invalid)?;
+    Ok(settings)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    UnknownProfile(String),
+    /// A setting the bank cannot work with
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Cannot read `{}`: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "Invalid config file: {}", e),
+            ConfigError::UnknownProfile(name) => write!(f, "No profile `{}` in the config file", name),
+            ConfigError::Invalid(reason) => write!(f, "Invalid setting: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Fees;
+    use crate::database::LockoutPolicy;
+    use std::time::Duration;
+
+    const EXAMPLE: &str = r#"
+        default_profile = "prod"
+
+        [profiles.prod]
+        db = "/var/lib/bank/bank.s3db"
+        limits = { max_withdrawal = 2000 }
+        fees = { transfer = 1 }
+
+        [profiles.training]
+        db = "training.s3db"
+        session_timeout_secs = 600
+        lockout = { max_attempts = 10, lock_minutes = 0 }
+        pin_policy = { length = 4, reject_birth_years = false }
+    "#;
+
+    #[test]
+    fn profiles_fill_in_defaults() {
+        let file = ConfigFile::parse(EXAMPLE).unwrap();
+
+        let prod = file.profile(None).unwrap();
+        assert_eq!(prod.db, PathBuf::from("/var/lib/bank/bank.s3db"));
+        assert_eq!(prod.limits.max_withdrawal, Some(2000));
+        assert_eq!(prod.limits.totp_transfer_threshold, 1000);
+        assert_eq!(prod.fees, Fees { withdrawal: 0, transfer: 1 });
+        assert_eq!(prod.lockout, LockoutPolicy::default());
+
+        let training = file.profile(Some("training")).unwrap();
+        assert_eq!(training.session_timeout(), Duration::from_secs(600));
+        assert_eq!(training.lockout.max_attempts, 10);
+        assert_eq!(training.pin_policy.length, 4);
+        assert!(!training.pin_policy.reject_birth_years);
+        assert!(training.pin_policy.reject_sequences);
+    }
+
+    #[test]
+    fn unknown_profiles_and_keys_are_refused() {
+        let file = ConfigFile::parse(EXAMPLE).unwrap();
+        assert!(matches!(file.profile(Some("staging")), Err(ConfigError::UnknownProfile(_))));
+        assert!(matches!(
+            ConfigFile::parse("[profiles.prod]\nmax_withdrawl = 5"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert_eq!(ConfigFile::default().profile(None).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn short_pins_are_refused() {
+        for length in [0, 1, 3] {
+            let file = ConfigFile::parse(&format!("[profiles.p]\npin_policy = {{ length = {} }}", length)).unwrap();
+            let Err(ConfigError::Invalid(reason)) = file.profile(Some("p")) else {
+                panic!("length {} passed", length);
+            };
+            assert!(reason.contains("pin_policy.length"), "{reason}");
+        }
+    }
+}
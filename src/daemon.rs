@@ -0,0 +1,892 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The bank as a long-lived daemon that owns the database and answers
+//! JSON-RPC 2.0 on a Unix domain socket, one object per line. Calls are
+//! served one at a time, whichever connection they come from, so the
+//! daemon is the only one taking locks on the database. `bank` turns into
+//! a `Client` of it when a socket is configured.
+//!
+//! ```text
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "deposit", "params": {"account": "8536276945", "amount": 50, "pin": "364917"}}
+//! <-- {"jsonrpc": "2.0", "id": 1, "result": 150}
+//! ```
+//!
+//! Refusals and failures of the bank are errors with the code `-32000`
+//! and the `error` name and `exit_code` of `--output json` as data. The
+//! admin methods are only answered for the user running the daemon and for
+//! root; anyone else gets `-32001`. The methods that take a `pin` also take
+//! a `token` from `issue_token` instead.
+
+use crate::config::SESSION_LIFETIME;
+use crate::database::{self, AccountStatus, Auth, Bank};
+use crate::error::BankError;
+use crate::storage::{LedgerEntry, Storage};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The bank refused the call, or its database failed
+const BANK_ERROR: i64 = -32000;
+/// An admin method called by another user
+const FORBIDDEN: i64 = -32001;
+
+/// Methods of the daemon, as named in requests
+pub const METHODS: &[&str] = &[
+    "create_account",
+    "issue_token",
+    "check_balance",
+    "deposit",
+    "withdraw",
+    "transfer",
+    "history",
+    "close_account",
+    "change_pin",
+    "needs_code",
+    "begin_totp_enrollment",
+    "confirm_totp_enrollment",
+    "disable_totp",
+    "set_status",
+    "unlock_account",
+    "mark_dormant",
+];
+
+/// Methods that change accounts without their PIN, for staff only
+const ADMIN_METHODS: &[&str] = &["set_status", "unlock_account", "mark_dormant"];
+
+/// A call with its parameters, which are named after those of `Bank`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case", deny_unknown_fields)]
+enum Call {
+    /// The bank draws the PIN unless one is given
+    CreateAccount {
+        pin: Option<String>,
+    },
+    /// The token lasts `SESSION_LIFETIME`
+    IssueToken {
+        account: String,
+        pin: String,
+        code: Option<String>,
+    },
+    CheckBalance {
+        account: String,
+        pin: Option<String>,
+        token: Option<String>,
+        code: Option<String>,
+    },
+    Deposit {
+        account: String,
+        amount: u64,
+        pin: Option<String>,
+        token: Option<String>,
+    },
+    Withdraw {
+        account: String,
+        amount: u64,
+        pin: Option<String>,
+        token: Option<String>,
+    },
+    Transfer {
+        account: String,
+        to: String,
+        amount: u64,
+        pin: Option<String>,
+        token: Option<String>,
+        code: Option<String>,
+    },
+    History {
+        account: String,
+        pin: Option<String>,
+        token: Option<String>,
+        code: Option<String>,
+        limit: usize,
+    },
+    CloseAccount {
+        account: String,
+        payout: Option<String>,
+        pin: String,
+    },
+    ChangePin {
+        account: String,
+        old_pin: String,
+        new_pin: String,
+    },
+    NeedsCode {
+        account: String,
+        transfer: Option<u64>,
+    },
+    BeginTotpEnrollment {
+        account: String,
+        pin: String,
+    },
+    ConfirmTotpEnrollment {
+        account: String,
+        code: String,
+    },
+    DisableTotp {
+        account: String,
+        pin: String,
+        code: String,
+    },
+    SetStatus {
+        account: String,
+        status: AccountStatus,
+        reason: String,
+    },
+    UnlockAccount {
+        account: String,
+    },
+    MarkDormant {
+        months: u32,
+    },
+}
+
+/// Result of `create_account`. `pin` is only set when the bank chose it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewAccount {
+    pub account: String,
+    pub pin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Left out for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Refusal>,
+}
+
+/// What a client learns about a `BankError`
+#[derive(Debug, Serialize, Deserialize)]
+struct Refusal {
+    error: String,
+    exit_code: i32,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<BankError> for RpcError {
+    fn from(e: BankError) -> Self {
+        Self {
+            code: BANK_ERROR,
+            message: e.to_string(),
+            data: Some(Refusal {
+                error: e.name().to_string(),
+                exit_code: e.exit_code(),
+            }),
+        }
+    }
+}
+
+/// The `pin` or the `token` of a call, whichever of the two was given
+fn auth<'a>(pin: &'a Option<String>, token: &'a Option<String>) -> Result<Auth<'a>, RpcError> {
+    match (pin, token) {
+        (Some(pin), None) => Ok(Auth::Pin(pin)),
+        (None, Some(token)) => Ok(Auth::Token(token)),
+        _ => Err(RpcError::new(INVALID_PARAMS, "Give either a pin or a token")),
+    }
+}
+
+/// `pin` and `token` of a call for `auth`
+fn credentials(auth: Auth<'_>) -> (Option<String>, Option<String>) {
+    match auth {
+        Auth::Pin(pin) => (Some(pin.to_string()), None),
+        Auth::Token(token) => (None, Some(token.to_string())),
+    }
+}
+
+fn dispatch<S: Storage>(bank: &mut Bank<S>, call: Call) -> Result<Value, RpcError> {
+    let result = match call {
+        Call::CreateAccount { pin: Some(pin) } => {
+            let (number, ()) = database::with_fresh_number(|number| bank.create_account_with_pin(number, 0, &pin))?;
+            json!(NewAccount {
+                account: number.to_string(),
+                pin: None,
+            })
+        }
+        Call::CreateAccount { pin: None } => {
+            let (number, pin) = database::with_fresh_number(|number| bank.create_account(number, 0))?;
+            json!(NewAccount {
+                account: number.to_string(),
+                pin: Some(pin),
+            })
+        }
+        Call::IssueToken { account, pin, code } => {
+            json!(bank.issue_token(&account, &pin, code.as_deref(), SESSION_LIFETIME)?)
+        }
+        Call::CheckBalance {
+            account,
+            pin,
+            token,
+            code,
+        } => json!(bank.check_balance(&account, auth(&pin, &token)?, code.as_deref())?),
+        Call::Deposit {
+            account,
+            amount,
+            pin,
+            token,
+        } => json!(bank.deposit(&account, amount, auth(&pin, &token)?)?),
+        Call::Withdraw {
+            account,
+            amount,
+            pin,
+            token,
+        } => json!(bank.withdraw(&account, amount, auth(&pin, &token)?)?),
+        Call::Transfer {
+            account,
+            to,
+            amount,
+            pin,
+            token,
+            code,
+        } => json!(bank.transfer(&account, &to, amount, auth(&pin, &token)?, code.as_deref())?),
+        Call::History {
+            account,
+            pin,
+            token,
+            code,
+            limit,
+        } => json!(bank.history(&account, auth(&pin, &token)?, code.as_deref(), limit)?),
+        Call::CloseAccount { account, payout, pin } => json!(bank.close_account(&account, payout.as_deref(), &pin)?),
+        Call::ChangePin {
+            account,
+            old_pin,
+            new_pin,
+        } => json!(bank.change_pin(&account, &old_pin, &new_pin)?),
+        Call::NeedsCode { account, transfer } => json!(bank.needs_code(&account, transfer)?),
+        Call::BeginTotpEnrollment { account, pin } => json!(bank.begin_totp_enrollment(&account, &pin)?),
+        Call::ConfirmTotpEnrollment { account, code } => json!(bank.confirm_totp_enrollment(&account, &code)?),
+        Call::DisableTotp { account, pin, code } => json!(bank.disable_totp(&account, &pin, &code)?),
+        Call::SetStatus {
+            account,
+            status,
+            reason,
+        } => json!(bank.set_status(&account, status, &reason)?),
+        Call::UnlockAccount { account } => json!(bank.unlock_account(&account)?),
+        Call::MarkDormant { months } => json!(bank.mark_dormant(months)?),
+    };
+    Ok(result)
+}
+
+/// Answering one line of a client, which may call the admin methods if
+/// `staff`. Notifications get no answer, unless the line cannot be read at all.
+fn answer<S: Storage>(bank: &Mutex<Bank<S>>, line: &str, staff: bool) -> Option<Response> {
+    let respond = |id: Value, outcome: Result<Value, RpcError>| Response {
+        jsonrpc: "2.0",
+        id,
+        outcome: match outcome {
+            Ok(result) => Outcome::Result(result),
+            Err(e) => Outcome::Error(e),
+        },
+    };
+
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(respond(Value::Null, Err(RpcError::new(PARSE_ERROR, e)))),
+    };
+    let id = request.get("id").cloned().unwrap_or_default();
+    let request = match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(_) => {
+            return Some(respond(
+                id,
+                Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")),
+            ))
+        }
+        Err(e) => return Some(respond(id, Err(RpcError::new(INVALID_REQUEST, e)))),
+    };
+
+    let outcome = if !METHODS.contains(&request.method.as_str()) {
+        Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("No method `{}`", request.method),
+        ))
+    } else if !staff && ADMIN_METHODS.contains(&request.method.as_str()) {
+        Err(RpcError {
+            code: FORBIDDEN,
+            message: format!("Only the user running the bank daemon may call `{}`", request.method),
+            data: Some(Refusal {
+                error: "forbidden".to_string(),
+                exit_code: 8,
+            }),
+        })
+    } else {
+        match serde_json::from_value(json!({"method": request.method, "params": request.params})) {
+            Ok(call) => {
+                let mut bank = bank.lock().unwrap_or_else(PoisonError::into_inner);
+                dispatch(&mut bank, call)
+            }
+            Err(e) => Err(RpcError::new(INVALID_PARAMS, e)),
+        }
+    };
+    request.id.map(|id| respond(id, outcome))
+}
+
+/// Answering the requests of one client until it hangs up
+fn converse<S: Storage>(bank: &Mutex<Bank<S>>, stream: UnixStream) -> io::Result<()> {
+    let peer = peer_uid(&stream)?;
+    let staff = peer == 0 || peer == own_uid();
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = answer(bank, &line, staff) {
+            let mut response = serde_json::to_vec(&response).map_err(io::Error::other)?;
+            response.push(b'\n');
+            writer.write_all(&response)?;
+        }
+    }
+    Ok(())
+}
+
+/// User the daemon runs as
+fn own_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }
+}
+
+/// User of the process at the other end of `stream`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: credentials and len are valid for writes of the size given in len
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut credentials as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// User of the process at the other end of `stream`
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: uid and gid are valid for writes
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Whether a daemon answers on `path`
+pub fn is_live(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+/// Listening on `path`, taking over the socket file a stopped daemon left
+/// behind. Only the owner of the socket may connect.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) if is_live(path) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("A bank daemon is already serving on {}", path.display()),
+            ));
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // The socket is made in a directory only we can enter and moved into
+    // place once it is private, so nobody can connect in between
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let staging = parent
+        .unwrap_or(Path::new("."))
+        .join(format!(".bank-daemon-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("bank.sock");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    listener
+}
+
+/// Serving `bank` to every client of `listener`, each on its own thread
+pub fn serve<S: Storage + Send + 'static>(bank: Bank<S>, listener: UnixListener) -> io::Result<()> {
+    let bank = Arc::new(Mutex::new(bank));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let bank = bank.clone();
+        thread::spawn(move || {
+            if let Err(e) = converse(&bank, stream) {
+                eprintln!("Client of the bank daemon failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Why a call through the daemon did not go through
+#[derive(Debug)]
+pub enum ClientError {
+    /// The daemon could not be reached or hung up
+    Io(io::Error),
+    /// The daemon answered something that is not a response to the call
+    Protocol(String),
+    /// The bank refused the call or failed, with the `BankError` name and
+    /// exit code
+    Refused {
+        error: String,
+        message: String,
+        exit_code: i32,
+    },
+}
+
+impl ClientError {
+    /// Exit code of the command line, the same as for the `BankError`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::Refused { exit_code, .. } => *exit_code,
+            _ => 1,
+        }
+    }
+
+    /// Name of the `BankError` for machine-readable output
+    pub fn name(&self) -> &str {
+        match self {
+            ClientError::Refused { error, .. } => error,
+            _ => "failure",
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ClientError::Io(e) => write!(f, "The bank daemon cannot be reached: {}", e),
+            ClientError::Protocol(message) => write!(f, "The bank daemon answered nonsense: {}", message),
+            ClientError::Refused { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    id: Value,
+    #[serde(default)]
+    result: Value,
+    error: Option<RpcError>,
+}
+
+/// Connection to a bank daemon. Its methods are those of `Bank`.
+pub struct Client {
+    stream: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl Client {
+    pub fn connect(path: impl AsRef<Path>) -> ClientResult<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{} on {}", e, path.display())))?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+            next_id: 1,
+        })
+    }
+
+    fn call<T: DeserializeOwned>(&mut self, call: Call) -> ClientResult<T> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request = serde_json::to_value(call).map_err(|e| ClientError::Protocol(e.to_string()))?;
+        request["jsonrpc"] = json!("2.0");
+        request["id"] = json!(id);
+        let mut line = request.to_string();
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes())?;
+
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let reply: Reply = serde_json::from_str(&line).map_err(|e| ClientError::Protocol(e.to_string()))?;
+        if reply.id != json!(id) {
+            return Err(ClientError::Protocol(format!(
+                "response to call {} instead of {}",
+                reply.id, id
+            )));
+        }
+        match reply.error {
+            Some(RpcError {
+                message,
+                data: Some(refusal),
+                ..
+            }) => Err(ClientError::Refused {
+                error: refusal.error,
+                message,
+                exit_code: refusal.exit_code,
+            }),
+            Some(e) => Err(ClientError::Protocol(e.message)),
+            None => serde_json::from_value(reply.result).map_err(|e| ClientError::Protocol(e.to_string())),
+        }
+    }
+
+    pub fn create_account(&mut self, pin: Option<&str>) -> ClientResult<NewAccount> {
+        self.call(Call::CreateAccount {
+            pin: pin.map(str::to_string),
+        })
+    }
+
+    pub fn issue_token(&mut self, account: &str, pin: &str, code: Option<&str>) -> ClientResult<String> {
+        self.call(Call::IssueToken {
+            account: account.to_string(),
+            pin: pin.to_string(),
+            code: code.map(str::to_string),
+        })
+    }
+
+    pub fn check_balance<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> ClientResult<u64> {
+        let (pin, token) = credentials(auth.into());
+        self.call(Call::CheckBalance {
+            account: account.to_string(),
+            pin,
+            token,
+            code: code.map(str::to_string),
+        })
+    }
+
+    pub fn deposit<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> ClientResult<u64> {
+        let (pin, token) = credentials(auth.into());
+        self.call(Call::Deposit {
+            account: account.to_string(),
+            amount,
+            pin,
+            token,
+        })
+    }
+
+    pub fn withdraw<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> ClientResult<u64> {
+        let (pin, token) = credentials(auth.into());
+        self.call(Call::Withdraw {
+            account: account.to_string(),
+            amount,
+            pin,
+            token,
+        })
+    }
+
+    pub fn transfer<'a>(
+        &mut self,
+        account: &str,
+        to: &str,
+        amount: u64,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> ClientResult<u64> {
+        let (pin, token) = credentials(auth.into());
+        self.call(Call::Transfer {
+            account: account.to_string(),
+            to: to.to_string(),
+            amount,
+            pin,
+            token,
+            code: code.map(str::to_string),
+        })
+    }
+
+    pub fn history<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+        limit: usize,
+    ) -> ClientResult<Vec<LedgerEntry>> {
+        let (pin, token) = credentials(auth.into());
+        self.call(Call::History {
+            account: account.to_string(),
+            pin,
+            token,
+            code: code.map(str::to_string),
+            limit,
+        })
+    }
+
+    pub fn close_account(&mut self, account: &str, payout: Option<&str>, pin: &str) -> ClientResult<u64> {
+        self.call(Call::CloseAccount {
+            account: account.to_string(),
+            payout: payout.map(str::to_string),
+            pin: pin.to_string(),
+        })
+    }
+
+    pub fn change_pin(&mut self, account: &str, old_pin: &str, new_pin: &str) -> ClientResult<()> {
+        self.call(Call::ChangePin {
+            account: account.to_string(),
+            old_pin: old_pin.to_string(),
+            new_pin: new_pin.to_string(),
+        })
+    }
+
+    pub fn needs_code(&mut self, account: &str, transfer: Option<u64>) -> ClientResult<bool> {
+        self.call(Call::NeedsCode {
+            account: account.to_string(),
+            transfer,
+        })
+    }
+
+    pub fn begin_totp_enrollment(&mut self, account: &str, pin: &str) -> ClientResult<String> {
+        self.call(Call::BeginTotpEnrollment {
+            account: account.to_string(),
+            pin: pin.to_string(),
+        })
+    }
+
+    pub fn confirm_totp_enrollment(&mut self, account: &str, code: &str) -> ClientResult<Vec<String>> {
+        self.call(Call::ConfirmTotpEnrollment {
+            account: account.to_string(),
+            code: code.to_string(),
+        })
+    }
+
+    pub fn disable_totp(&mut self, account: &str, pin: &str, code: &str) -> ClientResult<()> {
+        self.call(Call::DisableTotp {
+            account: account.to_string(),
+            pin: pin.to_string(),
+            code: code.to_string(),
+        })
+    }
+
+    pub fn set_status(&mut self, account: &str, status: AccountStatus, reason: &str) -> ClientResult<AccountStatus> {
+        self.call(Call::SetStatus {
+            account: account.to_string(),
+            status,
+            reason: reason.to_string(),
+        })
+    }
+
+    pub fn unlock_account(&mut self, account: &str) -> ClientResult<()> {
+        self.call(Call::UnlockAccount {
+            account: account.to_string(),
+        })
+    }
+
+    pub fn mark_dormant(&mut self, months: u32) -> ClientResult<usize> {
+        self.call(Call::MarkDormant { months })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::luhn::AccountNumber;
+    use std::str::FromStr;
+
+    const ACCOUNT: &str = "8536276945";
+    const PIN: &str = "364917";
+
+    fn ask(bank: &Mutex<Bank<crate::storage::MemoryStorage>>, line: &str) -> Value {
+        serde_json::to_value(answer(bank, line, true).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn requests_are_answered() {
+        let mut bank = Bank::in_memory();
+        bank.create_account_with_pin(&AccountNumber::from_str(ACCOUNT).unwrap(), 100, PIN)
+            .unwrap();
+        let bank = Mutex::new(bank);
+
+        let deposit = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "deposit",
+            "params": {"account": ACCOUNT, "amount": 50, "pin": PIN},
+        });
+        assert_eq!(
+            ask(&bank, &deposit.to_string()),
+            json!({"jsonrpc": "2.0", "id": 7, "result": 150})
+        );
+
+        let withdraw = r#"{"jsonrpc": "2.0", "id": "w", "method": "withdraw", "params": {"account": "8536276945", "amount": 500, "pin": "364917"}}"#;
+        assert_eq!(
+            ask(&bank, withdraw)["error"],
+            json!({
+                "code": BANK_ERROR,
+                "message": BankError::InsufficientFunds.to_string(),
+                "data": {"error": "insufficient_funds", "exit_code": 5},
+            })
+        );
+
+        let notification = r#"{"jsonrpc": "2.0", "method": "unlock_account", "params": {"account": "8536276945"}}"#;
+        assert!(answer(&bank, notification, true).is_none());
+    }
+
+    #[test]
+    fn tokens_stand_in_for_the_pin() {
+        let mut bank = Bank::in_memory();
+        bank.create_account_with_pin(&AccountNumber::from_str(ACCOUNT).unwrap(), 100, PIN)
+            .unwrap();
+        let bank = Mutex::new(bank);
+
+        let issue = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "issue_token",
+            "params": {"account": ACCOUNT, "pin": PIN},
+        });
+        let token = ask(&bank, &issue.to_string())["result"].clone();
+        let deposit = |params: Value| {
+            let call = json!({"jsonrpc": "2.0", "id": 2, "method": "deposit", "params": params});
+            ask(&bank, &call.to_string())
+        };
+        assert_eq!(
+            deposit(json!({"account": ACCOUNT, "amount": 50, "token": token}))["result"],
+            150
+        );
+        for params in [
+            json!({"account": ACCOUNT, "amount": 50}),
+            json!({"account": ACCOUNT, "amount": 50, "pin": PIN, "token": token}),
+        ] {
+            assert_eq!(deposit(params)["error"]["code"], INVALID_PARAMS);
+        }
+    }
+
+    #[test]
+    fn only_staff_call_admin_methods() {
+        let bank = Mutex::new(Bank::in_memory());
+        let line = r#"{"jsonrpc": "2.0", "id": 1, "method": "mark_dormant", "params": {"months": 12}}"#;
+        let refused = serde_json::to_value(answer(&bank, line, false).unwrap()).unwrap();
+        assert_eq!(refused["error"]["code"], json!(FORBIDDEN));
+        assert_eq!(refused["error"]["data"], json!({"error": "forbidden", "exit_code": 8}));
+
+        let create = r#"{"jsonrpc": "2.0", "id": 2, "method": "create_account", "params": {"pin": "364917"}}"#;
+        let created = serde_json::to_value(answer(&bank, create, false).unwrap()).unwrap();
+        assert!(created["result"]["account"].is_string(), "{created}");
+    }
+
+    #[test]
+    fn the_socket_is_private_and_knows_its_peer() {
+        let dir = std::env::temp_dir().join(format!("bank-daemon-bind-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("bank.sock");
+
+        let listener = bind(&path).unwrap();
+        assert!(is_live(&path));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "the staging directory is left behind");
+        let client = UnixStream::connect(&path).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        assert_eq!(peer_uid(&server).unwrap(), own_uid());
+        assert_eq!(peer_uid(&client).unwrap(), own_uid());
+
+        drop(listener);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn bad_requests_are_told_apart() {
+        let bank = Mutex::new(Bank::in_memory());
+        let code = |line: &str| ask(&bank, line)["error"]["code"].clone();
+
+        assert_eq!(code("{\"jsonrpc\": \"2.0\", "), json!(PARSE_ERROR));
+        assert_eq!(
+            code(r#"{"jsonrpc": "1.0", "id": 1, "method": "mark_dormant"}"#),
+            json!(INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "drop_tables"}"#),
+            json!(METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc": "2.0", "id": 1, "method": "mark_dormant", "params": {"months": "many"}}"#),
+            json!(INVALID_PARAMS)
+        );
+        assert_eq!(
+            ask(
+                &bank,
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "mark_dormant", "params": {"months": 12}}"#
+            )["result"],
+            json!(0)
+        );
+    }
+}
@@ -1,298 +1,1470 @@
 // SPDX-License-Identifier: Unlicense
 
-use std::{path::PathBuf};
+use std::fmt;
+use std::str::FromStr;
+use std::path::Path;
+use std::time::Duration;
+use crate::config::Settings;
+use crate::error::{BankError, BankResult};
 use crate::luhn::AccountNumber;
+use crate::pin;
+use crate::token::TokenKey;
+use crate::totp;
 use rand::prelude::*;
-use rusqlite::{Connection, Result as SqlResult};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use crate::storage::{Credentials, LedgerEntry, MemoryStorage, Posting, PostingKind, SqliteStorage, Storage};
+use rusqlite::Result as SqlResult;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Account {
     pub id: u64,
     pub account_number: String,
     pub balance: u64,
-    pub pin: String,
+    /// Argon2 hash of the PIN, or the plain PIN for accounts
+    /// that have not logged in since hashing was introduced.
+    /// Never serialized, and empty when deserialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub pin_hash: String,
+    pub status: AccountStatus,
+    /// Date the account was closed, if it has been
+    pub closed_at: Option<String>,
+    /// Last login or customer-initiated posting, used to find dormant accounts
+    pub last_activity_at: Option<String>,
 }
 
-#[cfg(not(test))]
-fn database_path() -> PathBuf {
-	PathBuf::from("bank.s3db")
+/// Lifecycle state of an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum AccountStatus {
+    /// Open for logins and postings
+    Active,
+    /// Stopped by the bank. No logins and no postings.
+    Frozen,
+    /// No activity for a long time. Incoming transfers are accepted,
+    /// everything else waits until the bank reactivates the account.
+    Dormant,
+    /// Logins and deposits are allowed, but no money can leave the account
+    BlockedDebit,
+    /// Logins and withdrawals are allowed, but no money can enter the account
+    BlockedCredit,
+    /// Closed by the customer. The account and its ledger are kept,
+    /// but it can no longer be logged into or posted to.
+    Closed,
 }
 
-#[cfg(test)]
-fn database_path() -> PathBuf {
-	PathBuf::from("mock_bank.s3db")
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Dormant => "dormant",
+            AccountStatus::BlockedDebit => "blocked-debit",
+            AccountStatus::BlockedCredit => "blocked-credit",
+            AccountStatus::Closed => "closed",
+        }
+    }
+
+    /// Whether the customer may log into the account
+    pub fn allows_login(&self) -> bool {
+        matches!(
+            self,
+            AccountStatus::Active | AccountStatus::BlockedDebit | AccountStatus::BlockedCredit
+        )
+    }
+
+    /// Whether money may leave the account
+    pub fn allows_debit(&self) -> bool {
+        matches!(self, AccountStatus::Active | AccountStatus::BlockedCredit)
+    }
+
+    /// Whether money may enter the account
+    pub fn allows_credit(&self) -> bool {
+        matches!(
+            self,
+            AccountStatus::Active | AccountStatus::BlockedDebit | AccountStatus::Dormant
+        )
+    }
 }
 
-pub fn initialise_bankdb() -> SqlResult<Connection> {
-    let db = Connection::open(database_path())?;
-
-    let command = "CREATE TABLE IF NOT EXISTS account(
-        id INTEGER PRIMARY KEY,
-        account_number TEXT,
-        pin TEXT DEFAULT '000000',
-        balance INTEGER DEFAULT 0
-    )";
-    db.execute(command, ())?;
-    Ok(db)
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
-/// Creating and storing accounts
-pub fn create_account(data: &AccountNumber, balance: u64) -> SqlResult<()> {
-    let db = initialise_bankdb()?;
-    let account_number = data.to_string();
-    
-    let mut stmt = db.prepare("SELECT id, account_number, balance, pin FROM account")?;
-    let accounts = stmt.query_map([], |row| {
-        Ok(Account {
-            id: row.get(0)?,
-            account_number: row.get(1)?,
-            balance: row.get(2)?,
-            pin: row.get(3)?,
-        })
-    })?;
-    
-    let get_latest_max_id = {
-        let mut x = 0;
-        for account in accounts.flatten() {
-            if account.id > x {
-                x = account.id;
-            }
+impl FromStr for AccountStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "active" => Ok(AccountStatus::Active),
+            "frozen" => Ok(AccountStatus::Frozen),
+            "dormant" => Ok(AccountStatus::Dormant),
+            "blocked-debit" => Ok(AccountStatus::BlockedDebit),
+            "blocked-credit" => Ok(AccountStatus::BlockedCredit),
+            "closed" => Ok(AccountStatus::Closed),
+            other => Err(format!("unknown account status `{}`", other)),
         }
-        x
-    };
-
-    let newest_max_id = get_latest_max_id + 1;
-    let mut rng = thread_rng();
-    let pin: String = (0..6).map(|_| rng.gen_range(0..=9).to_string()).collect();
-
-    let new_account = Account {
-        id: newest_max_id,
-        account_number,
-        balance,
-        pin,
-    };
-
-    db.execute(
-        "INSERT INTO account (id, account_number, pin, balance) VALUES (?1, ?2, ?3, ?4)", 
-        (
-            &new_account.id,
-            &new_account.account_number,
-            &new_account.pin,
-            &new_account.balance,
-        ),
-    )?;
-    Ok(())
+    }
 }
 
-/// Depositing money into a currently active account
-pub fn deposit(amount: &str, pin: &str, account_number: &str) -> SqlResult<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin FROM account WHERE account_number='{}';",
-        account_number
-    );
-    let pin_from_db: String = db.query_row(&query_string, [], |row| row.get(0))?;
-    let correct_pin = pin_from_db == pin;
-
-    if correct_pin {
-        db.execute(
-            "UPDATE account SET balance = balance + ?1 WHERE account_number=?2",
-            (amount, account_number),
-        )?;
-        let query_string = format!(
-            "SELECT balance FROM account WHERE account_number='{}';",
-            account_number
-        );
-        let amount_from_db: u64 = db.query_row(&query_string, [], |row| row.get(0))?;
-        println!(
-            "The account number `{}` now has a balance of `{}`.\n",
-            &account_number, &amount_from_db
-        );
-    } else {
-        eprintln!("Wrong pin. Try again...");
+impl ToSql for AccountStatus {
+    fn to_sql(&self) -> SqlResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
     }
-    Ok(())
 }
 
-/// Transferring money between accounts from a currently active account
-pub fn transfer(
-    amount: &str,
-    pin: &str,
-    account_number1: &str,
-    account_number2: &str,
-) -> SqlResult<()> {
-    if account_number1 == account_number2 {
-        eprintln!("Cannot perform a transfer to the same account!");
-        return Ok(());
-    }
-
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin, balance FROM account WHERE account_number='{}';",
-        account_number1
-    );
-
-    let (pin_from_db, balance): (String, u64) = db.query_row(&query_string, [], |row| {
-        Ok((row.get(0)?, row.get(1)?))
-    })?;
-
-    let correct_pin = pin_from_db == pin;
-    if correct_pin {
-        let amount = amount
-            .parse::<u64>()
-            .expect("Not able to parse string to u64");
-
-        if amount > balance {
-            eprintln!(
-                "You are trying to transfer an amount that exceeds your current balance... aborting...\n"
-            );
-        } else {
-            // Add money to account 2
-            db.execute(
-                "UPDATE account SET balance = balance + ?1 WHERE account_number=?2",
-                (amount, account_number2),
-            )?;
-            // Subtract money from account 1
-            db.execute(
-                "UPDATE account SET balance = balance - ?1 WHERE account_number=?2",
-                (amount, account_number1),
-            )?;
-            let query_string = format!(
-                "SELECT balance FROM account WHERE account_number='{}';",
-                account_number1
-            );
+impl FromSql for AccountStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
 
-            let updated_balance: u64 = db.query_row(&query_string, [], |row| row.get(0))?;
+/// Largest balance or amount, since SQLite stores signed 64-bit integers
+const MAX_BALANCE: u64 = i64::MAX as u64;
 
-            println!(
-                "The account number `{}` now has a balance of `{}`.\n",
-                &account_number1, &updated_balance
-            );
-        }
-    } else {
-        eprintln!("Wrong pin. Try again...");
+/// Random PINs drawn before giving up on a `PinPolicy` that refuses them all
+const PIN_DRAWS: usize = 1000;
+
+/// The bank. Checks PINs, statuses, limits and balances following its
+/// `Settings`, and keeps accounts and the ledger in a `Storage`, a SQLite
+/// database file by default.
+pub struct Bank<S = SqliteStorage> {
+    storage: S,
+    settings: Settings,
+    token_key: TokenKey,
+}
+
+/// How the customer proves they may use an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Auth<'a> {
+    Pin(&'a str),
+    /// A token from `Bank::issue_token`. It stands for the PIN and, on
+    /// accounts enrolled in TOTP, the code given at the login.
+    Token(&'a str),
+}
+
+impl<'a> From<&'a str> for Auth<'a> {
+    fn from(pin: &'a str) -> Self {
+        Auth::Pin(pin)
     }
-    Ok(())
 }
 
-/// Withdrawing money from a currently active account
-pub fn withdraw(amount: &str, pin: &str, account_number: &str) -> SqlResult<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin, balance FROM account WHERE account_number='{}';",
-        account_number
-    );
-
-    let (pin_from_db, balance): (String, u64) = db.query_row(&query_string, [], |row| {
-        Ok((row.get(0)?, row.get(1)?))
-    })?;
-
-    let correct_pin = pin_from_db == pin;
-
-    if correct_pin {
-        let amount = amount
-            .parse::<u64>()
-            .expect("Not able to parse string to u64");
-
-        if amount > balance {
-            eprintln!(
-                "You are trying to withdraw an amount that exceeds your current deposit... aborting...\n"
-            );
-        } else {
-            db.execute(
-                "UPDATE account SET balance = balance - ?1 WHERE account_number=?2",
-                (amount, account_number),
+impl<'a> From<&'a String> for Auth<'a> {
+    fn from(pin: &'a String) -> Self {
+        Auth::Pin(pin)
+    }
+}
+
+impl Bank {
+    /// Opening the bank database at `path`, creating the tables if needed
+    pub fn open(path: impl AsRef<Path>) -> BankResult<Self> {
+        Ok(Self::with_storage(SqliteStorage::open(path)?))
+    }
+}
+
+impl Bank<MemoryStorage> {
+    /// Bank that keeps everything in memory and forgets it when dropped
+    pub fn in_memory() -> Self {
+        Self::with_storage(MemoryStorage::new())
+    }
+}
+
+impl<S: Storage> Bank<S> {
+    /// Bank with the default settings
+    pub fn with_storage(storage: S) -> Self {
+        Self {
+            storage,
+            settings: Settings::default(),
+            token_key: TokenKey::generate(),
+        }
+    }
+
+    pub fn with_settings(self, settings: Settings) -> Self {
+        Self { settings, ..self }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Direct access to the storage, past every check the bank makes
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Whether an account, open or closed, has this number
+    pub fn account_exists(&self, account_number: &str) -> BankResult<bool> {
+        Ok(self.storage.account(account_number)?.is_some())
+    }
+
+    /// Creating and storing accounts. Returns the randomly generated PIN,
+    /// which is only stored hashed.
+    pub fn create_account(&mut self, data: &AccountNumber, balance: u64) -> BankResult<String> {
+        let policy = self.settings.pin_policy.clone();
+        let mut rng = thread_rng();
+        let mut refused = pin::WeakPin::WrongLength(policy.length);
+        for _ in 0..PIN_DRAWS {
+            let pin: String = (0..policy.length).map(|_| rng.gen_range(0..=9).to_string()).collect();
+            match policy.check(&pin) {
+                Ok(()) => {
+                    self.insert_account(data, balance, &pin)?;
+                    return Ok(pin);
+                }
+                Err(weak) => refused = weak,
+            }
+        }
+        Err(BankError::WeakPin(refused))
+    }
+
+    /// Creating and storing an account with a PIN chosen by the customer.
+    /// Nothing is created if the PIN is too weak.
+    pub fn create_account_with_pin(
+        &mut self,
+        data: &AccountNumber,
+        balance: u64,
+        pin: &str,
+    ) -> BankResult<()> {
+        self.settings.pin_policy.check(pin)?;
+        self.insert_account(data, balance, pin)
+    }
+
+    fn insert_account(&mut self, data: &AccountNumber, balance: u64, pin: &str) -> BankResult<()> {
+        if balance > MAX_BALANCE {
+            return Err(BankError::InvalidAmount);
+        }
+        let pin_hash = pin::hash(pin);
+        self.atomically(|bank| bank.storage.insert_account(&data.to_string(), &pin_hash, balance))
+    }
+
+    /// Fetching a single account, including closed ones
+    pub fn fetch_account(&self, account_number: &str) -> BankResult<Account> {
+        self.storage
+            .account(account_number)?
+            .ok_or(BankError::AccountNotFound)
+    }
+
+    /// Current balance of an account
+    pub fn balance(&self, account_number: &str) -> BankResult<u64> {
+        Ok(self.fetch_account(account_number)?.balance)
+    }
+
+    /// Looking up the status of an account. `None` if there is no such account.
+    fn account_status(&self, account_number: &str) -> BankResult<Option<AccountStatus>> {
+        Ok(self.storage.account(account_number)?.map(|a| a.status))
+    }
+
+    /// Depositing money into a currently active account. The status is only
+    /// told to callers who authenticated. Returns the new balance.
+    pub fn deposit<'a>(&mut self, account_number: &str, amount: u64, auth: impl Into<Auth<'a>>) -> BankResult<u64> {
+        let auth = auth.into();
+        self.atomically(|bank| {
+            check_amount(amount)?;
+            let status = bank.fetch_account(account_number)?.status;
+            bank.authenticate(account_number, auth, false, None, "deposit")?;
+            // Like withdrawals, deposits wait until a dormant account is reactivated
+            if !status.allows_login() || !status.allows_credit() {
+                return Err(BankError::Unavailable(status));
+            }
+            if bank.balance(account_number)? + amount > MAX_BALANCE {
+                return Err(BankError::InvalidAmount);
+            }
+
+            bank.storage.post(&[Posting {
+                account_number,
+                kind: PostingKind::Deposit,
+                amount,
+                counterparty: None,
+            }])?;
+            bank.storage.touch_activity(account_number)?;
+            bank.balance(account_number)
+        })
+    }
+
+    /// Withdrawing money from a currently active account. The status and
+    /// limits are only told to callers who authenticated. Returns the new balance.
+    pub fn withdraw<'a>(&mut self, account_number: &str, amount: u64, auth: impl Into<Auth<'a>>) -> BankResult<u64> {
+        let auth = auth.into();
+        self.atomically(|bank| {
+            check_amount(amount)?;
+            let status = bank.fetch_account(account_number)?.status;
+            bank.authenticate(account_number, auth, false, None, "withdraw")?;
+            if !status.allows_debit() {
+                return Err(BankError::Unavailable(status));
+            }
+            if let Some(limit) = bank.settings.limits.max_withdrawal.filter(|limit| amount > *limit) {
+                return Err(BankError::LimitExceeded(limit));
+            }
+            let fee = bank.settings.fees.withdrawal;
+            if amount.checked_add(fee).ok_or(BankError::InsufficientFunds)? > bank.balance(account_number)? {
+                return Err(BankError::InsufficientFunds);
+            }
+
+            let withdrawal = Posting {
+                account_number,
+                kind: PostingKind::Withdrawal,
+                amount,
+                counterparty: None,
+            };
+            bank.storage.post(&with_fee(&[withdrawal], account_number, fee))?;
+            bank.storage.touch_activity(account_number)?;
+            bank.balance(account_number)
+        })
+    }
+
+    /// Transferring money between accounts from a currently active account.
+    /// Accounts enrolled in TOTP need `code` from the threshold up, even
+    /// with a token. The status and limits are only told to callers who
+    /// authenticated. Returns the new balance of `from`.
+    pub fn transfer<'a>(
+        &mut self,
+        from: &str,
+        to: &str,
+        amount: u64,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> BankResult<u64> {
+        let auth = auth.into();
+        self.atomically(|bank| {
+            check_amount(amount)?;
+            if from == to {
+                return Err(BankError::SameAccount);
+            }
+
+            let status = bank.fetch_account(from)?.status;
+            let needs_code =
+                amount >= bank.settings.limits.totp_transfer_threshold && bank.totp_enabled(from)?;
+            bank.authenticate(from, auth, needs_code, code, "transfer")?;
+            if !status.allows_debit() {
+                return Err(BankError::Unavailable(status));
+            }
+            if let Some(limit) = bank.settings.limits.max_transfer.filter(|limit| amount > *limit) {
+                return Err(BankError::LimitExceeded(limit));
+            }
+            let fee = bank.settings.fees.transfer;
+            if amount.checked_add(fee).ok_or(BankError::InsufficientFunds)? > bank.balance(from)? {
+                return Err(BankError::InsufficientFunds);
+            }
+            bank.check_recipient(to, amount)?;
+
+            bank.storage
+                .post(&with_fee(&transfer_postings(from, to, amount), from, fee))?;
+            bank.storage.touch_activity(from)?;
+            bank.balance(from)
+        })
+    }
+
+    /// Closing a currently active account. The balance has to be zero, or the
+    /// remainder is paid out to `payout_account`. The account and its ledger
+    /// are kept, only the status changes. Returns the amount paid out.
+    pub fn close_account(
+        &mut self,
+        account_number: &str,
+        payout_account: Option<&str>,
+        pin: &str,
+    ) -> BankResult<u64> {
+        self.atomically(|bank| {
+            let Account { status, balance, .. } = bank.fetch_account(account_number)?;
+            bank.authenticate(account_number, Auth::Pin(pin), false, None, "close")?;
+            if !status.allows_login() || (balance > 0 && !status.allows_debit()) {
+                return Err(BankError::Unavailable(status));
+            }
+
+            if balance == 0 {
+                bank.storage.close_account(account_number, &[])?;
+                return Ok(0);
+            }
+            let payout_account = payout_account.ok_or(BankError::BalanceRemaining(balance))?;
+            if payout_account == account_number {
+                return Err(BankError::SameAccount);
+            }
+            bank.check_recipient(payout_account, balance)?;
+            bank.storage.close_account(
+                account_number,
+                &transfer_postings(account_number, payout_account, balance),
             )?;
+            Ok(balance)
+        })
+    }
+
+    /// Making sure `amount` can be paid into `account_number`
+    fn check_recipient(&self, account_number: &str, amount: u64) -> BankResult<()> {
+        match self.storage.account(account_number)? {
+            None => Err(BankError::RecipientNotFound),
+            Some(account) if account.status.allows_credit() && account.balance + amount <= MAX_BALANCE => Ok(()),
+            Some(_) => Err(BankError::InvalidRecipient),
+        }
+    }
+
+    /// Logging into an account. Checks the status and PIN, plus `code` if the
+    /// account enrolled in TOTP, and replaces a plaintext PIN left over from
+    /// before hashing with its hash while the plain PIN is at hand.
+    pub fn login(&mut self, account_number: &str, pin: &str, code: Option<&str>) -> BankResult<()> {
+        self.atomically(|bank| {
+            let Some(status) = bank.account_status(account_number)? else {
+                bank.storage.record_attempt(account_number, "login", "no_account")?;
+                return Err(BankError::AccountNotFound);
+            };
+
+            if !status.allows_login() {
+                return Err(BankError::Unavailable(status));
+            }
+            let needs_code = bank.totp_enabled(account_number)?;
+            bank.authenticate(account_number, Auth::Pin(pin), needs_code, code, "login")?;
+
+            if pin::is_legacy(&bank.fetch_account(account_number)?.pin_hash) {
+                bank.storage.set_pin_hash(account_number, &pin::hash(pin))?;
+            }
+            bank.storage.touch_activity(account_number)
+        })
+    }
 
-            let query_string = format!(
-                "SELECT balance FROM account WHERE account_number='{}';",
-                account_number
-            );
+    /// Logging in like `login` and handing out a token that stands in for
+    /// the PIN for `ttl`, or until the PIN changes
+    pub fn issue_token(
+        &mut self,
+        account_number: &str,
+        pin: &str,
+        code: Option<&str>,
+        ttl: Duration,
+    ) -> BankResult<String> {
+        self.login(account_number, pin, code)?;
+        let pin_hash = self.fetch_account(account_number)?.pin_hash;
+        let expires_at = totp::now() + ttl.as_secs();
+        Ok(self.token_key.issue(account_number, expires_at, &pin_hash))
+    }
 
-            let updated_balance: u64 = db.query_row(&query_string, [], |row| row.get(0))?;
+    /// Current balance of an account, for the customer. Needs the PIN, plus
+    /// `code` if the account enrolled in TOTP, like a login, or a token.
+    pub fn check_balance<'a>(
+        &mut self,
+        account_number: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> BankResult<u64> {
+        let auth = auth.into();
+        self.atomically(|bank| {
+            bank.authenticate_customer(account_number, auth, code, "balance")?;
+            bank.balance(account_number)
+        })
+    }
+
+    /// The last `limit` postings of an account, newest first, for the customer.
+    /// Needs the same as `check_balance`.
+    pub fn history<'a>(
+        &mut self,
+        account_number: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+        limit: usize,
+    ) -> BankResult<Vec<LedgerEntry>> {
+        let auth = auth.into();
+        self.atomically(|bank| {
+            bank.authenticate_customer(account_number, auth, code, "history")?;
+            bank.storage.history(account_number, limit)
+        })
+    }
 
-            println!(
-                "The account number `{}` now has a balance of `{}`.\n",
-                &account_number, &updated_balance
-            );
+    /// Checking the status, PIN and, for accounts enrolled in TOTP, `code`
+    /// before showing the customer their account. Tokens need no code.
+    fn authenticate_customer(
+        &mut self,
+        account_number: &str,
+        auth: Auth<'_>,
+        code: Option<&str>,
+        operation: &str,
+    ) -> BankResult<()> {
+        let status = self.fetch_account(account_number)?.status;
+        if !status.allows_login() {
+            return Err(BankError::Unavailable(status));
         }
-    } else {
-        eprintln!("Wrong pin. Try again...");
+        let needs_code = matches!(auth, Auth::Pin(_)) && self.totp_enabled(account_number)?;
+        self.authenticate(account_number, auth, needs_code, code, operation)
     }
-    Ok(())
-}
 
-/// Deleting a currently active account
-pub fn delete_account(account_number: &str, pin: &str) -> SqlResult<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT pin FROM account WHERE account_number='{}';",
-        account_number
-    );
+    /// Balance of an account and its ledger lines after the line `after_id`,
+    /// oldest first, read together so they agree. Checks no PIN; for feeds
+    /// of an account the customer already logged into.
+    pub fn changes_since(&mut self, account_number: &str, after_id: u64) -> BankResult<(u64, Vec<LedgerEntry>)> {
+        self.atomically(|bank| {
+            let entries = bank.storage.ledger_since(account_number, after_id, usize::MAX)?;
+            Ok((bank.balance(account_number)?, entries))
+        })
+    }
+
+    /// Changing the PIN of an account. The old PIN has to be given, counting
+    /// towards the lockout like any other PIN check, and the new one has to
+    /// pass the `PinPolicy`.
+    pub fn change_pin(&mut self, account_number: &str, old_pin: &str, new_pin: &str) -> BankResult<()> {
+        self.atomically(|bank| {
+            let status = bank.fetch_account(account_number)?.status;
+            if !status.allows_login() {
+                return Err(BankError::Unavailable(status));
+            }
+            bank.authenticate(account_number, Auth::Pin(old_pin), false, None, "change_pin")?;
+            if new_pin == old_pin {
+                return Err(BankError::PinUnchanged);
+            }
+            bank.settings.pin_policy.check(new_pin)?;
+
+            bank.storage.set_pin_hash(account_number, &pin::hash(new_pin))?;
+            bank.storage.touch_activity(account_number)
+        })
+    }
+
+    /// Whether the account has finished TOTP enrollment
+    pub fn totp_enabled(&self, account_number: &str) -> BankResult<bool> {
+        Ok(self
+            .storage
+            .credentials(account_number)?
+            .is_some_and(|c| c.totp_enabled))
+    }
+
+    /// Whether the customer has to give an authenticator code: for logins and
+    /// reads on accounts enrolled in TOTP, and for a `transfer` of an amount
+    /// from the threshold up
+    pub fn needs_code(&self, account_number: &str, transfer: Option<u64>) -> BankResult<bool> {
+        let threshold = self.settings.limits.totp_transfer_threshold;
+        Ok(transfer.is_none_or(|amount| amount >= threshold) && self.totp_enabled(account_number)?)
+    }
+
+    /// Starting TOTP enrollment. A fresh secret is stored but not enforced until
+    /// `confirm_totp_enrollment`. Returns the provisioning URI for the
+    /// authenticator app.
+    pub fn begin_totp_enrollment(&mut self, account_number: &str, pin: &str) -> BankResult<String> {
+        self.atomically(|bank| {
+            let status = bank.fetch_account(account_number)?.status;
+            if !status.allows_login() {
+                return Err(BankError::Unavailable(status));
+            }
+            bank.authenticate(account_number, Auth::Pin(pin), false, None, "totp_enroll")?;
+            if bank.totp_enabled(account_number)? {
+                return Err(BankError::TotpAlreadyEnabled);
+            }
+
+            let secret = totp::generate_secret();
+            bank.storage
+                .set_totp_secret(account_number, &totp::base32_encode(&secret))?;
+            Ok(totp::provisioning_uri(&secret, account_number, "Bank"))
+        })
+    }
+
+    /// Finishing TOTP enrollment with the first code from the authenticator app.
+    /// Returns ten one-time recovery codes, which are only stored hashed.
+    pub fn confirm_totp_enrollment(&mut self, account_number: &str, code: &str) -> BankResult<Vec<String>> {
+        self.atomically(|bank| {
+            let secret = bank.totp_secret(account_number)?.ok_or(BankError::TotpNotEnabled)?;
+            let step = totp::verify(&secret, code, totp::now())
+                .ok_or(BankError::WrongCode { locked: false })?;
+
+            let recovery_codes = totp::generate_recovery_codes(10);
+            let hashes: Vec<String> = recovery_codes.iter().map(|c| pin::hash(c)).collect();
+            bank.storage.enable_totp(account_number, step, &hashes)?;
+            Ok(recovery_codes)
+        })
+    }
+
+    /// Removing the authenticator from an account. Needs the PIN and a code.
+    pub fn disable_totp(&mut self, account_number: &str, pin: &str, code: &str) -> BankResult<()> {
+        self.atomically(|bank| {
+            if !bank.totp_enabled(account_number)? {
+                return Err(BankError::TotpNotEnabled);
+            }
+            bank.authenticate(account_number, Auth::Pin(pin), true, Some(code), "totp_disable")?;
+            bank.storage.disable_totp(account_number)
+        })
+    }
+
+    /// Lifting a PIN lockout before it runs out on its own
+    pub fn unlock_account(&mut self, account_number: &str) -> BankResult<()> {
+        self.atomically(|bank| {
+            if !bank.account_exists(account_number)? {
+                return Err(BankError::AccountNotFound);
+            }
+            bank.storage.reset_failures(account_number)?;
+            bank.storage.record_attempt(account_number, "unlock", "unlocked")
+        })
+    }
+
+    /// Changing the status of an account on behalf of the bank. The change and
+    /// its reason are kept in the status history. Closing goes through
+    /// `close_account` instead, and a closed account stays closed.
+    /// Returns the previous status.
+    pub fn set_status(
+        &mut self,
+        account_number: &str,
+        status: AccountStatus,
+        reason: &str,
+    ) -> BankResult<AccountStatus> {
+        self.atomically(|bank| {
+            let old_status = bank.fetch_account(account_number)?.status;
+            if old_status == AccountStatus::Closed || status == AccountStatus::Closed {
+                return Err(BankError::StatusChangeNotAllowed);
+            }
+
+            bank.storage.change_status(account_number, old_status, status, reason)?;
+            if status == AccountStatus::Active {
+                bank.storage.touch_activity(account_number)?;
+            }
+            Ok(old_status)
+        })
+    }
+
+    /// Marking every active account without activity in the last `months`
+    /// months as dormant. Returns the number of accounts marked.
+    pub fn mark_dormant(&mut self, months: u32) -> BankResult<usize> {
+        self.atomically(|bank| {
+            let idle = bank.storage.idle_accounts(months)?;
+            let reason = format!("no activity for {} months", months);
+            for account_number in &idle {
+                bank.storage
+                    .change_status(account_number, AccountStatus::Active, AccountStatus::Dormant, &reason)?;
+            }
+            Ok(idle.len())
+        })
+    }
+
+    /// Running a read-check-write sequence as one unit of work of the storage,
+    /// so no other process can change the accounts between the checks and the
+    /// writes. Refusals keep what was written on the way, such as a counted
+    /// wrong PIN; only a failing storage undoes the unit.
+    fn atomically<T>(&mut self, f: impl FnOnce(&mut Self) -> BankResult<T>) -> BankResult<T> {
+        self.storage.begin()?;
+        match f(self) {
+            Err(BankError::Storage(e)) => {
+                // The original error says more than a failed rollback
+                let _ = self.storage.rollback();
+                Err(BankError::Storage(e))
+            }
+            result => {
+                self.storage.commit()?;
+                result
+            }
+        }
+    }
+
+    /// Decoded TOTP secret of an account, also while enrollment is unconfirmed
+    fn totp_secret(&self, account_number: &str) -> BankResult<Option<Vec<u8>>> {
+        let credentials = self
+            .storage
+            .credentials(account_number)?
+            .ok_or(BankError::AccountNotFound)?;
+        Ok(credentials.totp_secret.and_then(|s| totp::base32_decode(&s)))
+    }
+
+    /// Checking the PIN and, when `needs_code` is set, the second factor: a code
+    /// from the authenticator app or an unused recovery code. Every attempt is
+    /// recorded. The lockout counts wrong PINs and wrong codes alike, and is
+    /// only reset once both passed. A token takes the place of the PIN.
+    fn authenticate(
+        &mut self,
+        account_number: &str,
+        auth: Auth<'_>,
+        needs_code: bool,
+        code: Option<&str>,
+        operation: &str,
+    ) -> BankResult<()> {
+        let credentials = self
+            .storage
+            .credentials(account_number)?
+            .ok_or(BankError::AccountNotFound)?;
+
+        if credentials.locked {
+            self.storage.record_attempt(account_number, operation, "locked")?;
+            return Err(BankError::Locked);
+        }
+
+        match auth {
+            Auth::Pin(pin) if !pin::verify(&credentials.pin_hash, pin) => {
+                let locked = self.register_failure(account_number, operation, "wrong_pin", &credentials)?;
+                return Err(BankError::WrongPin { locked });
+            }
+            // Tokens cannot be guessed, so a stale one does not count towards the lockout
+            Auth::Token(token) if !self.token_key.verify(token, account_number, &credentials.pin_hash, totp::now()) => {
+                self.storage.record_attempt(account_number, operation, "invalid_token")?;
+                return Err(BankError::InvalidToken);
+            }
+            _ => {}
+        }
 
-    let pin_from_db: String = db.query_row(&query_string, [], |row| row.get(0))?;
+        if needs_code {
+            let Some(code) = code else {
+                self.storage.record_attempt(account_number, operation, "missing_code")?;
+                return Err(BankError::SecondFactorRequired);
+            };
+            if !self.second_factor_matches(account_number, code, &credentials)? {
+                let locked = self.register_failure(account_number, operation, "wrong_code", &credentials)?;
+                return Err(BankError::WrongCode { locked });
+            }
+        }
 
-    let correct_pin = pin_from_db == pin;
+        // A token alone is no reason to forget wrong PINs typed elsewhere
+        if matches!(auth, Auth::Pin(_)) || needs_code {
+            self.storage.reset_failures(account_number)?;
+        }
+        self.storage.record_attempt(account_number, operation, "success")
+    }
 
-    if correct_pin {
-        db.execute(
-            "DELETE FROM account WHERE account_number=?1",
-            [account_number],
-        )?;
-        println!("DELETED ACCOUNT: {}", &account_number);
-    } else {
-        eprintln!("Wrong pin. Try again...");
+    /// Counting a wrong PIN or code, locking the account once the
+    /// `LockoutPolicy` runs out of attempts. Returns whether it got locked.
+    fn register_failure(
+        &mut self,
+        account_number: &str,
+        operation: &str,
+        outcome: &str,
+        credentials: &Credentials,
+    ) -> BankResult<bool> {
+        self.storage.record_attempt(account_number, operation, outcome)?;
+        let policy = self.settings.lockout;
+        if credentials.failed_attempts + 1 >= policy.max_attempts.max(1) {
+            self.storage.lock(account_number, policy.lock_minutes)?;
+            Ok(true)
+        } else {
+            self.storage.count_failure(account_number)?;
+            Ok(false)
+        }
+    }
+
+    /// Matching a TOTP code, which may not be reused, or else an unused
+    /// recovery code, which is used up
+    fn second_factor_matches(
+        &mut self,
+        account_number: &str,
+        code: &str,
+        credentials: &Credentials,
+    ) -> BankResult<bool> {
+        let secret = credentials
+            .totp_secret
+            .as_deref()
+            .and_then(totp::base32_decode)
+            .unwrap_or_default();
+
+        if let Some(step) = totp::verify(&secret, code, totp::now()) {
+            if credentials.totp_last_step.is_some_and(|last| step <= last) {
+                return Ok(false);
+            }
+            self.storage.set_totp_last_step(account_number, step)?;
+            return Ok(true);
+        }
+
+        let code = code.trim().to_lowercase();
+        let unused = self.storage.unused_recovery_codes(account_number)?;
+        match unused.iter().find(|c| pin::verify(&c.code_hash, &code)) {
+            Some(recovery_code) => {
+                self.storage.use_recovery_code(recovery_code.id)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Refusing amounts of zero and amounts SQLite cannot store
+fn check_amount(amount: u64) -> BankResult<()> {
+    if amount == 0 || amount > MAX_BALANCE {
+        return Err(BankError::InvalidAmount);
     }
     Ok(())
 }
 
-/// Showing the current balance of a currently active account
-pub fn show_balance(account_number: &str) -> SqlResult<()> {
-    let db = initialise_bankdb()?;
-    let query_string = format!(
-        "SELECT balance FROM account WHERE account_number='{}';",
-        account_number
-    );
+/// Both sides of moving `amount` from one account to another
+fn transfer_postings<'a>(from: &'a str, to: &'a str, amount: u64) -> [Posting<'a>; 2] {
+    [
+        Posting {
+            account_number: from,
+            kind: PostingKind::TransferOut,
+            amount,
+            counterparty: Some(to),
+        },
+        Posting {
+            account_number: to,
+            kind: PostingKind::TransferIn,
+            amount,
+            counterparty: Some(from),
+        },
+    ]
+}
 
-    let amount_from_db: u64 = db.query_row(&query_string, [], |row| row.get(0))?;
+/// The postings followed by a fee charged to `account_number`, if there is one
+fn with_fee<'a>(postings: &[Posting<'a>], account_number: &'a str, fee: u64) -> Vec<Posting<'a>> {
+    let mut postings = postings.to_vec();
+    if fee > 0 {
+        postings.push(Posting {
+            account_number,
+            kind: PostingKind::Fee,
+            amount: fee,
+            counterparty: None,
+        });
+    }
+    postings
+}
 
-    println!(
-        "The account number `{}` now has a balance of `{}`.\n",
-        &account_number, &amount_from_db
-    );
-    Ok(())
+/// Creating an account under a random number, drawing again while the
+/// number is taken
+pub fn with_fresh_number<T>(
+    mut create: impl FnMut(&AccountNumber) -> BankResult<T>,
+) -> BankResult<(AccountNumber, T)> {
+    loop {
+        let number = AccountNumber::default();
+        match create(&number) {
+            Err(BankError::AccountExists) => continue,
+            result => return Ok((number, result?)),
+        }
+    }
 }
-fn fetch_account(account: &str) -> Result<Account> {
-	let db = initialise_bankdb()?;
-	let mut stmt = db.prepare("SELECT id, account_number, balance, pin FROM account")?;
-	let accounts = stmt.query_map([], |row| {
-    	Ok(Account {
-        	id: row.get(0)?,
-        	account_number: row.get(1)?,
-        	balance: row.get(2)?,
-        	pin: row.get(3)?,
-    	})
-	})?;
-
-	let accounts = accounts.flatten().find(|acc| acc.account_number == account);
-	if let Some(fetched_account) = accounts {
-    		Ok(fetched_account)
-	} else {
-    		Err(rusqlite::Error::QueryReturnedNoRows)
-	}
+
+/// How many wrong PINs in a row lock an account, and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct LockoutPolicy {
+    pub max_attempts: u32,
+    /// Length of the lockout. Zero keeps the account locked until an admin unlocks it.
+    pub lock_minutes: u32,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            lock_minutes: 15,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::storage::AccountFilter;
+
+	fn open_bank() -> BankResult<Bank> {
+    		Ok(Bank::with_storage(SqliteStorage::open_in_memory()?))
+	}
+
+	fn new_account(bank: &mut Bank, balance: u64) -> BankResult<(Account, String)> {
+    		let number = AccountNumber::default();
+    		let pin = bank.create_account(&number, balance)?;
+    		Ok((bank.fetch_account(&number.to_string())?, pin))
+	}
 	
 	#[test]
-	fn created_account_is_correct_fetched_from_db() -> Result<()> {
-    		let acc1 = Account::new()?;
-    		let acc2 = fetch_account(&acc1.account_number)?;
+	fn created_account_is_correct_fetched_from_db() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc1, pin) = new_account(&mut bank, 0)?;
+    		let acc2 = bank.fetch_account(&acc1.account_number)?;
 
     		assert_eq!(acc1.id, acc2.id);
+    		assert_eq!(acc2.status, AccountStatus::Active);
+    		assert_eq!(acc2.closed_at, None);
+    		assert_ne!(acc2.pin_hash, pin);
+    		assert!(pin::verify(&acc2.pin_hash, &pin));
+
+    		Ok(())
+	}
+
+	#[test]
+	fn account_numbers_are_unique() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (first, _) = new_account(&mut bank, 0)?;
+    		let (second, _) = new_account(&mut bank, 0)?;
+    		assert!(second.id > first.id);
+
+    		let number: AccountNumber = first.account_number.parse().unwrap();
+    		assert!(matches!(bank.create_account(&number, 5), Err(BankError::AccountExists)));
+    		let mut memory = Bank::in_memory();
+    		memory.create_account(&number, 0)?;
+    		assert!(matches!(memory.create_account(&number, 5), Err(BankError::AccountExists)));
+    		assert_eq!(bank.balance(&first.account_number)?, 0);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn closing_with_balance_needs_payout_account() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 50)?;
+    		assert!(matches!(
+        		bank.close_account(&acc.account_number, None, &pin),
+        		Err(BankError::BalanceRemaining(50))
+    		));
+
+    		let acc = bank.fetch_account(&acc.account_number)?;
+    		assert_eq!(acc.status, AccountStatus::Active);
+    		assert_eq!(acc.balance, 50);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn closing_pays_out_and_keeps_account() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 50)?;
+    		let (payout, _) = new_account(&mut bank, 10)?;
+    		assert_eq!(bank.close_account(&acc.account_number, Some(&payout.account_number), &pin)?, 50);
+
+    		let acc = bank.fetch_account(&acc.account_number)?;
+    		assert_eq!(acc.status, AccountStatus::Closed);
+    		assert!(acc.closed_at.is_some());
+    		assert_eq!(acc.balance, 0);
+    		assert_eq!(bank.fetch_account(&payout.account_number)?.balance, 60);
+
+    		Ok(())
+	}
 
+	#[test]
+	fn closed_account_takes_no_postings() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 0)?;
+    		let (other, other_pin) = new_account(&mut bank, 20)?;
+    		bank.close_account(&acc.account_number, None, &pin)?;
+
+    		assert!(matches!(
+        		bank.deposit(&acc.account_number, 5, &pin),
+        		Err(BankError::Unavailable(AccountStatus::Closed))
+    		));
+    		assert!(matches!(
+        		bank.transfer(&other.account_number, &acc.account_number, 5, &other_pin, None),
+        		Err(BankError::InvalidRecipient)
+    		));
+
+    		assert_eq!(bank.fetch_account(&acc.account_number)?.balance, 0);
+    		assert_eq!(bank.fetch_account(&other.account_number)?.balance, 20);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn blocked_statuses_restrict_postings() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 30)?;
+    		bank.set_status(&acc.account_number, AccountStatus::BlockedDebit, "test")?;
+    		assert!(bank.withdraw(&acc.account_number, 10, &pin).is_err());
+    		assert_eq!(bank.deposit(&acc.account_number, 5, &pin)?, 35);
+
+    		bank.set_status(&acc.account_number, AccountStatus::BlockedCredit, "test")?;
+    		assert_eq!(bank.withdraw(&acc.account_number, 10, &pin)?, 25);
+    		assert!(bank.deposit(&acc.account_number, 5, &pin).is_err());
+
+    		bank.set_status(&acc.account_number, AccountStatus::Frozen, "test")?;
+    		assert!(matches!(
+        		bank.withdraw(&acc.account_number, 10, &pin),
+        		Err(BankError::Unavailable(AccountStatus::Frozen))
+    		));
+    		assert!(bank.deposit(&acc.account_number, 5, &pin).is_err());
+    		assert_eq!(bank.fetch_account(&acc.account_number)?.balance, 25);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn postings_check_the_pin_before_the_status() -> BankResult<()> {
+    		let mut settings = Settings::default();
+    		settings.limits.max_withdrawal = Some(10);
+    		settings.limits.max_transfer = Some(10);
+    		let mut bank = open_bank()?.with_settings(settings);
+    		let (acc, pin) = new_account(&mut bank, 30)?;
+    		let (other, other_pin) = new_account(&mut bank, 30)?;
+    		let wrong_pin = if pin == "000000" { "111111" } else { "000000" };
+    		let number = acc.account_number.as_str();
+
+    		// Without the PIN, neither limits nor the status give anything away
+    		assert!(matches!(bank.withdraw(number, 20, wrong_pin), Err(BankError::WrongPin { .. })));
+    		assert!(matches!(
+        		bank.transfer(number, &other.account_number, 20, wrong_pin, None),
+        		Err(BankError::WrongPin { .. })
+    		));
+    		bank.unlock_account(number)?;
+    		assert!(matches!(bank.withdraw(number, 20, &pin), Err(BankError::LimitExceeded(10))));
+
+    		bank.set_status(number, AccountStatus::Frozen, "test")?;
+    		assert!(matches!(bank.deposit(number, 5, wrong_pin), Err(BankError::WrongPin { .. })));
+    		assert!(matches!(bank.withdraw(number, 5, wrong_pin), Err(BankError::WrongPin { .. })));
+    		bank.unlock_account(number)?;
+    		assert!(matches!(
+        		bank.transfer(number, &other.account_number, 5, wrong_pin, None),
+        		Err(BankError::WrongPin { .. })
+    		));
+    		assert!(matches!(
+        		bank.close_account(number, Some(&other.account_number), wrong_pin),
+        		Err(BankError::WrongPin { .. })
+    		));
+    		bank.unlock_account(number)?;
+    		assert!(matches!(bank.deposit(number, 5, &pin), Err(BankError::Unavailable(AccountStatus::Frozen))));
+    		assert!(matches!(bank.withdraw(number, 5, &pin), Err(BankError::Unavailable(AccountStatus::Frozen))));
+    		assert!(matches!(
+        		bank.transfer(number, &other.account_number, 5, &pin, None),
+        		Err(BankError::Unavailable(AccountStatus::Frozen))
+    		));
+
+    		bank.set_status(&acc.account_number, AccountStatus::Dormant, "test")?;
+    		assert!(matches!(
+        		bank.deposit(&acc.account_number, 5, &pin),
+        		Err(BankError::Unavailable(AccountStatus::Dormant))
+    		));
+    		// Transfers still reach a dormant account
+    		assert_eq!(bank.transfer(&other.account_number, &acc.account_number, 5, &other_pin, None)?, 25);
+    		assert_eq!(bank.fetch_account(&acc.account_number)?.balance, 35);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn bad_postings_are_refused() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 30)?;
+    		assert!(matches!(
+        		bank.withdraw(&acc.account_number, 31, &pin),
+        		Err(BankError::InsufficientFunds)
+    		));
+    		assert!(matches!(bank.deposit(&acc.account_number, 0, &pin), Err(BankError::InvalidAmount)));
+    		assert!(matches!(
+        		bank.deposit(&acc.account_number, u64::MAX, &pin),
+        		Err(BankError::InvalidAmount)
+    		));
+    		assert!(matches!(
+        		bank.transfer(&acc.account_number, &acc.account_number, 5, &pin, None),
+        		Err(BankError::SameAccount)
+    		));
+    		assert!(matches!(
+        		bank.deposit(&AccountNumber::default().to_string(), 5, &pin),
+        		Err(BankError::AccountNotFound)
+    		));
+    		assert_eq!(bank.withdraw(&acc.account_number, 30, &pin)?, 0);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn fees_and_limits_follow_the_settings() -> BankResult<()> {
+    		let mut settings = Settings::default();
+    		settings.limits.max_withdrawal = Some(50);
+    		settings.fees = crate::config::Fees { withdrawal: 2, transfer: 1 };
+    		let mut bank = open_bank()?.with_settings(settings);
+    		let (acc, pin) = new_account(&mut bank, 100)?;
+    		let (other, _) = new_account(&mut bank, 0)?;
+
+    		assert!(matches!(
+        		bank.withdraw(&acc.account_number, 60, &pin),
+        		Err(BankError::LimitExceeded(50))
+    		));
+    		assert_eq!(bank.withdraw(&acc.account_number, 50, &pin)?, 48);
+    		assert!(matches!(
+        		bank.transfer(&acc.account_number, &other.account_number, 48, &pin, None),
+        		Err(BankError::InsufficientFunds)
+    		));
+    		assert_eq!(bank.transfer(&acc.account_number, &other.account_number, 47, &pin, None)?, 0);
+    		assert_eq!(bank.balance(&other.account_number)?, 47);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn short_pin_policies_do_not_hang() -> BankResult<()> {
+    		for length in [0, 1] {
+        		let mut settings = Settings::default();
+        		settings.pin_policy.length = length;
+        		let mut bank = open_bank()?.with_settings(settings);
+        		let (account, pin) = new_account(&mut bank, 0)?;
+        		assert_eq!(pin.len(), length);
+        		assert!(bank.account_exists(&account.account_number)?);
+    		}
     		Ok(())
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn huge_fees_do_not_overflow() -> BankResult<()> {
+    		let settings = Settings {
+        		fees: crate::config::Fees { withdrawal: u64::MAX, transfer: u64::MAX },
+        		..Settings::default()
+    		};
+    		let mut bank = open_bank()?.with_settings(settings);
+    		let (acc, pin) = new_account(&mut bank, 100)?;
+    		let (other, _) = new_account(&mut bank, 0)?;
+
+    		assert!(matches!(
+        		bank.withdraw(&acc.account_number, u64::MAX, &pin),
+        		Err(BankError::InvalidAmount)
+    		));
+    		for amount in [1, i64::MAX as u64] {
+        		assert!(matches!(
+            		bank.withdraw(&acc.account_number, amount, &pin),
+            		Err(BankError::InsufficientFunds)
+        		));
+        		assert!(matches!(
+            		bank.transfer(&acc.account_number, &other.account_number, amount, &pin, None),
+            		Err(BankError::InsufficientFunds)
+        		));
+    		}
+    		assert_eq!(bank.balance(&acc.account_number)?, 100);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn balance_and_history_need_the_pin() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (account, pin) = new_account(&mut bank, 0)?;
+    		let (other, _) = new_account(&mut bank, 0)?;
+    		let number = &account.account_number;
+    		bank.deposit(number, 100, &pin)?;
+    		bank.transfer(number, &other.account_number, 30, &pin, None)?;
+
+    		assert_eq!(bank.check_balance(number, &pin, None)?, 70);
+    		assert!(matches!(bank.check_balance(number, "wrong", None), Err(BankError::WrongPin { .. })));
+    		assert!(matches!(bank.history(number, "wrong", None, 10), Err(BankError::WrongPin { .. })));
+
+    		let history = bank.history(number, &pin, None, 10)?;
+    		let kinds: Vec<_> = history.iter().map(|e| e.kind).collect();
+    		assert_eq!(kinds, [PostingKind::TransferOut, PostingKind::Deposit]);
+    		assert_eq!(history[0].counterparty.as_deref(), Some(other.account_number.as_str()));
+    		assert_eq!(bank.history(number, &pin, None, 1)?.len(), 1);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn tokens_stand_in_for_the_pin() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (account, pin) = new_account(&mut bank, 100)?;
+    		let (other, _) = new_account(&mut bank, 0)?;
+    		let number = &account.account_number;
+    		let token = bank.issue_token(number, &pin, None, Duration::from_secs(60))?;
+
+    		assert_eq!(bank.deposit(number, 50, Auth::Token(&token))?, 150);
+    		assert_eq!(bank.check_balance(number, Auth::Token(&token), None)?, 150);
+    		assert!(matches!(
+            		bank.check_balance(&other.account_number, Auth::Token(&token), None),
+            		Err(BankError::InvalidToken)
+    		));
+
+    		// Using a token does not wipe out wrong PINs typed in between
+    		assert!(bank.withdraw(number, 10, "000000").is_err());
+    		bank.withdraw(number, 10, Auth::Token(&token))?;
+    		assert_eq!(bank.storage().credentials(number)?.unwrap().failed_attempts, 1);
+
+    		bank.change_pin(number, &pin, "642853")?;
+    		assert!(matches!(bank.deposit(number, 50, Auth::Token(&token)), Err(BankError::InvalidToken)));
+    		let expired = bank.issue_token(number, "642853", None, Duration::ZERO)?;
+    		assert!(matches!(bank.deposit(number, 50, Auth::Token(&expired)), Err(BankError::InvalidToken)));
+
+    		Ok(())
+	}
+
+	#[test]
+	fn closed_status_is_final() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 0)?;
+    		bank.close_account(&acc.account_number, None, &pin)?;
+    		assert!(matches!(
+        		bank.set_status(&acc.account_number, AccountStatus::Active, "test"),
+        		Err(BankError::StatusChangeNotAllowed)
+    		));
+    		assert_eq!(bank.fetch_account(&acc.account_number)?.status, AccountStatus::Closed);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn idle_accounts_turn_dormant() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (idle, _) = new_account(&mut bank, 0)?;
+    		let (busy, _) = new_account(&mut bank, 0)?;
+    		bank.storage.connection().execute(
+        		"UPDATE account SET last_activity_at = datetime('now', '-13 months') WHERE account_number=?1",
+        		[&idle.account_number],
+    		)?;
+
+    		assert!(bank.mark_dormant(12)? >= 1);
+    		assert_eq!(bank.fetch_account(&idle.account_number)?.status, AccountStatus::Dormant);
+    		assert_eq!(bank.fetch_account(&busy.account_number)?.status, AccountStatus::Active);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn accounts_are_listed_in_pages() -> BankResult<()> {
+    		fn check<S: Storage>(mut bank: Bank<S>) -> BankResult<()> {
+        		let mut numbers = Vec::new();
+        		for balance in [10, 20, 30, 40] {
+            		let number = AccountNumber::default();
+            		bank.create_account(&number, balance)?;
+            		numbers.push(number.to_string());
+        		}
+        		bank.set_status(&numbers[2], AccountStatus::Frozen, "test")?;
+
+        		let listed = |bank: &Bank<S>, filter: &AccountFilter, after_id, limit| -> BankResult<Vec<String>> {
+            		let accounts = bank.storage().accounts(filter, after_id, limit)?;
+            		Ok(accounts.into_iter().map(|a| a.account_number).collect())
+        		};
+        		let all = AccountFilter::default();
+        		assert_eq!(listed(&bank, &all, 0, 10)?, numbers);
+        		let first = bank.fetch_account(&numbers[0])?.id;
+        		assert_eq!(listed(&bank, &all, first, 2)?, numbers[1..3]);
+
+        		let middle = AccountFilter {
+            		min_balance: Some(20),
+            		max_balance: Some(30),
+            		..all
+        		};
+        		assert_eq!(listed(&bank, &middle, 0, 10)?, numbers[1..3]);
+        		let frozen = AccountFilter {
+            		status: Some(AccountStatus::Frozen),
+            		..all
+        		};
+        		assert_eq!(listed(&bank, &frozen, 0, 10)?, numbers[2..3]);
+        		Ok(())
+    		}
+    		check(open_bank()?)?;
+    		check(Bank::in_memory())
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn accounts_serialize_without_the_pin_hash() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (account, _) = new_account(&mut bank, 25)?;
+    		let json = serde_json::to_value(&account).unwrap();
+    		assert_eq!(json["account_number"], account.account_number.as_str());
+    		assert_eq!((&json["balance"], &json["status"]), (&25.into(), &"active".into()));
+    		assert!(json.get("pin_hash").is_none());
+
+    		let back: Account = serde_json::from_value(json).unwrap();
+    		assert_eq!((back.id, back.balance, back.status), (account.id, 25, AccountStatus::Active));
+    		assert!(back.pin_hash.is_empty());
+    		Ok(())
+	}
+
+	#[test]
+	fn legacy_plaintext_pin_is_upgraded() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, _) = new_account(&mut bank, 0)?;
+    		bank.storage.connection().execute(
+        		"UPDATE account SET pin='123456' WHERE account_number=?1",
+        		[&acc.account_number],
+    		)?;
+    		assert!(pin::is_legacy(&bank.fetch_account(&acc.account_number)?.pin_hash));
+
+    		bank.login(&acc.account_number, "123456", None)?;
+    		let stored = bank.fetch_account(&acc.account_number)?.pin_hash;
+    		assert!(!pin::is_legacy(&stored));
+    		assert!(pin::verify(&stored, "123456"));
+
+    		Ok(())
+	}
+
+	#[test]
+	fn wrong_pins_lock_the_account() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 30)?;
+    		let policy = bank.settings().lockout;
+    		for attempt in 1..=policy.max_attempts {
+        		let locked = attempt == policy.max_attempts;
+        		assert!(matches!(
+            		bank.login(&acc.account_number, "wrong", None),
+            		Err(BankError::WrongPin { locked: l }) if l == locked
+        		));
+    		}
+
+    		assert!(matches!(bank.login(&acc.account_number, &pin, None), Err(BankError::Locked)));
+    		assert!(matches!(bank.withdraw(&acc.account_number, 10, &pin), Err(BankError::Locked)));
+    		assert_eq!(bank.fetch_account(&acc.account_number)?.balance, 30);
+
+    		bank.unlock_account(&acc.account_number)?;
+    		bank.login(&acc.account_number, &pin, None)?;
+
+    		let attempts: u32 = bank.storage.connection().query_row(
+        		"SELECT COUNT(*) FROM auth_attempts WHERE account_number=?1",
+        		[&acc.account_number],
+        		|row| row.get(0),
+    		)?;
+    		assert_eq!(attempts, policy.max_attempts + 4);
+
+    		Ok(())
+	}
+
+	#[test]
+	fn lockout_runs_out() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 0)?;
+    		bank.storage.connection().execute(
+        		"UPDATE account SET locked_until = datetime('now', '-1 minutes') WHERE account_number=?1",
+        		[&acc.account_number],
+    		)?;
+    		bank.login(&acc.account_number, &pin, None)?;
+
+    		Ok(())
+	}
+
+	#[test]
+	fn chosen_pin_must_pass_policy() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let number = AccountNumber::default();
+    		assert!(matches!(
+        		bank.create_account_with_pin(&number, 0, "111111"),
+        		Err(BankError::WeakPin(_))
+    		));
+    		assert!(matches!(
+        		bank.fetch_account(&number.to_string()),
+        		Err(BankError::AccountNotFound)
+    		));
+
+    		bank.create_account_with_pin(&number, 0, "493817")?;
+    		bank.login(&number.to_string(), "493817", None)?;
+
+    		Ok(())
+	}
+
+	#[test]
+	fn pin_change_needs_old_pin() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 0)?;
+    		assert!(matches!(
+        		bank.change_pin(&acc.account_number, "wrong", "705349"),
+        		Err(BankError::WrongPin { .. })
+    		));
+    		assert!(matches!(
+        		bank.change_pin(&acc.account_number, &pin, "123456"),
+        		Err(BankError::WeakPin(_))
+    		));
+    		bank.change_pin(&acc.account_number, &pin, "705349")?;
+
+    		bank.login(&acc.account_number, "705349", None)?;
+    		assert!(bank.login(&acc.account_number, &pin, None).is_err());
+
+    		Ok(())
+	}
+
+	fn current_code(bank: &Bank, account_number: &str) -> BankResult<String> {
+    		let secret = bank.totp_secret(account_number)?.unwrap();
+    		Ok(totp::hotp(totp::Algorithm::Sha1, &secret, totp::step_at(totp::now()), totp::DIGITS))
+	}
+
+	#[test]
+	fn totp_guards_login_and_large_transfers() -> BankResult<()> {
+    		let mut bank = open_bank()?;
+    		let (acc, pin) = new_account(&mut bank, 5000)?;
+    		let (other, _) = new_account(&mut bank, 0)?;
+    		let uri = bank.begin_totp_enrollment(&acc.account_number, &pin)?;
+    		assert!(uri.starts_with("otpauth://totp/"));
+    		assert!(!bank.totp_enabled(&acc.account_number)?);
+
+    		let code = current_code(&bank, &acc.account_number)?;
+    		let recovery_codes = bank.confirm_totp_enrollment(&acc.account_number, &code)?;
+    		assert_eq!(recovery_codes.len(), 10);
+
+    		assert!(matches!(
+        		bank.login(&acc.account_number, &pin, None),
+        		Err(BankError::SecondFactorRequired)
+    		));
+    		// The enrollment code was used up
+    		assert!(matches!(
+        		bank.login(&acc.account_number, &pin, Some(&code)),
+        		Err(BankError::WrongCode { locked: false })
+    		));
+    		bank.login(&acc.account_number, &pin, Some(&recovery_codes[0]))?;
+    		assert!(bank.login(&acc.account_number, &pin, Some(&recovery_codes[0])).is_err());
+
+    		bank.transfer(&acc.account_number, &other.account_number, 10, &pin, None)?;
+    		assert!(matches!(
+        		bank.transfer(&acc.account_number, &other.account_number, 2000, &pin, None),
+        		Err(BankError::SecondFactorRequired)
+    		));
+    		assert_eq!(bank.fetch_account(&other.account_number)?.balance, 10);
+    		bank.transfer(&acc.account_number, &other.account_number, 2000, &pin, Some(&recovery_codes[1]))?;
+    		assert_eq!(bank.fetch_account(&other.account_number)?.balance, 2010);
+
+    		Ok(())
+	}
+
+	/// Runs this test binary again as `STRESS_WORKERS` tellers, each making
+	/// random transfers between the same accounts of one database file
+	#[test]
+	fn concurrent_transfers_conserve_money() -> BankResult<()> {
+    		let dir = std::env::temp_dir().join(format!("bank-stress-{}", std::process::id()));
+    		std::fs::create_dir_all(&dir).unwrap();
+    		let path = dir.join("bank.s3db");
+    		let mut bank = Bank::open(&path)?;
+    		// Plaintext PINs from before hashing are checked without Argon2, which
+    		// leaves the tellers time for little else than racing each other
+    		for _ in 0..STRESS_ACCOUNTS {
+    		    let number = AccountNumber::default().to_string();
+    		    bank.storage_mut().insert_account(&number, STRESS_PIN, 1000)?;
+    		}
+
+    		let workers: Vec<_> = (0..STRESS_WORKERS)
+    		    .map(|_| {
+    		        std::process::Command::new(std::env::current_exe().unwrap())
+    		            .args(["--exact", "database::tests::stress_worker", "--test-threads=1"])
+    		            .env("BANK_STRESS_DB", &path)
+    		            .stdout(std::process::Stdio::null())
+    		            .spawn()
+    		            .unwrap()
+    		    })
+    		    .collect();
+    		for mut worker in workers {
+    		    assert!(worker.wait().unwrap().success());
+    		}
+
+    		let db = bank.storage.connection();
+    		let (total, lowest): (i64, i64) =
+    		    db.query_row("SELECT SUM(balance), MIN(balance) FROM account", [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    		assert_eq!(total, 1000 * STRESS_ACCOUNTS as i64);
+    		assert!(lowest >= 0);
+    		// Every balance is what its ledger says, so no update got lost
+    		let mismatched: i64 = db.query_row(
+    		    "SELECT COUNT(*) FROM account WHERE balance != 1000 + (
+    		        SELECT COALESCE(SUM(CASE kind WHEN 'transfer_in' THEN amount ELSE -amount END), 0)
+    		        FROM ledger WHERE ledger.account_number = account.account_number)",
+    		    [],
+    		    |row| row.get(0),
+    		)?;
+    		assert_eq!(mismatched, 0);
+    		let transfers: i64 = db.query_row("SELECT COUNT(*) FROM ledger WHERE kind = 'transfer_in'", [], |row| row.get(0))?;
+    		assert!(transfers > 0);
+
+    		drop(bank);
+    		std::fs::remove_dir_all(&dir).unwrap();
+    		Ok(())
+	}
+
+	const STRESS_ACCOUNTS: usize = 4;
+	const STRESS_WORKERS: usize = 4;
+	const STRESS_ROUNDS: usize = 500;
+	const STRESS_PIN: &str = "591732";
+
+	/// One teller of `concurrent_transfers_conserve_money`. Does nothing
+	/// unless `BANK_STRESS_DB` is set.
+	#[test]
+	fn stress_worker() -> BankResult<()> {
+    		let Ok(path) = std::env::var("BANK_STRESS_DB") else {
+    		    return Ok(());
+    		};
+    		let mut bank = Bank::open(path)?;
+    		let accounts: Vec<String> = bank
+    		    .storage
+    		    .connection()
+    		    .prepare("SELECT account_number FROM account")?
+    		    .query_map([], |row| row.get(0))?
+    		    .collect::<SqlResult<_>>()?;
+
+    		let mut rng = thread_rng();
+    		for _ in 0..STRESS_ROUNDS {
+    		    let mut pair = accounts.choose_multiple(&mut rng, 2);
+    		    let (from, to) = (pair.next().unwrap(), pair.next().unwrap());
+    		    match bank.transfer(from, to, rng.gen_range(1..=600), STRESS_PIN, None) {
+    		        Ok(_) | Err(BankError::InsufficientFunds) => {}
+    		        Err(e) => return Err(e),
+    		    }
+    		}
+    		Ok(())
+	}
+}
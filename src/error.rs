@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: Unlicense
+
+use crate::database::AccountStatus;
+use crate::pin::WeakPin;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub type BankResult<T> = Result<T, BankError>;
+
+/// Why a bank operation did not go through
+#[derive(Debug)]
+pub enum BankError {
+    /// The PIN is wrong. `locked` if this attempt used up the last try.
+    WrongPin { locked: bool },
+    /// The authenticator or recovery code is wrong or was already used
+    WrongCode { locked: bool },
+    /// The account enrolled in TOTP and this operation needs a code
+    SecondFactorRequired,
+    /// The token is malformed, expired, or from before a PIN change
+    InvalidToken,
+    /// Too many wrong PINs or codes; the account is locked for now
+    Locked,
+    InsufficientFunds,
+    /// The amount is above the limit for the operation, which is given
+    LimitExceeded(u64),
+    AccountNotFound,
+    /// Another account already has the number
+    AccountExists,
+    /// Zero, or too large to be stored
+    InvalidAmount,
+    /// The status of the account does not allow the operation
+    Unavailable(AccountStatus),
+    /// Transfers and payouts need two different accounts
+    SameAccount,
+    /// The receiving account does not exist
+    RecipientNotFound,
+    /// The status or balance of the receiving account does not allow the money in
+    InvalidRecipient,
+    /// The account still holds money and no payout account was given
+    BalanceRemaining(u64),
+    WeakPin(WeakPin),
+    /// The new PIN is the same as the old one
+    PinUnchanged,
+    TotpAlreadyEnabled,
+    TotpNotEnabled,
+    /// Closed is only reached through account closure and never left
+    StatusChangeNotAllowed,
+    /// The database was written by a newer version of the bank
+    SchemaTooNew { found: u32, supported: u32 },
+    /// Schema versions can only be raised, up to the latest one
+    InvalidMigrationTarget(u32),
+    /// The database failed
+    Storage(rusqlite::Error),
+}
+
+impl BankError {
+    /// Exit code of the command line for this error, so scripts can tell
+    /// refusals apart. 1 is left for failures of the database or the
+    /// environment, and 2 for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BankError::WrongPin { .. }
+            | BankError::WrongCode { .. }
+            | BankError::SecondFactorRequired
+            | BankError::InvalidToken => 3,
+            BankError::Locked => 4,
+            BankError::InsufficientFunds => 5,
+            BankError::AccountNotFound | BankError::RecipientNotFound => 6,
+            BankError::Unavailable(_) => 7,
+            BankError::SchemaTooNew { .. } | BankError::Storage(_) => 1,
+            _ => 8,
+        }
+    }
+
+    /// Stable name of the error for machine-readable output
+    pub fn name(&self) -> &'static str {
+        match self {
+            BankError::WrongPin { .. } => "wrong_pin",
+            BankError::WrongCode { .. } => "wrong_code",
+            BankError::SecondFactorRequired => "second_factor_required",
+            BankError::InvalidToken => "invalid_token",
+            BankError::Locked => "locked",
+            BankError::InsufficientFunds => "insufficient_funds",
+            BankError::LimitExceeded(_) => "limit_exceeded",
+            BankError::AccountNotFound => "account_not_found",
+            BankError::AccountExists => "account_exists",
+            BankError::InvalidAmount => "invalid_amount",
+            BankError::Unavailable(_) => "unavailable",
+            BankError::SameAccount => "same_account",
+            BankError::RecipientNotFound => "recipient_not_found",
+            BankError::InvalidRecipient => "invalid_recipient",
+            BankError::BalanceRemaining(_) => "balance_remaining",
+            BankError::WeakPin(_) => "weak_pin",
+            BankError::PinUnchanged => "pin_unchanged",
+            BankError::TotpAlreadyEnabled => "totp_already_enabled",
+            BankError::TotpNotEnabled => "totp_not_enabled",
+            BankError::StatusChangeNotAllowed => "status_change_not_allowed",
+            BankError::SchemaTooNew { .. } => "schema_too_new",
+            BankError::InvalidMigrationTarget(_) => "invalid_migration_target",
+            BankError::Storage(_) => "storage",
+        }
+    }
+}
+
+impl Display for BankError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            BankError::WrongPin { locked: true } => {
+                write!(f, "Wrong pin. Too many wrong attempts, the account is now locked...")
+            }
+            BankError::WrongPin { .. } => write!(f, "Wrong pin. Try again..."),
+            BankError::WrongCode { locked: true } => {
+                write!(f, "Wrong code. Too many wrong attempts, the account is now locked...")
+            }
+            BankError::WrongCode { .. } => write!(f, "Wrong code. Try again..."),
+            BankError::SecondFactorRequired => {
+                write!(f, "An authenticator code or recovery code is needed...")
+            }
+            BankError::InvalidToken => write!(f, "The token is invalid or expired. Log in again..."),
+            BankError::Locked => {
+                write!(f, "Too many wrong pins. The account is locked, try again later...")
+            }
+            BankError::InsufficientFunds => write!(
+                f,
+                "You are trying to move an amount that exceeds your current balance... aborting..."
+            ),
+            BankError::LimitExceeded(limit) => write!(
+                f,
+                "The amount is above the limit of `{}` for this operation... aborting...",
+                limit
+            ),
+            BankError::AccountNotFound => write!(f, "No such account"),
+            BankError::AccountExists => write!(f, "An account with this number already exists"),
+            BankError::InvalidAmount => write!(f, "Invalid amount. Please try again..."),
+            BankError::Unavailable(status) => write!(f, "This account is {}", status),
+            BankError::SameAccount => write!(f, "Cannot move money to the same account!"),
+            BankError::RecipientNotFound => write!(f, "The receiving account does not exist... aborting..."),
+            BankError::InvalidRecipient => write!(f, "The receiving account cannot receive money... aborting..."),
+            BankError::BalanceRemaining(balance) => write!(
+                f,
+                "The account still holds `{}`. Withdraw it or give a payout account... aborting...",
+                balance
+            ),
+            BankError::WeakPin(weak) => write!(f, "{}. Choose another one...", weak),
+            BankError::PinUnchanged => write!(f, "The new PIN is the same as the old one..."),
+            BankError::TotpAlreadyEnabled => {
+                write!(f, "An authenticator is already set up for this account.")
+            }
+            BankError::TotpNotEnabled => write!(f, "No authenticator is set up for this account."),
+            BankError::StatusChangeNotAllowed => {
+                write!(f, "Closed accounts can only be reached through account closure.")
+            }
+            BankError::SchemaTooNew { found, supported } => write!(
+                f,
+                "The database has schema version {}, but this bank only knows up to {}. Upgrade the bank.",
+                found, supported
+            ),
+            BankError::InvalidMigrationTarget(target) => write!(
+                f,
+                "Cannot migrate to schema version {}: only upgrades up to the latest version are possible.",
+                target
+            ),
+            BankError::Storage(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl Error for BankError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BankError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for BankError {
+    fn from(e: rusqlite::Error) -> Self {
+        BankError::Storage(e)
+    }
+}
+
+impl From<WeakPin> for BankError {
+    fn from(weak: WeakPin) -> Self {
+        BankError::WeakPin(weak)
+    }
+}
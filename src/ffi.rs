@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: Unlicense
+
+//! C API for the teller application, declared in `include/bank.h`. Every
+//! function returns a `BankCode`, writes its results through pointers, and
+//! leaves the message of a failure for `bank_last_error`. Strings are
+//! NUL-terminated UTF-8 and stay owned by the caller.
+
+use crate::database::{self, Auth, Bank};
+use crate::error::BankError;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// Bytes a buffer needs for any account number and its NUL
+pub const BANK_ACCOUNT_CAPACITY: usize = 16;
+
+/// Outcome of a call. The values are part of the API and never change;
+/// new codes are only ever added.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankCode {
+    Ok = 0,
+    /// A pointer that has to be set was NULL
+    NullArgument = 1,
+    /// A string was not UTF-8
+    InvalidUtf8 = 2,
+    /// The buffer given for the result is too small
+    BufferTooSmall = 3,
+    /// The bank failed in a way it did not foresee
+    Panic = 4,
+    WrongPin = 10,
+    WrongCode = 11,
+    SecondFactorRequired = 12,
+    InvalidToken = 13,
+    Locked = 14,
+    InsufficientFunds = 15,
+    LimitExceeded = 16,
+    AccountNotFound = 17,
+    AccountExists = 18,
+    InvalidAmount = 19,
+    Unavailable = 20,
+    SameAccount = 21,
+    InvalidRecipient = 22,
+    BalanceRemaining = 23,
+    WeakPin = 24,
+    PinUnchanged = 25,
+    TotpAlreadyEnabled = 26,
+    TotpNotEnabled = 27,
+    StatusChangeNotAllowed = 28,
+    SchemaTooNew = 29,
+    InvalidMigrationTarget = 30,
+    Storage = 31,
+    RecipientNotFound = 32,
+}
+
+impl From<&BankError> for BankCode {
+    fn from(e: &BankError) -> Self {
+        match e {
+            BankError::WrongPin { .. } => BankCode::WrongPin,
+            BankError::WrongCode { .. } => BankCode::WrongCode,
+            BankError::SecondFactorRequired => BankCode::SecondFactorRequired,
+            BankError::InvalidToken => BankCode::InvalidToken,
+            BankError::Locked => BankCode::Locked,
+            BankError::InsufficientFunds => BankCode::InsufficientFunds,
+            BankError::LimitExceeded(_) => BankCode::LimitExceeded,
+            BankError::AccountNotFound => BankCode::AccountNotFound,
+            BankError::AccountExists => BankCode::AccountExists,
+            BankError::InvalidAmount => BankCode::InvalidAmount,
+            BankError::Unavailable(_) => BankCode::Unavailable,
+            BankError::SameAccount => BankCode::SameAccount,
+            BankError::RecipientNotFound => BankCode::RecipientNotFound,
+            BankError::InvalidRecipient => BankCode::InvalidRecipient,
+            BankError::BalanceRemaining(_) => BankCode::BalanceRemaining,
+            BankError::WeakPin(_) => BankCode::WeakPin,
+            BankError::PinUnchanged => BankCode::PinUnchanged,
+            BankError::TotpAlreadyEnabled => BankCode::TotpAlreadyEnabled,
+            BankError::TotpNotEnabled => BankCode::TotpNotEnabled,
+            BankError::StatusChangeNotAllowed => BankCode::StatusChangeNotAllowed,
+            BankError::SchemaTooNew { .. } => BankCode::SchemaTooNew,
+            BankError::InvalidMigrationTarget(_) => BankCode::InvalidMigrationTarget,
+            BankError::Storage(_) => BankCode::Storage,
+        }
+    }
+}
+
+impl BankCode {
+    /// Stable name of the code, the same as the `error` of `--output json`
+    fn name(self) -> &'static CStr {
+        match self {
+            BankCode::Ok => c"ok",
+            BankCode::NullArgument => c"null_argument",
+            BankCode::InvalidUtf8 => c"invalid_utf8",
+            BankCode::BufferTooSmall => c"buffer_too_small",
+            BankCode::Panic => c"panic",
+            BankCode::WrongPin => c"wrong_pin",
+            BankCode::WrongCode => c"wrong_code",
+            BankCode::SecondFactorRequired => c"second_factor_required",
+            BankCode::InvalidToken => c"invalid_token",
+            BankCode::Locked => c"locked",
+            BankCode::InsufficientFunds => c"insufficient_funds",
+            BankCode::LimitExceeded => c"limit_exceeded",
+            BankCode::AccountNotFound => c"account_not_found",
+            BankCode::AccountExists => c"account_exists",
+            BankCode::InvalidAmount => c"invalid_amount",
+            BankCode::Unavailable => c"unavailable",
+            BankCode::SameAccount => c"same_account",
+            BankCode::RecipientNotFound => c"recipient_not_found",
+            BankCode::InvalidRecipient => c"invalid_recipient",
+            BankCode::BalanceRemaining => c"balance_remaining",
+            BankCode::WeakPin => c"weak_pin",
+            BankCode::PinUnchanged => c"pin_unchanged",
+            BankCode::TotpAlreadyEnabled => c"totp_already_enabled",
+            BankCode::TotpNotEnabled => c"totp_not_enabled",
+            BankCode::StatusChangeNotAllowed => c"status_change_not_allowed",
+            BankCode::SchemaTooNew => c"schema_too_new",
+            BankCode::InvalidMigrationTarget => c"invalid_migration_target",
+            BankCode::Storage => c"storage",
+        }
+    }
+}
+
+/// An open bank database, owned by the caller until `bank_free`
+pub struct BankHandle(Bank);
+
+/// Why a call failed, before it is handed to C
+struct Failure(BankCode, String);
+
+impl From<BankError> for Failure {
+    fn from(e: BankError) -> Self {
+        Failure(BankCode::from(&e), e.to_string())
+    }
+}
+
+impl Failure {
+    fn null(name: &str) -> Self {
+        Failure(BankCode::NullArgument, format!("`{}` is NULL", name))
+    }
+}
+
+thread_local! {
+    /// Message of the last failed call on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Running `f` as the body of a call: its failure, or a panic, becomes the
+/// code returned and the last error
+fn run(f: impl FnOnce() -> Result<(), Failure>) -> BankCode {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(Failure(BankCode::Panic, "The bank failed unexpectedly".to_string())));
+    let (code, message) = match result {
+        Ok(()) => (BankCode::Ok, None),
+        Err(Failure(code, message)) => (code, CString::new(message).ok()),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+/// The string at `ptr`, which has to be set
+///
+/// # Safety
+///
+/// `ptr` is NULL or points to a NUL-terminated string.
+unsafe fn text<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    optional_text(ptr, name)?.ok_or_else(|| Failure::null(name))
+}
+
+/// The string at `ptr`, if there is one
+///
+/// # Safety
+///
+/// `ptr` is NULL or points to a NUL-terminated string.
+unsafe fn optional_text<'a>(ptr: *const c_char, name: &str) -> Result<Option<&'a str>, Failure> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(Some)
+        .map_err(|_| Failure(BankCode::InvalidUtf8, format!("`{}` is not UTF-8", name)))
+}
+
+/// The bank behind `handle`
+///
+/// # Safety
+///
+/// `handle` is NULL or comes from `bank_open` and was not freed.
+unsafe fn bank<'a>(handle: *mut BankHandle) -> Result<&'a mut Bank, Failure> {
+    handle.as_mut().map(|handle| &mut handle.0).ok_or_else(|| Failure::null("bank"))
+}
+
+/// Writing `value` to `out`, which has to be set
+///
+/// # Safety
+///
+/// `out` is NULL or valid for writes.
+unsafe fn write<T>(out: *mut T, value: T, name: &str) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::null(name));
+    }
+    out.write(value);
+    Ok(())
+}
+
+/// Opens the bank database at `path`, creating it if needed, and stores
+/// the handle in `*out`.
+///
+/// # Safety
+///
+/// `path` is a NUL-terminated string and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_open(path: *const c_char, out: *mut *mut BankHandle) -> BankCode {
+    run(|| {
+        let path = text(path, "path")?;
+        if out.is_null() {
+            return Err(Failure::null("out"));
+        }
+        let bank = Bank::open(path)?;
+        write(out, Box::into_raw(Box::new(BankHandle(bank))), "out")
+    })
+}
+
+/// Closes a bank from `bank_open`. NULL is ignored.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open` and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bank_free(bank: *mut BankHandle) {
+    if !bank.is_null() {
+        drop(Box::from_raw(bank));
+    }
+}
+
+/// Opens an empty account with the customer's `pin` and writes its number
+/// to `account`, a buffer of `capacity` bytes; `BANK_ACCOUNT_CAPACITY` is
+/// always enough.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open`, `pin` is a NUL-terminated string, and
+/// `account` is valid for writes of `capacity` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_create_account(
+    bank: *mut BankHandle,
+    pin: *const c_char,
+    account: *mut c_char,
+    capacity: usize,
+) -> BankCode {
+    run(|| {
+        let bank = self::bank(bank)?;
+        let pin = text(pin, "pin")?;
+        if account.is_null() {
+            return Err(Failure::null("account"));
+        }
+        if capacity < BANK_ACCOUNT_CAPACITY {
+            return Err(Failure(
+                BankCode::BufferTooSmall,
+                format!("An account number needs {} bytes", BANK_ACCOUNT_CAPACITY),
+            ));
+        }
+        let (number, ()) = database::with_fresh_number(|number| bank.create_account_with_pin(number, 0, pin))?;
+        let number = CString::new(number.to_string()).expect("account numbers are digits");
+        let bytes = number.as_bytes_with_nul();
+        ptr::copy_nonoverlapping(bytes.as_ptr().cast(), account, bytes.len());
+        Ok(())
+    })
+}
+
+/// Writes the balance of `account` to `*balance`. `code` is the
+/// authenticator or recovery code of accounts enrolled in TOTP, or NULL.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open`, the strings are NUL-terminated or NULL
+/// where allowed, and `balance` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_balance(
+    bank: *mut BankHandle,
+    account: *const c_char,
+    pin: *const c_char,
+    code: *const c_char,
+    balance: *mut u64,
+) -> BankCode {
+    run(|| {
+        let bank = self::bank(bank)?;
+        let (account, pin) = (text(account, "account")?, text(pin, "pin")?);
+        let code = optional_text(code, "code")?;
+        write(balance, bank.check_balance(account, Auth::Pin(pin), code)?, "balance")
+    })
+}
+
+/// Pays `amount` into `account` and writes the new balance to `*balance`.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open`, the strings are NUL-terminated, and
+/// `balance` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_deposit(
+    bank: *mut BankHandle,
+    account: *const c_char,
+    amount: u64,
+    pin: *const c_char,
+    balance: *mut u64,
+) -> BankCode {
+    run(|| {
+        let bank = self::bank(bank)?;
+        let (account, pin) = (text(account, "account")?, text(pin, "pin")?);
+        write(balance, bank.deposit(account, amount, pin)?, "balance")
+    })
+}
+
+/// Pays `amount` out of `account` and writes the new balance to `*balance`.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open`, the strings are NUL-terminated, and
+/// `balance` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_withdraw(
+    bank: *mut BankHandle,
+    account: *const c_char,
+    amount: u64,
+    pin: *const c_char,
+    balance: *mut u64,
+) -> BankCode {
+    run(|| {
+        let bank = self::bank(bank)?;
+        let (account, pin) = (text(account, "account")?, text(pin, "pin")?);
+        write(balance, bank.withdraw(account, amount, pin)?, "balance")
+    })
+}
+
+/// Moves `amount` from `from` to `to` and writes the new balance of `from`
+/// to `*balance`. `code` is needed from the TOTP threshold up on accounts
+/// enrolled in TOTP, and may be NULL otherwise.
+///
+/// # Safety
+///
+/// `bank` comes from `bank_open`, the strings are NUL-terminated or NULL
+/// where allowed, and `balance` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bank_transfer(
+    bank: *mut BankHandle,
+    from: *const c_char,
+    to: *const c_char,
+    amount: u64,
+    pin: *const c_char,
+    code: *const c_char,
+    balance: *mut u64,
+) -> BankCode {
+    run(|| {
+        let bank = self::bank(bank)?;
+        let (from, to, pin) = (text(from, "from")?, text(to, "to")?, text(pin, "pin")?);
+        let code = optional_text(code, "code")?;
+        write(balance, bank.transfer(from, to, amount, pin, code)?, "balance")
+    })
+}
+
+/// Stable name of `code`, such as `insufficient_funds`. The string is
+/// static and must not be freed.
+#[no_mangle]
+pub extern "C" fn bank_code_name(code: BankCode) -> *const c_char {
+    code.name().as_ptr()
+}
+
+/// Message of the last failed call on this thread, or NULL after a call
+/// that went through. The string lives until the next call on the thread
+/// and must not be freed.
+#[no_mangle]
+pub extern "C" fn bank_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_leave_a_code_and_a_message() {
+        let dir = std::env::temp_dir().join(format!("bank-ffi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = CString::new(dir.join("bank.s3db").to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut bank = ptr::null_mut();
+            assert_eq!(bank_open(path.as_ptr(), &mut bank), BankCode::Ok);
+            assert!(bank_last_error().is_null());
+
+            let mut account = [0 as c_char; BANK_ACCOUNT_CAPACITY];
+            let pin = c"591732".as_ptr();
+            let created = bank_create_account(bank, pin, account.as_mut_ptr(), account.len());
+            assert_eq!(created, BankCode::Ok);
+
+            let mut balance = 0;
+            assert_eq!(bank_deposit(bank, account.as_ptr(), 50, pin, &mut balance), BankCode::Ok);
+            assert_eq!(balance, 50);
+            let code = bank_withdraw(bank, account.as_ptr(), 80, pin, &mut balance);
+            assert_eq!(code, BankCode::InsufficientFunds);
+            assert_eq!(CStr::from_ptr(bank_code_name(code)), c"insufficient_funds");
+            assert!(!bank_last_error().is_null());
+
+            let code = bank_balance(bank, account.as_ptr(), ptr::null(), ptr::null(), &mut balance);
+            assert_eq!(code, BankCode::NullArgument);
+            assert_eq!(CStr::from_ptr(bank_last_error()), c"`pin` is NULL");
+            let short = bank_create_account(bank, pin, account.as_mut_ptr(), 4);
+            assert_eq!(short, BankCode::BufferTooSmall);
+            bank_free(bank);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn codes_follow_the_errors() {
+        assert_eq!(BankCode::from(&BankError::Locked), BankCode::Locked);
+        for e in [BankError::WrongPin { locked: true }, BankError::AccountNotFound, BankError::InvalidAmount] {
+            assert_eq!(BankCode::from(&e).name().to_str().unwrap(), e.name());
+        }
+    }
+}
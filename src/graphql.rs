@@ -0,0 +1,469 @@
+// SPDX-License-Identifier: Unlicense
+
+//! GraphQL endpoint of `bank serve` for dashboards, at `/graphql`. Queries
+//! and mutations are posted as JSON with a token from the REST API as
+//! `Authorization: Bearer <token>`, and reach the account of that token.
+//! The staff key, if the server was given one, reads every account instead.
+//! Subscriptions are answered as server-sent events when the request accepts
+//! `text/event-stream`. The schema is served at `/graphql/schema.graphql`.
+
+use crate::database::{self, Auth, Bank};
+use crate::error::{BankError, BankResult};
+use crate::rest::{self, SharedBank};
+use crate::storage::{AccountFilter, LedgerEntry, PostingKind, Storage};
+use async_graphql::connection::{Connection, CursorType, Edge};
+use async_graphql::{Context, Enum, ErrorExtensions, InputObject, Object, Schema, SimpleObject, Subscription};
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::{stream, Stream, StreamExt};
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::Notify;
+
+/// Accounts or transactions returned when the query gives no `first`
+const DEFAULT_PAGE: usize = 20;
+/// Most accounts or transactions returned at once
+const MAX_PAGE: usize = 100;
+/// How often a subscription looks for postings made by other processes
+const POLL: Duration = Duration::from_millis(500);
+
+type Result<T> = async_graphql::Result<T>;
+
+/// The schema on a bank kept in `S`
+type BankSchema<S> = Schema<Query<S>, Mutation<S>, Subscription<S>>;
+
+/// Who sent the request
+#[derive(Debug, Clone)]
+enum Caller {
+    Staff,
+    Token(String),
+    Anonymous,
+}
+
+/// What the resolvers share: the bank, and a signal for subscriptions
+/// after every mutation
+struct Shared<S> {
+    bank: SharedBank<S>,
+    posted: Arc<Notify>,
+}
+
+/// A refusal of the bank as a GraphQL error, with the `error` name of
+/// `--output json` as extension
+fn refusal(e: BankError) -> async_graphql::Error {
+    async_graphql::Error::new(e.to_string()).extend_with(|_, extensions| extensions.set("error", e.name()))
+}
+
+fn shared<'a, S: Storage + Send + 'static>(ctx: &Context<'a>) -> &'a Shared<S> {
+    ctx.data_unchecked::<Shared<S>>()
+}
+
+async fn with_bank<S, T>(
+    ctx: &Context<'_>,
+    f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+) -> Result<T>
+where
+    S: Storage + Send + 'static,
+    T: Send + 'static,
+{
+    rest::blocking(shared::<S>(ctx).bank.clone(), f).await.map_err(refusal)
+}
+
+/// Fetching an account the caller may read: any for the staff, the one
+/// of the token otherwise
+async fn readable_account<S: Storage + Send + 'static>(ctx: &Context<'_>, number: String) -> Result<Account<S>> {
+    let caller = ctx.data_unchecked::<Caller>().clone();
+    let account = with_bank(ctx, move |bank: &mut Bank<S>| {
+        match caller {
+            Caller::Staff => {}
+            Caller::Token(token) => {
+                bank.check_balance(&number, Auth::Token(&token), None)?;
+            }
+            Caller::Anonymous => return Err(BankError::InvalidToken),
+        }
+        bank.fetch_account(&number)
+    })
+    .await?;
+    Ok(Account::new(account))
+}
+
+/// The token of the caller, which mutations need whoever sends them
+fn token(ctx: &Context<'_>) -> Result<String> {
+    match ctx.data_unchecked::<Caller>() {
+        Caller::Token(token) => Ok(token.clone()),
+        _ => Err(refusal(BankError::InvalidToken)),
+    }
+}
+
+/// Number of items asked for with `first`, within bounds
+fn page_size(first: Option<i32>) -> Result<usize> {
+    match first {
+        None => Ok(DEFAULT_PAGE),
+        Some(first) if first >= 0 => Ok((first as usize).min(MAX_PAGE)),
+        Some(_) => Err("`first` cannot be negative".into()),
+    }
+}
+
+/// Id the cursor `after` points at, or zero from the start
+fn decode_cursor(after: Option<String>) -> Result<u64> {
+    after.map_or(Ok(0), |after| {
+        u64::decode_cursor(&after).map_err(|_| async_graphql::Error::new(format!("`{}` is not a cursor", after)))
+    })
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "crate::database::AccountStatus")]
+enum Status {
+    Active,
+    Frozen,
+    Dormant,
+    BlockedDebit,
+    BlockedCredit,
+    Closed,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "crate::storage::PostingKind", name = "PostingKind")]
+enum Kind {
+    Deposit,
+    Withdrawal,
+    TransferOut,
+    TransferIn,
+    Fee,
+}
+
+/// Which accounts to list. Unset fields match every account.
+#[derive(InputObject, Default)]
+struct AccountsFilter {
+    status: Option<Status>,
+    min_balance: Option<u64>,
+    max_balance: Option<u64>,
+}
+
+impl From<AccountsFilter> for AccountFilter {
+    fn from(filter: AccountsFilter) -> Self {
+        AccountFilter {
+            status: filter.status.map(Into::into),
+            min_balance: filter.min_balance,
+            max_balance: filter.max_balance,
+        }
+    }
+}
+
+/// A line of the ledger of an account
+#[derive(SimpleObject)]
+struct Transaction {
+    id: u64,
+    kind: Kind,
+    amount: u64,
+    /// The other account of a transfer
+    counterparty: Option<String>,
+    /// `YYYY-MM-DD HH:MM:SS` in UTC
+    created_at: String,
+}
+
+impl From<LedgerEntry> for Transaction {
+    fn from(entry: LedgerEntry) -> Self {
+        Transaction {
+            id: entry.id,
+            kind: entry.kind.into(),
+            amount: entry.amount,
+            counterparty: entry.counterparty,
+            created_at: entry.created_at,
+        }
+    }
+}
+
+/// An account the caller may read
+struct Account<S> {
+    account: database::Account,
+    storage: PhantomData<fn() -> S>,
+}
+
+impl<S> Account<S> {
+    fn new(account: database::Account) -> Self {
+        Self {
+            account,
+            storage: PhantomData,
+        }
+    }
+}
+
+#[Object]
+impl<S: Storage + Send + 'static> Account<S> {
+    async fn number(&self) -> &str {
+        &self.account.account_number
+    }
+
+    async fn status(&self) -> Status {
+        self.account.status.into()
+    }
+
+    async fn balance(&self) -> u64 {
+        self.account.balance
+    }
+
+    async fn closed_at(&self) -> Option<&str> {
+        self.account.closed_at.as_deref()
+    }
+
+    async fn last_activity_at(&self) -> Option<&str> {
+        self.account.last_activity_at.as_deref()
+    }
+
+    /// Transactions of the account, oldest first
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        kind: Option<Kind>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<u64, Transaction>> {
+        let first = page_size(first)?;
+        let after = decode_cursor(after)?;
+        let number = self.account.account_number.clone();
+        let kind: Option<PostingKind> = kind.map(Into::into);
+        // One more than asked for tells whether there is a next page
+        let entries = with_bank(ctx, move |bank: &mut Bank<S>| {
+            let mut entries = Vec::new();
+            let mut cursor = after;
+            loop {
+                let batch = bank.storage().ledger_since(&number, cursor, MAX_PAGE)?;
+                let exhausted = batch.len() < MAX_PAGE;
+                for entry in batch {
+                    cursor = entry.id;
+                    if kind.is_none_or(|kind| entry.kind == kind) && entries.len() <= first {
+                        entries.push(entry);
+                    }
+                }
+                if exhausted || entries.len() > first {
+                    return Ok(entries);
+                }
+            }
+        })
+        .await?;
+
+        let mut connection = Connection::new(after > 0, entries.len() > first);
+        connection.edges.extend(
+            entries
+                .into_iter()
+                .take(first)
+                .map(|entry| Edge::new(entry.id, Transaction::from(entry))),
+        );
+        Ok(connection)
+    }
+}
+
+struct Query<S>(PhantomData<fn() -> S>);
+
+#[Object]
+impl<S: Storage + Send + 'static> Query<S> {
+    /// The account with this number
+    async fn account(&self, ctx: &Context<'_>, number: String) -> Result<Account<S>> {
+        readable_account(ctx, number).await
+    }
+
+    /// Accounts in the order they were opened. Needs the staff key.
+    async fn accounts(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AccountsFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<u64, Account<S>>> {
+        if !matches!(ctx.data_unchecked::<Caller>(), Caller::Staff) {
+            return Err("Listing accounts needs the staff key".into());
+        }
+        let first = page_size(first)?;
+        let after = decode_cursor(after)?;
+        let filter = AccountFilter::from(filter.unwrap_or_default());
+        let accounts = with_bank(ctx, move |bank: &mut Bank<S>| {
+            bank.storage().accounts(&filter, after, first + 1)
+        })
+        .await?;
+
+        let mut connection = Connection::new(after > 0, accounts.len() > first);
+        connection.edges.extend(
+            accounts
+                .into_iter()
+                .take(first)
+                .map(|account| Edge::new(account.id, Account::new(account))),
+        );
+        Ok(connection)
+    }
+}
+
+/// Mutations go through the same checks as the other front ends and need
+/// the token of the account money leaves or enters
+struct Mutation<S>(PhantomData<fn() -> S>);
+
+impl<S: Storage + Send + 'static> Mutation<S> {
+    /// Running a posting with the token of the caller and fetching the account
+    async fn post(
+        ctx: &Context<'_>,
+        number: String,
+        f: impl FnOnce(&mut Bank<S>, &str, Auth<'_>) -> BankResult<u64> + Send + 'static,
+    ) -> Result<Account<S>> {
+        let token = token(ctx)?;
+        let account = with_bank(ctx, move |bank: &mut Bank<S>| {
+            f(bank, &number, Auth::Token(&token))?;
+            bank.fetch_account(&number)
+        })
+        .await;
+        shared::<S>(ctx).posted.notify_waiters();
+        Ok(Account::new(account?))
+    }
+}
+
+#[Object]
+impl<S: Storage + Send + 'static> Mutation<S> {
+    async fn deposit(&self, ctx: &Context<'_>, account: String, amount: u64) -> Result<Account<S>> {
+        Self::post(ctx, account, move |bank, number, auth| bank.deposit(number, amount, auth)).await
+    }
+
+    async fn withdraw(&self, ctx: &Context<'_>, account: String, amount: u64) -> Result<Account<S>> {
+        Self::post(ctx, account, move |bank, number, auth| bank.withdraw(number, amount, auth)).await
+    }
+
+    /// Transfers from the TOTP threshold up need the `code` of an account
+    /// enrolled in TOTP
+    async fn transfer(
+        &self,
+        ctx: &Context<'_>,
+        account: String,
+        to: String,
+        amount: u64,
+        code: Option<String>,
+    ) -> Result<Account<S>> {
+        Self::post(ctx, account, move |bank, number, auth| {
+            bank.transfer(number, &to, amount, auth, code.as_deref())
+        })
+        .await
+    }
+}
+
+/// New transactions on an account, and its balance after them
+#[derive(SimpleObject)]
+struct BalanceChange {
+    account: String,
+    balance: u64,
+    transactions: Vec<Transaction>,
+}
+
+struct Subscription<S>(PhantomData<fn() -> S>);
+
+#[Subscription]
+impl<S: Storage + Send + 'static> Subscription<S> {
+    /// Every change of the balance of an account from now on, whichever
+    /// process posts it
+    async fn balance_changed(
+        &self,
+        ctx: &Context<'_>,
+        account: String,
+    ) -> Result<impl Stream<Item = Result<BalanceChange>>> {
+        let number = readable_account::<S>(ctx, account).await?.account.account_number;
+        let start = number.clone();
+        let latest = with_bank(ctx, move |bank: &mut Bank<S>| bank.storage().history(&start, 1)).await?;
+        let after = latest.first().map_or(0, |entry| entry.id);
+
+        let Shared { bank, posted } = shared::<S>(ctx);
+        let (bank, posted) = (bank.clone(), posted.clone());
+        Ok(stream::unfold(after, move |after| {
+            let (bank, posted, number) = (bank.clone(), posted.clone(), number.clone());
+            async move {
+                loop {
+                    let _ = tokio::time::timeout(POLL, posted.notified()).await;
+                    let reading = number.clone();
+                    let changes = rest::blocking(bank.clone(), move |bank| bank.changes_since(&reading, after)).await;
+                    match changes {
+                        Ok((_, entries)) if entries.is_empty() => continue,
+                        Ok((balance, entries)) => {
+                            let after = entries.last().map_or(after, |entry| entry.id);
+                            let change = BalanceChange {
+                                account: number,
+                                balance,
+                                transactions: entries.into_iter().map(Into::into).collect(),
+                            };
+                            return Some((Ok(change), after));
+                        }
+                        Err(e) => return Some((Err(refusal(e)), after)),
+                    }
+                }
+            }
+        }))
+    }
+}
+
+struct Endpoint<S> {
+    schema: BankSchema<S>,
+    staff_key: Option<String>,
+}
+
+impl<S> Endpoint<S> {
+    fn caller(&self, headers: &HeaderMap) -> Caller {
+        let Some(bearer) = rest::bearer(headers).ok() else {
+            return Caller::Anonymous;
+        };
+        match &self.staff_key {
+            Some(key) if bool::from(key.as_bytes().ct_eq(bearer.as_bytes())) => Caller::Staff,
+            _ => Caller::Token(bearer),
+        }
+    }
+}
+
+async fn execute<S: Storage + Send + 'static>(
+    State(endpoint): State<Arc<Endpoint<S>>>,
+    headers: HeaderMap,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    let request = request.data(endpoint.caller(&headers));
+    let streaming = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if !streaming {
+        return Json(endpoint.schema.execute(request).await).into_response();
+    }
+
+    // GraphQL over server-sent events: a `next` event per result, then `complete`
+    let events = endpoint
+        .schema
+        .execute_stream(request)
+        .map(|response| Event::default().event("next").json_data(response))
+        .chain(stream::once(async { Ok(Event::default().event("complete")) }))
+        .map(|event| Ok::<_, Infallible>(event.unwrap_or_else(|e| Event::default().event("error").data(e.to_string()))));
+    Sse::new(events).into_response()
+}
+
+async fn sdl<S: Storage + Send + 'static>(State(endpoint): State<Arc<Endpoint<S>>>) -> String {
+    endpoint.schema.sdl()
+}
+
+/// The schema on `bank`, without the data of a request
+fn schema<S: Storage + Send + 'static>(bank: SharedBank<S>) -> BankSchema<S> {
+    Schema::build(Query(PhantomData), Mutation(PhantomData), Subscription(PhantomData))
+        .data(Shared {
+            bank,
+            posted: Arc::new(Notify::new()),
+        })
+        .limit_depth(8)
+        .finish()
+}
+
+/// Routes of the GraphQL endpoint on `bank`. `staff_key` reads every account.
+pub fn router<S: Storage + Send + 'static>(bank: SharedBank<S>, staff_key: Option<String>) -> Router {
+    let endpoint = Arc::new(Endpoint {
+        schema: schema(bank),
+        staff_key,
+    });
+    Router::new()
+        .route("/graphql", post(execute::<S>))
+        .route("/graphql/schema.graphql", get(sdl::<S>))
+        .with_state(endpoint)
+}
@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: Unlicense
+
+//! gRPC `BankService` for internal services, generated from
+//! `proto/bank.proto` and served with tonic next to the REST API.
+
+use crate::database::{self, Bank};
+use crate::error::{BankError, BankResult};
+use crate::storage::{self, LedgerEntry, Storage};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Response, Status};
+
+/// Types and client generated from `proto/bank.proto`
+pub mod proto {
+    tonic::include_proto!("bank.v1");
+}
+
+use proto::bank_service_server::{BankService, BankServiceServer};
+use proto::*;
+
+/// How often a stream looks for lines posted by other processes
+const POLL: Duration = Duration::from_millis(250);
+/// Ledger lines read at once by a stream
+const BATCH: usize = 100;
+
+/// The service on a bank, shared by every call
+pub struct GrpcBank<S> {
+    bank: Arc<Mutex<Bank<S>>>,
+    /// Woken after this server posts, so streams do not wait for the next poll
+    posted: Arc<Notify>,
+}
+
+impl<S: Storage + Send + 'static> GrpcBank<S> {
+    pub fn new(bank: Bank<S>) -> Self {
+        Self {
+            bank: Arc::new(Mutex::new(bank)),
+            posted: Arc::new(Notify::new()),
+        }
+    }
+
+    /// The service, ready to be added to a tonic server
+    pub fn into_service(self) -> BankServiceServer<Self> {
+        BankServiceServer::new(self)
+    }
+
+    async fn with_bank<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+    ) -> Result<T, Status> {
+        with_bank(self.bank.clone(), f).await
+    }
+
+    /// Like `with_bank`, waking the streams afterwards
+    async fn post<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+    ) -> Result<T, Status> {
+        let result = self.with_bank(f).await;
+        self.posted.notify_waiters();
+        result
+    }
+}
+
+/// Running `f` on the bank off the async workers, since SQLite and Argon2 block
+async fn with_bank<S, T>(
+    bank: Arc<Mutex<Bank<S>>>,
+    f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+) -> Result<T, Status>
+where
+    S: Storage + Send + 'static,
+    T: Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || f(&mut bank.lock().unwrap_or_else(PoisonError::into_inner)))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    result.map_err(status)
+}
+
+/// The gRPC status closest to a refusal or failure of the bank
+fn status(e: BankError) -> Status {
+    let code = match e {
+        BankError::WrongPin { .. }
+        | BankError::WrongCode { .. }
+        | BankError::SecondFactorRequired
+        | BankError::InvalidToken => Code::Unauthenticated,
+        BankError::Locked => Code::PermissionDenied,
+        BankError::AccountNotFound | BankError::RecipientNotFound => Code::NotFound,
+        BankError::AccountExists => Code::AlreadyExists,
+        BankError::InsufficientFunds
+        | BankError::Unavailable(_)
+        | BankError::BalanceRemaining(_)
+        | BankError::StatusChangeNotAllowed
+        | BankError::TotpAlreadyEnabled
+        | BankError::TotpNotEnabled => Code::FailedPrecondition,
+        BankError::SchemaTooNew { .. } | BankError::InvalidMigrationTarget(_) | BankError::Storage(_) => {
+            Code::Internal
+        }
+        _ => Code::InvalidArgument,
+    };
+    let mut status = Status::new(code, e.to_string());
+    status
+        .metadata_mut()
+        .insert("bank-error", MetadataValue::from_static(e.name()));
+    status
+}
+
+/// An empty code means none was given
+fn code(code: &str) -> Option<&str> {
+    Some(code).filter(|c| !c.is_empty())
+}
+
+fn event(account: &str, entry: LedgerEntry) -> LedgerEvent {
+    let kind = match entry.kind {
+        storage::PostingKind::Deposit => PostingKind::Deposit,
+        storage::PostingKind::Withdrawal => PostingKind::Withdrawal,
+        storage::PostingKind::TransferIn => PostingKind::TransferIn,
+        storage::PostingKind::TransferOut => PostingKind::TransferOut,
+        storage::PostingKind::Fee => PostingKind::Fee,
+    };
+    LedgerEvent {
+        id: entry.id,
+        account: account.to_string(),
+        kind: kind.into(),
+        amount: entry.amount,
+        counterparty: entry.counterparty.unwrap_or_default(),
+        created_at: entry.created_at,
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Storage + Send + 'static> BankService for GrpcBank<S> {
+    async fn create_account(
+        &self,
+        request: Request<CreateAccountRequest>,
+    ) -> Result<Response<CreateAccountResponse>, Status> {
+        let pin = request.into_inner().pin;
+        let response = self
+            .with_bank(move |bank| {
+                if pin.is_empty() {
+                    let (number, pin) = database::with_fresh_number(|number| bank.create_account(number, 0))?;
+                    Ok(CreateAccountResponse {
+                        account: number.to_string(),
+                        pin,
+                    })
+                } else {
+                    let (number, ()) =
+                        database::with_fresh_number(|number| bank.create_account_with_pin(number, 0, &pin))?;
+                    Ok(CreateAccountResponse {
+                        account: number.to_string(),
+                        pin: String::new(),
+                    })
+                }
+            })
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_balance(&self, request: Request<GetBalanceRequest>) -> Result<Response<BalanceResponse>, Status> {
+        let GetBalanceRequest { account, pin, code: c } = request.into_inner();
+        let response = self
+            .with_bank(move |bank| {
+                Ok(BalanceResponse {
+                    balance: bank.check_balance(&account, &pin, code(&c))?,
+                    account,
+                })
+            })
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn deposit(&self, request: Request<DepositRequest>) -> Result<Response<BalanceResponse>, Status> {
+        let DepositRequest { account, amount, pin } = request.into_inner();
+        let response = self
+            .post(move |bank| {
+                Ok(BalanceResponse {
+                    balance: bank.deposit(&account, amount, &pin)?,
+                    account,
+                })
+            })
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn withdraw(&self, request: Request<WithdrawRequest>) -> Result<Response<BalanceResponse>, Status> {
+        let WithdrawRequest { account, amount, pin } = request.into_inner();
+        let response = self
+            .post(move |bank| {
+                Ok(BalanceResponse {
+                    balance: bank.withdraw(&account, amount, &pin)?,
+                    account,
+                })
+            })
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn transfer(&self, request: Request<TransferRequest>) -> Result<Response<BalanceResponse>, Status> {
+        let TransferRequest {
+            account,
+            to,
+            amount,
+            pin,
+            code: c,
+        } = request.into_inner();
+        let response = self
+            .post(move |bank| {
+                Ok(BalanceResponse {
+                    balance: bank.transfer(&account, &to, amount, &pin, code(&c))?,
+                    account,
+                })
+            })
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    type StreamTransactionsStream = ReceiverStream<Result<LedgerEvent, Status>>;
+
+    async fn stream_transactions(
+        &self,
+        request: Request<StreamTransactionsRequest>,
+    ) -> Result<Response<Self::StreamTransactionsStream>, Status> {
+        let StreamTransactionsRequest {
+            account,
+            pin,
+            code: c,
+            after_id,
+        } = request.into_inner();
+
+        // Checking the PIN once, like a login, and finding where to start
+        let checked = account.clone();
+        let latest = self
+            .with_bank(move |bank| bank.history(&checked, &pin, code(&c), 1))
+            .await?;
+        let mut after = after_id.unwrap_or_else(|| latest.first().map_or(0, |entry| entry.id));
+
+        let (sender, receiver) = mpsc::channel(BATCH);
+        let bank = self.bank.clone();
+        let posted = self.posted.clone();
+        tokio::spawn(async move {
+            loop {
+                let reading = account.clone();
+                let entries = with_bank(bank.clone(), move |bank| bank.storage().ledger_since(&reading, after, BATCH)).await;
+                let entries = match entries {
+                    Ok(entries) => entries,
+                    Err(status) => {
+                        let _ = sender.send(Err(status)).await;
+                        return;
+                    }
+                };
+                let caught_up = entries.len() < BATCH;
+                for entry in entries {
+                    after = entry.id;
+                    if sender.send(Ok(event(&account, entry))).await.is_err() {
+                        return;
+                    }
+                }
+                if caught_up {
+                    let _ = tokio::time::timeout(POLL, posted.notified()).await;
+                }
+                if sender.is_closed() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Serving the service on `addr` until Ctrl-C. There is no TLS, so only
+/// loopback addresses are accepted.
+pub fn serve<S: Storage + Send + 'static>(bank: Bank<S>, addr: SocketAddr) -> io::Result<()> {
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("gRPC has no TLS and only listens on localhost, not on {}", addr.ip()),
+        ));
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        eprintln!("Serving the bank over gRPC on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(GrpcBank::new(bank).into_service())
+            .serve_with_shutdown(addr, async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+            .map_err(io::Error::other)
+    })
+}
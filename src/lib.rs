@@ -1,6 +1,32 @@
 // SPDX-License-Identifier: Unlicense
 
+#[cfg(feature = "cli")]
 pub mod cli;
+pub mod config;
+#[cfg(all(unix, feature = "cli"))]
+pub mod daemon;
 pub mod database;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod luhn;
-pub mod menu;
\ No newline at end of file
+#[cfg(feature = "cli")]
+pub mod menu;
+#[cfg(any(feature = "cli", feature = "rest"))]
+pub mod output;
+pub mod pin;
+#[cfg(feature = "cli")]
+pub mod pin_entry;
+#[cfg(feature = "rest")]
+pub mod rest;
+pub mod storage;
+#[cfg(feature = "cli")]
+pub mod teller;
+pub mod token;
+pub mod totp;
+#[cfg(feature = "tui")]
+pub mod tui;
\ No newline at end of file
@@ -4,6 +4,9 @@ use std::str::FromStr;
 use std::result::Result as StdResult;
 use std::io;
 
+/// Most digits an account number may have, as for card numbers
+pub const MAX_LENGTH: usize = 19;
+
 /// The account number uses random number generation
 /// for the payload. This payload is then calculated
 /// to produce the check digit.
@@ -84,10 +87,35 @@ impl AccountNumber {
     }
 }
 
-/// Helper functions for AccountNumber
+/// Account numbers are serialized as their digit string
+#[cfg(feature = "serde")]
+impl serde::Serialize for AccountNumber {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Only digit strings with a valid check digit deserialize
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AccountNumber {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> StdResult<Self, D::Error> {
+        let digits = String::deserialize(deserializer)?;
+        let digits = digits.trim();
+        if !verify(digits) {
+            return Err(serde::de::Error::custom(format!("`{}` is not a valid account number", digits)));
+        }
+        digits.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Helper functions for AccountNumber. Anything but two to `MAX_LENGTH`
+/// digits is not an account number, so this is safe to call on partial input.
 pub fn verify(account_number: &str) -> bool {
     let account_number = account_number.to_string();
     let digits: Vec<char> = account_number.trim().chars().collect();
+    if !(2..=MAX_LENGTH).contains(&digits.len()) || !digits.iter().all(char::is_ascii_digit) {
+        return false;
+    }
 
     let mut payload: Vec<u8> = digits
         .iter()
@@ -102,7 +130,7 @@ pub fn verify(account_number: &str) -> bool {
 /// Helper function to get the "Nonce" or check digit to validate the account number
 fn get_check_digit(payload: &[u8]) -> u8 {
     let mut new_payload = payload.iter().copied();
-    let mut luhn_sum = 0;
+    let mut luhn_sum: u32 = 0;
     let mut index = 0;
 
     while let Some(item) = new_payload.next_back() {
@@ -117,13 +145,14 @@ fn get_check_digit(payload: &[u8]) -> u8 {
                 .collect();
 
             let sum = digits.iter().sum::<u8>();
-            luhn_sum += sum;
+            luhn_sum += u32::from(sum);
         } else {
-            luhn_sum += item;
+            luhn_sum += u32::from(item);
         }
         index += 1;
     }
-    10 - (luhn_sum % 10)
+    // A sum that is already a multiple of ten needs a check digit of 0, not 10
+    (10 - luhn_sum % 10) as u8 % 10
 }
 
 #[cfg(test)]
@@ -186,4 +215,66 @@ mod tests {
             assert!(account.is_err());
         }
     }
+
+    #[test]
+    fn check_digit_is_zero_for_sums_divisible_by_ten() {
+        // The doubled Luhn sum of 123456783 is 40
+        assert_eq!(get_check_digit(&[1, 2, 3, 4, 5, 6, 7, 8, 3]), 0);
+        assert!(verify("1234567830"));
+        assert!(!verify("12345678310"));
+        assert_eq!(AccountNumber::from_str("1234567830").unwrap().check_digit(), 0);
+    }
+
+    #[test]
+    fn generated_account_numbers_are_valid() {
+        for _ in 0..1000 {
+            let account = AccountNumber::default().to_string();
+            assert_eq!(account.len(), 10, "{account}");
+            assert!(verify(&account), "{account}");
+        }
+    }
+
+    #[test]
+    fn partial_input_is_not_an_account_number() {
+        for input in ["", "7", "85362a6945", "8536-276945", "８５"] {
+            assert!(!verify(input), "{input}");
+        }
+    }
+
+    #[test]
+    fn long_input_does_not_overflow() {
+        // Every 9 adds 9 to the sum, which no longer fits a u8 after 28 of them
+        assert_eq!(get_check_digit(&[9; 1000]), 0);
+        assert_eq!(get_check_digit(&[9; 29]), 9);
+        for length in [29, 1000] {
+            assert!(!verify(&"9".repeat(length)), "{length}");
+        }
+
+        assert!(verify(&AccountNumber::new(MAX_LENGTH).to_string()));
+        // Twenty zeros have a valid check digit, but too many digits
+        assert!(!verify(&"0".repeat(MAX_LENGTH + 1)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn account_numbers_serialize_as_digits() {
+        let account = AccountNumber::from_str("8536276945").unwrap();
+        assert_eq!(serde_json::to_string(&account).unwrap(), r#""8536276945""#);
+        let parsed: AccountNumber = serde_json::from_str(r#""35001576202""#).unwrap();
+        assert_eq!(parsed.to_string(), "35001576202");
+        for invalid in [r#""8536276944""#, r#""85362a6945""#, "8536276945"] {
+            assert!(serde_json::from_str::<AccountNumber>(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn generated_account_numbers_survive_a_round_trip() {
+        for _ in 0..1000 {
+            let account = AccountNumber::default();
+            let json = serde_json::to_string(&account).unwrap();
+            let parsed: AccountNumber = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.to_string(), account.to_string());
+        }
+    }
 }
@@ -1,70 +1,433 @@
 // SPDX-License-Identifier: Unlicense
 
-use banking_system::cli;
-use banking_system::database;
-use banking_system::luhn::AccountNumber;
+use banking_system::cli::{self, OutputFormat};
+use banking_system::config;
+#[cfg(unix)]
+use banking_system::daemon;
+use banking_system::database::Bank;
 use banking_system::menu;
+use banking_system::pin_entry::PinReader;
+use banking_system::output::{self, MigrationState, Report};
+use banking_system::storage::backup;
+use banking_system::storage::migrations::{self, Migration};
+use banking_system::storage;
+use banking_system::teller::Teller;
 use clap::Parser;
-use rusqlite::Result;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-fn main() -> Result<()> {
+fn main() {
     let cli = cli::Opts::parse();
+    let format = cli.output;
 
-    match cli.account {
-        cli::AccountOpts::Login { account, pin } => {
-            let db = database::initialise_bankdb()?;
-            let query_string = format!(
-                "SELECT pin FROM account where account_number='{}';",
-                account
-            );
-            let pin_from_db: Result<String> = db.query_row(&query_string, [], |row| row.get(0));
-            match pin_from_db {
-                Ok(p) => {
-                    if p == pin {
-                        menu::prompt(&account).expect("Something went wrong");
-                    } else {
-                        eprintln!("Wrong pin... try again");
-                    };
+    if let Err(e) = run(cli) {
+        output::print_error(e.as_ref(), format);
+        std::process::exit(output::exit_code(e.as_ref()));
+    }
+}
+
+fn run(cli: cli::Opts) -> Result<(), Box<dyn Error>> {
+    let settings = config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+    let db = cli.db.unwrap_or_else(|| settings.db.clone());
+    let session_timeout = settings.session_timeout();
+    #[cfg(unix)]
+    let socket = cli.socket.or_else(|| settings.socket.clone());
+    #[cfg(unix)]
+    if let Some(socket) = socket.as_ref().filter(|_| through_daemon(&cli.command)) {
+        let mut client = daemon::Client::connect(socket)?;
+        if let Some(report) = teller_command(&mut client, cli.command, cli.output, session_timeout)? {
+            report.print(cli.output);
+        }
+        return Ok(());
+    }
+    // The daemon owns the database; the TUI and servers would be a second
+    // writer, and a restore would swap the file under it
+    #[cfg(unix)]
+    if let Some(socket) = socket.as_ref().filter(|_| writes_beside_daemon(&cli.command)) {
+        if daemon::is_live(socket) {
+            return Err(format!("A bank daemon owns the database on {}. Stop it first.", socket.display()).into());
+        }
+    }
+
+    // Opening the bank would already upgrade the schema, and backups work
+    // on the file
+    let report = match cli.command {
+        cli::Command::Admin(cli::AdminOpts::Migrate { status, to }) => Some(migrate_command(&db, status, to)?),
+        cli::Command::Admin(cli::AdminOpts::Backup { path, keep }) => Some(backup_command(&db, &path, keep)?),
+        cli::Command::Admin(cli::AdminOpts::Restore { path }) => Some(restore_command(&db, &path)?),
+        cli::Command::Admin(cli::AdminOpts::VerifyBackup { path }) => {
+            let verification = backup::verify(&path)?;
+            Some(Report::BackupVerified {
+                path: path.display().to_string(),
+                schema_version: verification.schema_version,
+                accounts: verification.accounts,
+                postings: verification.postings,
+            })
+        }
+        command => {
+            let mut bank = Bank::open(&db)?.with_settings(settings);
+            match command {
+                #[cfg(unix)]
+                cli::Command::Daemon => {
+                    let socket = socket.ok_or("No socket to serve on. Give one with --socket or in the profile.")?;
+                    let listener = daemon::bind(&socket)?;
+                    eprintln!("Serving the bank on {}", socket.display());
+                    daemon::serve(bank, listener)?;
+                    None
                 }
-                Err(_) => {
-                    eprintln!("No such account");
+                #[cfg(feature = "tui")]
+                cli::Command::Tui => {
+                    banking_system::tui::run(&mut bank)?;
+                    None
                 }
+                #[cfg(feature = "rest")]
+                cli::Command::Serve {
+                    listen,
+                    token_minutes,
+                    #[cfg(feature = "grpc")]
+                    grpc,
+                    #[cfg(feature = "graphql")]
+                    staff_key_file,
+                } => {
+                    // Each server has its own connection; the database keeps them apart
+                    #[cfg(feature = "grpc")]
+                    if let Some(addr) = grpc {
+                        let grpc_bank = Bank::open(&db)?.with_settings(bank.settings().clone());
+                        std::thread::spawn(move || {
+                            if let Err(e) = banking_system::grpc::serve(grpc_bank, addr) {
+                                eprintln!("{}", e);
+                                std::process::exit(1);
+                            }
+                        });
+                    }
+                    let options = banking_system::rest::ApiOptions {
+                        token_ttl: std::time::Duration::from_secs(token_minutes * 60),
+                        #[cfg(feature = "graphql")]
+                        staff_key: staff_key_file.as_deref().map(read_staff_key).transpose()?,
+                        #[cfg(not(feature = "graphql"))]
+                        staff_key: None,
+                    };
+                    banking_system::rest::serve(bank, listen, options)?;
+                    None
+                }
+                command => teller_command(&mut bank, command, cli.output, session_timeout)?,
+            }
+        }
+    };
+    if let Some(report) = report {
+        report.print(cli.output);
+    }
+    Ok(())
+}
+
+/// The staff key in `path`, which has to be long enough not to be guessed
+#[cfg(feature = "graphql")]
+fn read_staff_key(path: &Path) -> Result<String, Box<dyn Error>> {
+    let key = std::fs::read_to_string(path)?.trim().to_string();
+    if key.len() < 32 {
+        return Err(format!("The staff key in {} is shorter than 32 characters", path.display()).into());
+    }
+    Ok(key)
+}
+
+/// Whether a daemon, if there is one, runs `command`
+#[cfg(unix)]
+fn through_daemon(command: &cli::Command) -> bool {
+    match command {
+        cli::Command::Account(_) => true,
+        cli::Command::Admin(admin) => !matches!(
+            admin,
+            cli::AdminOpts::Migrate { .. }
+                | cli::AdminOpts::Backup { .. }
+                | cli::AdminOpts::Restore { .. }
+                | cli::AdminOpts::VerifyBackup { .. }
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `command` needs the database file itself and so cannot run while
+/// a daemon owns it. Migrations and backups wait for the daemon's locks instead.
+#[cfg(unix)]
+fn writes_beside_daemon(command: &cli::Command) -> bool {
+    match command {
+        cli::Command::Admin(admin) => matches!(admin, cli::AdminOpts::Restore { .. }),
+        #[cfg(feature = "tui")]
+        cli::Command::Tui => true,
+        #[cfg(feature = "rest")]
+        cli::Command::Serve { .. } => true,
+        _ => false,
+    }
+}
+
+/// Logging in and handing over to the menu, or replaying a script. The menu
+/// logs out after `timeout` without an answer.
+fn login_command(
+    bank: &mut impl Teller,
+    account: &str,
+    pin_fd: Option<u32>,
+    script: Option<PathBuf>,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut pins = match &script {
+        Some(script) => PinReader::open(script)?,
+        None => PinReader::new(pin_fd)?,
+    };
+    let pin = pins.read("Please input the pin:")?;
+    let code = read_code(bank, &mut pins, account, None)?;
+    let token = bank.issue_token(account, &pin, code.as_deref())?;
+    match pins.into_rest().filter(|_| script.is_some()) {
+        Some(script) => menu::replay(bank, account, &token, timeout, script, std::io::stdout().lock())?,
+        None => menu::prompt(bank, account, &token, timeout)?,
+    }
+    Ok(())
+}
+
+/// Running an account or admin subcommand, the login with a menu session
+/// that times out after `session_timeout`
+fn teller_command(
+    teller: &mut impl Teller,
+    command: cli::Command,
+    format: OutputFormat,
+    session_timeout: Duration,
+) -> Result<Option<Report>, Box<dyn Error>> {
+    match command {
+        cli::Command::Account(cli::AccountOpts::Login {
+            account,
+            pin_fd,
+            script,
+        }) => {
+            login_command(teller, &account, pin_fd, script, session_timeout)?;
+            Ok(None)
+        }
+        cli::Command::Account(account) => account_command(teller, account, format),
+        cli::Command::Admin(admin) => Ok(Some(admin_command(teller, admin)?)),
+        _ => unreachable!("only account and admin subcommands are run by a teller"),
+    }
+}
+
+/// Running an account subcommand other than the login. Returns nothing
+/// when the new PINs did not match.
+fn account_command(
+    bank: &mut impl Teller,
+    opts: cli::AccountOpts,
+    format: OutputFormat,
+) -> Result<Option<Report>, Box<dyn Error>> {
+    let report = match opts {
+        cli::AccountOpts::Login { .. } => unreachable!("logins run the menu"),
+        cli::AccountOpts::Balance { account, pin_fd } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let pin = pins.read("Please input the pin:")?;
+            let code = read_code(bank, &mut pins, &account, None)?;
+            let balance = bank.check_balance(&account, &pin, code.as_deref())?;
+            Report::Balance { account, balance }
+        }
+        cli::AccountOpts::Deposit {
+            account,
+            amount,
+            pin_fd,
+        } => {
+            let pin = PinReader::new(pin_fd)?.read("Please input the pin:")?;
+            let balance = bank.deposit(&account, amount, &pin)?;
+            Report::Balance { account, balance }
+        }
+        cli::AccountOpts::Withdraw {
+            account,
+            amount,
+            pin_fd,
+        } => {
+            let pin = PinReader::new(pin_fd)?.read("Please input the pin:")?;
+            let balance = bank.withdraw(&account, amount, &pin)?;
+            Report::Balance { account, balance }
+        }
+        cli::AccountOpts::Transfer {
+            account,
+            to,
+            amount,
+            pin_fd,
+        } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let pin = pins.read("Please input the pin:")?;
+            let code = read_code(bank, &mut pins, &account, Some(amount))?;
+            let balance = bank.transfer(&account, &to, amount, &pin, code.as_deref())?;
+            Report::Balance { account, balance }
+        }
+        cli::AccountOpts::History {
+            account,
+            limit,
+            pin_fd,
+        } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let pin = pins.read("Please input the pin:")?;
+            let code = read_code(bank, &mut pins, &account, None)?;
+            let entries = bank.history(&account, &pin, code.as_deref(), limit)?;
+            Report::History { account, entries }
+        }
+        cli::AccountOpts::Close {
+            account,
+            payout,
+            pin_fd,
+        } => {
+            let pin = PinReader::new(pin_fd)?.read("Please input the pin:")?;
+            let paid_out = bank.close_account(&account, payout.as_deref(), &pin)?;
+            Report::AccountClosed {
+                account,
+                paid_out,
+                payout_account: payout,
+            }
+        }
+        cli::AccountOpts::ChangePin { account, pin_fd } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let old_pin = pins.read("Please input the current pin:")?;
+            let Some(new_pin) = pins.read_new("Please input the new pin:")? else {
+                return Ok(None);
             };
+            bank.change_pin(&account, &old_pin, &new_pin)?;
+            Report::PinChanged { account }
+        }
+        cli::AccountOpts::EnableTotp { account, pin_fd } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let pin = pins.read("Please input the pin:")?;
+            let uri = bank.begin_totp_enrollment(&account, &pin)?;
+            Report::TotpEnrollment {
+                account: account.clone(),
+                uri,
+            }
+            .print(format);
+            let code = pins.read("Please input the code shown by the app:")?;
+            let recovery_codes = bank.confirm_totp_enrollment(&account, &code)?;
+            Report::TotpEnabled {
+                account,
+                recovery_codes,
+            }
         }
-        cli::AccountOpts::Delete { account, pin } => {
-            database::delete_account(&account, &pin)?;
+        cli::AccountOpts::DisableTotp { account, pin_fd } => {
+            let mut pins = PinReader::new(pin_fd)?;
+            let pin = pins.read("Please input the pin:")?;
+            let code = pins.read("Please input the authenticator code or a recovery code:")?;
+            bank.disable_totp(&account, &pin, &code)?;
+            Report::TotpDisabled { account }
         }
-        cli::AccountOpts::Create => {
-            let mut new_account = AccountNumber::default();
+        cli::AccountOpts::Create { choose_pin, pin_fd } => {
+            let pin = if choose_pin || pin_fd.is_some() {
+                match PinReader::new(pin_fd)?.read_new("Please input the new pin:")? {
+                    Some(pin) => Some(pin),
+                    None => return Ok(None),
+                }
+            } else {
+                None
+            };
 
-            let db = database::initialise_bankdb()?;
-            loop {
-                let query_string = format!(
-                    "SELECT 1 FROM account where account_number='{}';",
-                    new_account
-                );
+            let (account, pin) = bank.open_account(pin.as_deref())?;
+            Report::AccountCreated { account, pin }
+        }
+    };
+    Ok(Some(report))
+}
 
-                match db.query_row(&query_string, [], |row| row.get::<usize, usize>(0)) {
-                    Ok(_) => {
-                        new_account = AccountNumber::default();
-                    }
-                    Err(_) => break,
-                }
+/// Reading the authenticator or recovery code after the PIN if the account
+/// enrolled in TOTP and the operation, or a `transfer` of the amount, needs it
+fn read_code(
+    bank: &mut impl Teller,
+    pins: &mut PinReader,
+    account: &str,
+    transfer: Option<u64>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if bank.needs_code(account, transfer)? {
+        Ok(Some(pins.read("Please input the authenticator code or a recovery code:")?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn admin_command(bank: &mut impl Teller, opts: cli::AdminOpts) -> Result<Report, Box<dyn Error>> {
+    let report = match opts {
+        cli::AdminOpts::SetStatus {
+            account,
+            status,
+            reason,
+        } => {
+            let old_status = bank.set_status(&account, status, &reason)?;
+            Report::StatusChanged {
+                account,
+                status,
+                old_status,
             }
+        }
+        cli::AdminOpts::Unlock { account } => {
+            bank.unlock_account(&account)?;
+            Report::Unlocked { account }
+        }
+        cli::AdminOpts::MarkDormant { months } => Report::MarkedDormant {
+            accounts: bank.mark_dormant(months)?,
+        },
+        cli::AdminOpts::Migrate { .. }
+        | cli::AdminOpts::Backup { .. }
+        | cli::AdminOpts::Restore { .. }
+        | cli::AdminOpts::VerifyBackup { .. } => unreachable!("these run before the bank is opened"),
+    };
+    Ok(report)
+}
 
-            let _ = database::create_account(&new_account, 0);
+fn migrate_command(db: &Path, status: bool, to: Option<u32>) -> Result<Report, Box<dyn Error>> {
+    let mut db = storage::open_existing(db, false)?;
+    let state = |migration: &Migration, version: u32| MigrationState {
+        version: migration.version,
+        description: migration.description,
+        applied: migration.version <= version,
+    };
 
-            let query_string = format!(
-                "SELECT pin FROM account where account_number='{}';",
-                &new_account
-            );
-            let pin_from_db: String = db.query_row(&query_string, [], |row| row.get(0))?;
-            println!(
-                "YOUR NEW ACCOUNT: `{}`\nYOUR PIN: `{}`\n",
-                &new_account, &pin_from_db
-            );
-        }
+    let version = migrations::current_version(&db)?;
+    if status {
+        return Ok(Report::SchemaStatus {
+            version,
+            latest: migrations::latest_version(),
+            migrations: migrations::MIGRATIONS.iter().map(|m| state(m, version)).collect(),
+        });
+    }
+
+    let applied = migrations::migrate(&mut db, to.unwrap_or_else(migrations::latest_version))?;
+    let version = migrations::current_version(&db)?;
+    Ok(Report::Migrated {
+        applied: applied.into_iter().map(|m| state(m, version)).collect(),
+        version,
+    })
+}
+
+/// Copying the database to `path`, or into it under a timestamped name if it
+/// is a directory
+fn backup_command(db: &Path, path: &Path, keep: Option<u64>) -> Result<Report, Box<dyn Error>> {
+    let db = storage::open_existing(db, false)?;
+    let (path, removed) = if path.is_dir() {
+        backup::backup_into(&db, path, keep.map(|keep| keep as usize))?
+    } else if keep.is_some() {
+        return Err("--keep only applies when backing up into a directory".into());
+    } else {
+        backup::backup(&db, path)?;
+        (path.to_path_buf(), Vec::new())
     };
-    Ok(())
+    Ok(Report::BackedUp {
+        path: path.display().to_string(),
+        removed: removed.iter().map(|path| path.display().to_string()).collect(),
+    })
+}
 
-}
\ No newline at end of file
+fn restore_command(db: &Path, path: &Path) -> Result<Report, Box<dyn Error>> {
+    let mut db = backup::open_for_restore(db)?;
+    let (verification, applied) = backup::restore(&mut db, path)?;
+    let version = migrations::current_version(&db)?;
+    Ok(Report::Restored {
+        path: path.display().to_string(),
+        schema_version: verification.schema_version,
+        applied: applied
+            .into_iter()
+            .map(|migration| MigrationState {
+                version: migration.version,
+                description: migration.description,
+                applied: true,
+            })
+            .collect(),
+        version,
+    })
+}
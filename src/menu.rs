@@ -1,96 +1,505 @@
 // SPDX-License-Identifier: Unlicense
 
-use crate::database;
-use std::io::BufRead;
+//! The menu of a logged in customer, as a state machine: every state asks one
+//! question, and the answer moves it to the next state. It reads from any
+//! `BufRead` and writes to any `Write`, so it can run on the terminal, replay
+//! a script or be driven by tests, and it runs on any `Teller`, so on the
+//! database or through the daemon.
 
-pub fn prompt(account_number: &str) -> std::io::Result<()> {
-    let prompt_text = "0) Show Current Balance
+use crate::database::Auth;
+use crate::output;
+use crate::teller::Teller;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const CHOICES: &str = "0) Show Current Balance
 1) Deposit Money
 2) Transfer Money
 3) Withdraw Money
-4) Delete Account
-5) Exit";
+4) Close Account
+5) Exit
+6) Change PIN";
+
+/// Where the conversation with the customer is
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Choice,
+    DepositAmount,
+    WithdrawAmount,
+    WithdrawPin { amount: u64 },
+    TransferAmount,
+    TransferRecipient { amount: u64 },
+    TransferPin { amount: u64, to: String },
+    TransferCode { amount: u64, to: String, pin: String },
+    ClosePayout,
+    ClosePin { payout: Option<String> },
+    CurrentPin,
+    NewPin { old_pin: String },
+    ConfirmPin { old_pin: String, new_pin: String },
+    Done,
+}
+
+impl State {
+    fn question(&self) -> &'static str {
+        match self {
+            State::Choice => CHOICES,
+            State::DepositAmount | State::WithdrawAmount | State::TransferAmount => {
+                "Please input the amount:"
+            }
+            State::TransferRecipient { .. } => "Please input the account number of the recipient:",
+            State::WithdrawPin { .. } | State::TransferPin { .. } => "Please input your pin to confirm:",
+            State::TransferCode { .. } => "Please input the authenticator code:",
+            State::ClosePayout => {
+                "Please input the account number to pay the remaining balance out to (empty if none):"
+            }
+            State::ClosePin { .. } => "Please input the pin:",
+            State::CurrentPin => "Please input the current pin:",
+            State::NewPin { .. } => "Please input the new pin:",
+            State::ConfirmPin { .. } => "Please input the new pin again:",
+            State::Done => "",
+        }
+    }
 
-    loop {
-        println!("{}", &prompt_text);
-        let stdin = std::io::stdin();
-        let mut query = String::new();
-        let mut handle = stdin.lock();
+    /// Whether the answer is a PIN or code, which is never shown
+    fn is_secret(&self) -> bool {
+        matches!(
+            self,
+            State::WithdrawPin { .. }
+                | State::TransferPin { .. }
+                | State::TransferCode { .. }
+                | State::ClosePin { .. }
+                | State::CurrentPin
+                | State::NewPin { .. }
+                | State::ConfirmPin { .. }
+        )
+    }
+}
 
-        handle.read_line(&mut query)?;
+/// A logged in menu. The token from the login stands in for the PIN when
+/// showing the balance and depositing; withdrawals, transfers, closing the
+/// account and changing the PIN ask for the PIN again.
+struct Menu<'a, B: Teller, W: Write> {
+    bank: &'a mut B,
+    account_number: &'a str,
+    token: &'a str,
+    out: W,
+    last_input: Instant,
+    timeout: Duration,
+    /// Set once the token no longer holds, which ends the menu
+    logged_out: bool,
+}
 
-        let query = query.trim();
+impl<B: Teller, W: Write> Menu<'_, B, W> {
+    fn timed_out(&self) -> bool {
+        self.last_input.elapsed() > self.timeout
+    }
 
-        if query == "5" {
-            eprintln!("Exiting bank machine...");
-            break;
-        } else if query == "4" {
-            println!("You are going to delete your account...");
-            println!("Please input the pin:");
-            let mut pin = String::new();
-            handle.read_line(&mut pin)?;
-            let pin = pin.trim();
-
-            database::delete_account(account_number, pin)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-            eprintln!("Account is not accessible. Exiting...");
+    /// Time left to answer before the session times out
+    fn time_left(&self) -> Duration {
+        self.timeout.saturating_sub(self.last_input.elapsed())
+    }
+
+    /// Printing why an operation was refused, so the customer can try again.
+    /// Failures of the database or the daemon end the menu, and so does an
+    /// expired token.
+    fn report<T>(&mut self, result: Result<T, B::Error>) -> io::Result<Option<T>> {
+        let e = match result {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => e,
+        };
+        match output::error_name(&e) {
+            "storage" | "failure" => Err(io::Error::other(e)),
+            name => {
+                self.logged_out |= name == "invalid_token";
+                writeln!(self.out, "{}\n", e)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn show_balance(&mut self, result: Result<u64, B::Error>) -> io::Result<()> {
+        if let Some(balance) = self.report(result)? {
+            writeln!(
+                self.out,
+                "The account number `{}` now has a balance of `{}`.\n",
+                self.account_number, balance
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reading an amount, or `None` after telling the customer it is not one
+    fn amount(&mut self, answer: &str, operation: &str) -> io::Result<Option<u64>> {
+        writeln!(self.out, "The amount you wanted to {}: {}\n", operation, answer)?;
+        let amount = answer.parse().ok();
+        if amount.is_none() {
+            writeln!(self.out, "Invalid amount. Please try again...")?;
+        }
+        Ok(amount)
+    }
+
+    /// Acting on the answer to the question of `state`. Returns the next state.
+    fn answer(&mut self, state: State, answer: &str) -> io::Result<State> {
+        let account_number = self.account_number;
+        let next = match state {
+            State::Choice => match answer {
+                "0" => {
+                    let balance = self.bank.check_balance(account_number, Auth::Token(self.token), None);
+                    self.show_balance(balance)?;
+                    State::Choice
+                }
+                "1" => State::DepositAmount,
+                "2" => State::TransferAmount,
+                "3" => State::WithdrawAmount,
+                "4" => {
+                    writeln!(self.out, "You are going to close your account...")?;
+                    State::ClosePayout
+                }
+                "5" => {
+                    writeln!(self.out, "Exiting bank machine...")?;
+                    State::Done
+                }
+                "6" => State::CurrentPin,
+                _ => {
+                    writeln!(self.out, "Invalid choice. Please try again...")?;
+                    State::Choice
+                }
+            },
+            State::DepositAmount => {
+                if let Some(amount) = self.amount(answer, "deposit")? {
+                    let balance = self.bank.deposit(account_number, amount, Auth::Token(self.token));
+                    self.show_balance(balance)?;
+                }
+                State::Choice
+            }
+            State::WithdrawAmount => match self.amount(answer, "withdraw")? {
+                Some(amount) => State::WithdrawPin { amount },
+                None => State::Choice,
+            },
+            State::WithdrawPin { amount } => {
+                let balance = self.bank.withdraw(account_number, amount, answer);
+                self.show_balance(balance)?;
+                State::Choice
+            }
+            State::TransferAmount => match self.amount(answer, "transfer")? {
+                Some(amount) => State::TransferRecipient { amount },
+                None => State::Choice,
+            },
+            State::TransferRecipient { amount } => {
+                writeln!(
+                    self.out,
+                    "The account number you want to send is {} and the amount to transfer is {}\n",
+                    answer, amount
+                )?;
+                State::TransferPin {
+                    amount,
+                    to: answer.to_string(),
+                }
+            }
+            State::TransferPin { amount, to } => {
+                let needs_code = self.bank.needs_code(account_number, Some(amount));
+                if self.report(needs_code)?.unwrap_or(false) {
+                    State::TransferCode {
+                        amount,
+                        to,
+                        pin: answer.to_string(),
+                    }
+                } else {
+                    let balance = self.bank.transfer(account_number, &to, amount, answer, None);
+                    self.show_balance(balance)?;
+                    State::Choice
+                }
+            }
+            State::TransferCode { amount, to, pin } => {
+                let balance = self.bank.transfer(account_number, &to, amount, &pin, Some(answer));
+                self.show_balance(balance)?;
+                State::Choice
+            }
+            State::ClosePayout => State::ClosePin {
+                payout: (!answer.is_empty()).then(|| answer.to_string()),
+            },
+            State::ClosePin { payout } => {
+                let closed = self.bank.close_account(account_number, payout.as_deref(), answer);
+                match self.report(closed)? {
+                    Some(paid_out) => {
+                        if let Some(payout) = payout.filter(|_| paid_out > 0) {
+                            writeln!(self.out, "Paid out `{}` to the account number `{}`.", paid_out, payout)?;
+                        }
+                        writeln!(self.out, "CLOSED ACCOUNT: {}", account_number)?;
+                        writeln!(self.out, "Account is not accessible. Exiting...")?;
+                        State::Done
+                    }
+                    None => State::Choice,
+                }
+            }
+            State::CurrentPin => State::NewPin {
+                old_pin: answer.to_string(),
+            },
+            State::NewPin { old_pin } => State::ConfirmPin {
+                old_pin,
+                new_pin: answer.to_string(),
+            },
+            State::ConfirmPin { old_pin, new_pin } => {
+                if answer != new_pin {
+                    writeln!(self.out, "The new pins do not match. Try again...")?;
+                } else {
+                    let changed = self.bank.change_pin(account_number, &old_pin, &new_pin);
+                    if self.report(changed)?.is_some() {
+                        // The new PIN voids the token of the login
+                        writeln!(self.out, "PIN CHANGED FOR ACCOUNT: {}", account_number)?;
+                        writeln!(self.out, "Please log in again with the new pin. Exiting...")?;
+                        return Ok(State::Done);
+                    }
+                }
+                State::Choice
+            }
+            State::Done => State::Done,
+        };
+        Ok(if self.logged_out { State::Done } else { next })
+    }
+}
+
+/// How answers are read and shown
+#[derive(Debug, Clone, Copy)]
+struct Input {
+    /// Reading PINs at a hidden terminal prompt instead of from the input
+    hide_pins: bool,
+    /// Writing every answer back after its question, PINs masked
+    echo: bool,
+    /// Giving up waiting on the standard input once the session times out
+    watch_stdin: bool,
+}
+
+fn converse(
+    bank: &mut impl Teller,
+    account_number: &str,
+    token: &str,
+    timeout: Duration,
+    mut input: impl BufRead,
+    out: impl Write,
+    how: Input,
+) -> io::Result<()> {
+    let mut menu = Menu {
+        timeout,
+        bank,
+        account_number,
+        token,
+        out,
+        last_input: Instant::now(),
+        logged_out: false,
+    };
+
+    let mut state = State::Choice;
+    while state != State::Done {
+        writeln!(menu.out, "{}", state.question())?;
+        menu.out.flush()?;
+        if how.watch_stdin && !stdin_ready(menu.time_left())? {
+            writeln!(menu.out, "Session timed out after inactivity. Please log in again...")?;
             break;
-        } else if query == "3" {
-            println!("Please input the amount:");
-            let mut amount = String::new();
-            handle.read_line(&mut amount)?;
-            let amount = amount.trim();
-            println!("The amount you wanted to withdraw: {}\n", &amount);
-
-            println!("Please input the pin:");
-            let mut pin = String::new();
-            handle.read_line(&mut pin)?;
-            let pin = pin.trim();
-
-            database::withdraw(amount, pin, account_number)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        } else if query == "2" {
-            println!("Please input the amount:");
-            let mut amount = String::new();
-            handle.read_line(&mut amount)?;
-            let amount = amount.trim();
-            println!("The amount you wanted to transfer: {}\n", &amount);
-
-            println!("Please input the account number of the recipient:");
-            let mut account_number2 = String::new();
-            handle.read_line(&mut account_number2)?;
-            let account_number2 = account_number2.trim();
-            println!(
-                "The account number you want to send is {} and the amount to transfer is {}\n",
-                &account_number2, &amount
-            );
-
-            println!("Please input your pin:");
-            let mut pin = String::new();
-            handle.read_line(&mut pin)?;
-            let pin = pin.trim();
-            database::transfer(amount, pin, account_number, account_number2)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        } else if query == "1" {
-            println!("Please input the amount:");
-            let mut amount = String::new();
-            handle.read_line(&mut amount)?;
-            let amount = amount.trim();
-            println!("The amount you wanted to deposit: {}\n", &amount);
-
-            println!("Please input the pin:");
-            let mut pin = String::new();
-            handle.read_line(&mut pin)?;
-            let pin = pin.trim();
-
-            database::deposit(amount, pin, account_number)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        } else if query == "0" {
-            database::show_balance(account_number)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+        let answer = if how.hide_pins && state.is_secret() {
+            rpassword::read_password()?
         } else {
-            eprintln!("Invalid choice. Please try again...");
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                // The customer walked away, or the script ended
+                break;
+            }
+            line
+        };
+        let answer = answer.trim();
+        if how.echo {
+            writeln!(menu.out, "> {}", if state.is_secret() { "****" } else { answer })?;
+        }
+
+        if menu.timed_out() {
+            writeln!(menu.out, "Session timed out after inactivity. Please log in again...")?;
+            break;
+        }
+        menu.last_input = Instant::now();
+        state = menu.answer(state, answer)?;
+    }
+    menu.out.flush()
+}
+
+/// Whether the standard input has something to read within `timeout`
+#[cfg(unix)]
+fn stdin_ready(timeout: Duration) -> io::Result<bool> {
+    let mut stdin = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: stdin is one valid pollfd
+    match unsafe { libc::poll(&mut stdin, 1, millis) } {
+        -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => Ok(true),
+        -1 => Err(io::Error::last_os_error()),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Whether the standard input has something to read within `timeout`.
+/// Without `poll`, the answer is checked once it arrives.
+#[cfg(not(unix))]
+fn stdin_ready(_timeout: Duration) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Running the menu on the terminal for a login that got `token`, with PINs
+/// typed at a hidden prompt. It logs out after `timeout` without an answer.
+pub fn prompt(bank: &mut impl Teller, account_number: &str, token: &str, timeout: Duration) -> io::Result<()> {
+    let terminal = io::stdin().is_terminal();
+    let how = Input {
+        hide_pins: terminal,
+        echo: false,
+        watch_stdin: terminal,
+    };
+    converse(bank, account_number, token, timeout, io::stdin().lock(), io::stdout().lock(), how)
+}
+
+/// Running the menu for a login that got `token` on answers from `input`,
+/// one per line, and writing the transcript with the answers to `out`. The
+/// menu ends with the input, or after `timeout` between answers.
+pub fn replay(
+    bank: &mut impl Teller,
+    account_number: &str,
+    token: &str,
+    timeout: Duration,
+    input: impl BufRead,
+    out: impl Write,
+) -> io::Result<()> {
+    let how = Input {
+        hide_pins: false,
+        echo: true,
+        watch_stdin: false,
+    };
+    converse(bank, account_number, token, timeout, input, out, how)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Settings, SESSION_LIFETIME};
+    use crate::database::Bank;
+    use crate::error::BankResult;
+    use crate::luhn::AccountNumber;
+    use crate::storage::MemoryStorage;
+    use crate::totp;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    const ACCOUNT: &str = "8536276945";
+    const OTHER: &str = "35001576202";
+    const PIN: &str = "591732";
+
+    fn open_bank(balance: u64) -> BankResult<Bank<MemoryStorage>> {
+        let mut bank = Bank::in_memory();
+        for number in [ACCOUNT, OTHER] {
+            bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), balance, PIN)?;
+        }
+        Ok(bank)
+    }
+
+    fn log_in(bank: &mut Bank<MemoryStorage>) -> BankResult<String> {
+        bank.issue_token(ACCOUNT, PIN, None, SESSION_LIFETIME)
+    }
+
+    /// Replaying `tests/transcripts/<name>.in` after a login that got `token`
+    /// and comparing the transcript with `<name>.out`. Each
+    /// `(placeholder, value)` is filled into the input. With
+    /// `UPDATE_TRANSCRIPTS` set, the transcript is written out instead.
+    fn check_transcript(name: &str, bank: &mut Bank<MemoryStorage>, token: &str, fill: &[(&str, &str)]) {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+        let mut input = std::fs::read_to_string(dir.join(format!("{}.in", name))).unwrap();
+        for (placeholder, value) in fill {
+            input = input.replacen(placeholder, value, 1);
+        }
+
+        let mut transcript = Vec::new();
+        let timeout = bank.settings().session_timeout();
+        replay(bank, ACCOUNT, token, timeout, input.as_bytes(), &mut transcript).unwrap();
+        let transcript = String::from_utf8(transcript).unwrap();
+
+        let golden = dir.join(format!("{}.out", name));
+        if std::env::var_os("UPDATE_TRANSCRIPTS").is_some() {
+            std::fs::write(&golden, &transcript).unwrap();
         }
+        assert_eq!(transcript, std::fs::read_to_string(&golden).unwrap(), "transcript {}", name);
+    }
+
+    #[test]
+    fn everyday_operations() -> BankResult<()> {
+        let mut bank = open_bank(100)?;
+        let token = log_in(&mut bank)?;
+        check_transcript("everyday", &mut bank, &token, &[]);
+        assert_eq!(bank.balance(ACCOUNT)?, 130);
+        Ok(())
+    }
+
+    #[test]
+    fn transfers() -> BankResult<()> {
+        let mut bank = open_bank(100)?;
+        let token = log_in(&mut bank)?;
+        check_transcript("transfer", &mut bank, &token, &[]);
+        assert_eq!(bank.balance(OTHER)?, 130);
+        Ok(())
+    }
+
+    #[test]
+    fn large_transfers_ask_for_the_code() -> BankResult<()> {
+        let mut bank = open_bank(2000)?;
+        // The token stands for the code, which is only asked for at the login
+        let token = log_in(&mut bank)?;
+        let uri = bank.begin_totp_enrollment(ACCOUNT, PIN)?;
+        let secret = uri.split("secret=").nth(1).unwrap().split('&').next().unwrap();
+        let secret = totp::base32_decode(secret).unwrap();
+        let code = totp::hotp(totp::Algorithm::Sha1, &secret, totp::step_at(totp::now()), totp::DIGITS);
+        let recovery_codes = bank.confirm_totp_enrollment(ACCOUNT, &code)?;
+
+        // The second transfer reuses the used up recovery code
+        let fill = [("{recovery_code}", recovery_codes[0].as_str()); 2];
+        check_transcript("transfer_with_code", &mut bank, &token, &fill);
+        assert_eq!(bank.balance(OTHER)?, 3500);
+        Ok(())
+    }
+
+    #[test]
+    fn pin_changes() -> BankResult<()> {
+        let mut bank = open_bank(100)?;
+        let token = log_in(&mut bank)?;
+        check_transcript("change_pin", &mut bank, &token, &[]);
+        assert!(bank.check_balance(ACCOUNT, "642853", None).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn closing_ends_the_menu() -> BankResult<()> {
+        let mut bank = open_bank(100)?;
+        let token = log_in(&mut bank)?;
+        check_transcript("close", &mut bank, &token, &[]);
+        assert_eq!(bank.balance(OTHER)?, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn input_ending_ends_the_menu() -> BankResult<()> {
+        let mut bank = open_bank(100)?;
+        let token = log_in(&mut bank)?;
+        check_transcript("end_of_input", &mut bank, &token, &[]);
+
+        let settings = Settings {
+            session_timeout_secs: 0,
+            ..Settings::default()
+        };
+        let mut bank = open_bank(100)?.with_settings(settings);
+        let token = log_in(&mut bank)?;
+        check_transcript("timeout", &mut bank, &token, &[]);
+
+        // A token that expired ends the menu like a timeout
+        let mut bank = open_bank(100)?;
+        let token = bank.issue_token(ACCOUNT, PIN, None, Duration::ZERO)?;
+        check_transcript("expired", &mut bank, &token, &[]);
+        Ok(())
     }
-    Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: Unlicense
+
+//! What the commands report, as text for people or as one JSON object per
+//! line for scripts. The JSON field names are kept stable; the text may change.
+
+#[cfg(all(unix, feature = "cli"))]
+use crate::daemon::ClientError;
+use crate::database::AccountStatus;
+use crate::error::BankError;
+use crate::storage::backup::BackupError;
+use crate::storage::{LedgerEntry, PostingKind};
+use serde::Serialize;
+use std::error::Error;
+
+/// How results and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OutputFormat {
+    /// Sentences for people, errors on stderr
+    Text,
+    /// One JSON object per line on stdout, errors included
+    Json,
+}
+
+/// State of one migration
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationState {
+    pub version: u32,
+    pub description: &'static str,
+    pub applied: bool,
+}
+
+/// Outcome of a command, tagged with `"result"` in JSON
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Report {
+    /// `pin` is only set when the bank chose it
+    AccountCreated { account: String, pin: Option<String> },
+    Balance { account: String, balance: u64 },
+    Account {
+        account: String,
+        status: AccountStatus,
+        balance: u64,
+    },
+    /// Bearer token for the API, valid for `expires_in` seconds
+    Token {
+        account: String,
+        token: String,
+        expires_in: u64,
+    },
+    History { account: String, entries: Vec<LedgerEntry> },
+    AccountClosed {
+        account: String,
+        paid_out: u64,
+        payout_account: Option<String>,
+    },
+    PinChanged { account: String },
+    /// First step of `enable-totp`, before the code from the app is read
+    TotpEnrollment { account: String, uri: String },
+    TotpEnabled { account: String, recovery_codes: Vec<String> },
+    TotpDisabled { account: String },
+    StatusChanged {
+        account: String,
+        status: AccountStatus,
+        old_status: AccountStatus,
+    },
+    Unlocked { account: String },
+    MarkedDormant { accounts: usize },
+    SchemaStatus {
+        version: u32,
+        latest: u32,
+        migrations: Vec<MigrationState>,
+    },
+    Migrated { applied: Vec<MigrationState>, version: u32 },
+    /// `removed` are the older copies rotated out with `--keep`
+    BackedUp { path: String, removed: Vec<String> },
+    BackupVerified {
+        path: String,
+        schema_version: u32,
+        accounts: u64,
+        postings: u64,
+    },
+    Restored {
+        path: String,
+        schema_version: u32,
+        applied: Vec<MigrationState>,
+        version: u32,
+    },
+}
+
+impl Report {
+    pub fn print(&self, format: OutputFormat) {
+        match format {
+            OutputFormat::Json => println!("{}", serde_json::to_string(self).expect("reports serialize")),
+            OutputFormat::Text => self.print_text(),
+        }
+    }
+
+    fn print_text(&self) {
+        match self {
+            Report::AccountCreated { account, pin: Some(pin) } => {
+                println!("YOUR NEW ACCOUNT: `{}`\nYOUR PIN: `{}`\n", account, pin)
+            }
+            Report::AccountCreated { account, pin: None } => println!("YOUR NEW ACCOUNT: `{}`\n", account),
+            Report::Balance { account, balance } => println!(
+                "The account number `{}` now has a balance of `{}`.",
+                account, balance
+            ),
+            Report::Account {
+                account,
+                status,
+                balance,
+            } => println!(
+                "The account number `{}` is {} and has a balance of `{}`.",
+                account, status, balance
+            ),
+            Report::Token { token, expires_in, .. } => {
+                println!("TOKEN (valid for {} seconds): {}", expires_in, token)
+            }
+            Report::History { account, entries } => {
+                if entries.is_empty() {
+                    eprintln!("No postings on the account number `{}` yet.", account);
+                }
+                for entry in entries {
+                    let sign = if entry.kind.is_credit() { '+' } else { '-' };
+                    let counterparty = match (entry.kind, &entry.counterparty) {
+                        (PostingKind::TransferIn, Some(from)) => format!("from {}", from),
+                        (_, Some(to)) => format!("to {}", to),
+                        (_, None) => String::new(),
+                    };
+                    let line = format!(
+                        "{}  {:<12} {:>14}  {}",
+                        entry.created_at,
+                        entry.kind.as_str(),
+                        format!("{}{}", sign, entry.amount),
+                        counterparty
+                    );
+                    println!("{}", line.trim_end());
+                }
+            }
+            Report::AccountClosed {
+                account,
+                paid_out,
+                payout_account,
+            } => {
+                if let Some(payout) = payout_account.as_ref().filter(|_| *paid_out > 0) {
+                    println!("Paid out `{}` to the account number `{}`.", paid_out, payout);
+                }
+                println!("CLOSED ACCOUNT: {}", account);
+            }
+            Report::PinChanged { account } => println!("PIN CHANGED FOR ACCOUNT: {}", account),
+            Report::TotpEnrollment { uri, .. } => {
+                println!("Add this account to your authenticator app:\n{}\n", uri)
+            }
+            Report::TotpEnabled { recovery_codes, .. } => {
+                println!("AUTHENTICATOR ENABLED. Keep these one-time recovery codes somewhere safe:");
+                for recovery_code in recovery_codes {
+                    println!("{}", recovery_code);
+                }
+            }
+            Report::TotpDisabled { account } => println!("AUTHENTICATOR REMOVED FROM ACCOUNT: {}", account),
+            Report::StatusChanged {
+                account,
+                status,
+                old_status,
+            } => println!(
+                "The account number `{}` is now {} (was {}).",
+                account, status, old_status
+            ),
+            Report::Unlocked { account } => println!("UNLOCKED ACCOUNT: {}", account),
+            Report::MarkedDormant { accounts } => println!("Marked {} account(s) as dormant.", accounts),
+            Report::SchemaStatus {
+                version,
+                latest,
+                migrations,
+            } => {
+                println!("Schema version {} (this bank knows up to {}).", version, latest);
+                for migration in migrations {
+                    let state = if migration.applied { "applied" } else { "pending" };
+                    println!("{:>4}  {:<8} {}", migration.version, state, migration.description);
+                }
+            }
+            Report::Migrated { applied, version } => {
+                for migration in applied {
+                    println!("Applied migration {}: {}", migration.version, migration.description);
+                }
+                println!("Schema version {}.", version);
+            }
+            Report::BackedUp { path, removed } => {
+                println!("Backed up to `{}`.", path);
+                for path in removed {
+                    println!("Removed the old backup `{}`.", path);
+                }
+            }
+            Report::BackupVerified {
+                path,
+                schema_version,
+                accounts,
+                postings,
+            } => println!(
+                "The backup `{}` is sound: schema version {}, {} accounts, {} postings.",
+                path, schema_version, accounts, postings
+            ),
+            Report::Restored {
+                path,
+                schema_version,
+                applied,
+                version,
+            } => {
+                println!("Restored `{}` (schema version {}).", path, schema_version);
+                for migration in applied {
+                    println!("Applied migration {}: {}", migration.version, migration.description);
+                }
+                println!("Schema version {}.", version);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    result: &'static str,
+    error: &'a str,
+    exit_code: i32,
+    message: String,
+}
+
+/// Error as it is reported. In JSON it goes to stdout like any other
+/// result, with the exit code and a stable `error` name.
+pub fn print_error(error: &(dyn Error + 'static), format: OutputFormat) {
+    match format {
+        OutputFormat::Text => eprintln!("{}", error),
+        OutputFormat::Json => {
+            let report = ErrorReport {
+                result: "error",
+                error: error_name(error),
+                exit_code: exit_code(error),
+                message: error.to_string(),
+            };
+            println!("{}", serde_json::to_string(&report).expect("reports serialize"));
+        }
+    }
+}
+
+/// Stable name of `error`, see `BankError::name`
+pub fn error_name<'a>(error: &'a (dyn Error + 'static)) -> &'a str {
+    #[cfg(all(unix, feature = "cli"))]
+    if let Some(e) = error.downcast_ref::<ClientError>() {
+        return e.name();
+    }
+    if let Some(e) = error.downcast_ref::<BackupError>() {
+        return e.name();
+    }
+    error.downcast_ref::<BankError>().map_or("failure", BankError::name)
+}
+
+/// Exit code of the command line for `error`, see `BankError::exit_code`
+pub fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    #[cfg(all(unix, feature = "cli"))]
+    if let Some(e) = error.downcast_ref::<ClientError>() {
+        return e.exit_code();
+    }
+    if let Some(e) = error.downcast_ref::<BackupError>() {
+        return e.exit_code();
+    }
+    error.downcast_ref::<BankError>().map_or(1, BankError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_fields_stay_put() {
+        let created = Report::AccountCreated {
+            account: "2334841596".to_string(),
+            pin: Some("591732".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&created).unwrap(),
+            json!({"result": "account_created", "account": "2334841596", "pin": "591732"})
+        );
+
+        let changed = Report::StatusChanged {
+            account: "2334841596".to_string(),
+            status: AccountStatus::BlockedDebit,
+            old_status: AccountStatus::Active,
+        };
+        assert_eq!(serde_json::to_value(&changed).unwrap()["status"], "blocked-debit");
+
+        let history = Report::History {
+            account: "2334841596".to_string(),
+            entries: vec![LedgerEntry {
+                id: 1,
+                kind: PostingKind::TransferIn,
+                amount: 30,
+                counterparty: Some("8536276945".to_string()),
+                created_at: "2024-03-31 00:00:00".to_string(),
+            }],
+        };
+        assert_eq!(serde_json::to_value(&history).unwrap()["entries"][0]["kind"], "transfer_in");
+    }
+}
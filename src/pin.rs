@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: Unlicense
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use subtle::ConstantTimeEq;
+
+/// Hashing a PIN with a fresh random salt. The result is a PHC string
+/// (`$argon2id$v=19$...`) carrying its own salt and cost parameters,
+/// so it can be stored as is in the `pin` column.
+pub fn hash(pin: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .expect("Not able to hash the PIN")
+        .to_string()
+}
+
+/// PINs stored before hashing was introduced are the plain digits
+pub fn is_legacy(stored: &str) -> bool {
+    !stored.starts_with('$')
+}
+
+/// Checking a PIN against its stored form. Both hashes and legacy plaintext
+/// PINs are compared in constant time.
+pub fn verify(stored: &str, pin: &str) -> bool {
+    if is_legacy(stored) {
+        return stored.as_bytes().ct_eq(pin.as_bytes()).into();
+    }
+
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(pin.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Fewest digits a `PinPolicy` may ask for
+pub const MIN_LENGTH: usize = 4;
+
+/// Rules a customer-chosen PIN has to pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct PinPolicy {
+    /// Exact number of digits
+    pub length: usize,
+    /// Refuse PINs made of one repeated digit or a short repeated group, like `111111` or `121212`
+    pub reject_repeated: bool,
+    /// Refuse runs of consecutive digits, like `123456` or `987654`
+    pub reject_sequences: bool,
+    /// Refuse PINs that look like a birth year or a date, like `198512` or `241290`
+    pub reject_birth_years: bool,
+}
+
+impl Default for PinPolicy {
+    fn default() -> Self {
+        Self {
+            length: 6,
+            reject_repeated: true,
+            reject_sequences: true,
+            reject_birth_years: true,
+        }
+    }
+}
+
+/// Why a PIN was refused by the `PinPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeakPin {
+    WrongLength(usize),
+    NotDigits,
+    Repeated,
+    Sequence,
+    BirthYear,
+}
+
+impl Display for WeakPin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            WeakPin::WrongLength(length) => write!(f, "The PIN must have exactly {} digits", length),
+            WeakPin::NotDigits => write!(f, "The PIN may only contain digits"),
+            WeakPin::Repeated => write!(f, "The PIN repeats the same digits"),
+            WeakPin::Sequence => write!(f, "The PIN is a sequence of consecutive digits"),
+            WeakPin::BirthYear => write!(f, "The PIN looks like a birth year or a date"),
+        }
+    }
+}
+
+impl PinPolicy {
+    /// Checking a PIN against the policy
+    pub fn check(&self, pin: &str) -> Result<(), WeakPin> {
+        if !pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err(WeakPin::NotDigits);
+        }
+        if pin.len() != self.length {
+            return Err(WeakPin::WrongLength(self.length));
+        }
+
+        let digits: Vec<u8> = pin.bytes().map(|b| b - b'0').collect();
+        if self.reject_repeated && is_repeated(&digits) {
+            return Err(WeakPin::Repeated);
+        }
+        if self.reject_sequences && is_sequence(&digits) {
+            return Err(WeakPin::Sequence);
+        }
+        if self.reject_birth_years && looks_like_birth_date(&digits) {
+            return Err(WeakPin::BirthYear);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the PIN is one group of up to three digits repeated, e.g. `777777`, `121212`, `123123`
+fn is_repeated(digits: &[u8]) -> bool {
+    (1..=3).any(|period| {
+        digits.len() > period && digits.iter().enumerate().all(|(i, d)| *d == digits[i % period])
+    })
+}
+
+/// Whether every digit is one more, or one less, than the one before.
+/// Wrapping around is included, so `890123` counts too. A single digit is
+/// no sequence.
+fn is_sequence(digits: &[u8]) -> bool {
+    if digits.len() < 2 {
+        return false;
+    }
+    let steps: Vec<u8> = digits.windows(2).map(|w| (w[1] + 10 - w[0]) % 10).collect();
+    steps.iter().all(|s| *s == 1) || steps.iter().all(|s| *s == 9)
+}
+
+/// Whether the PIN starts or ends with a year from 1900 to 2099, or reads as
+/// a date in the DDMMYY, MMDDYY or YYMMDD formats
+fn looks_like_birth_date(digits: &[u8]) -> bool {
+    let pair = |i: usize| digits.get(i..i + 2).map(|p| p[0] * 10 + p[1]);
+    let is_century = |p: Option<u8>| matches!(p, Some(19) | Some(20));
+    let is_day = |p: Option<u8>| matches!(p, Some(1..=31));
+    let is_month = |p: Option<u8>| matches!(p, Some(1..=12));
+
+    let n = digits.len();
+    if n >= 4 && (is_century(pair(0)) || is_century(pair(n - 4))) {
+        return true;
+    }
+    n == 6
+        && ((is_day(pair(0)) && is_month(pair(2)))
+            || (is_month(pair(0)) && is_day(pair(2)))
+            || (is_month(pair(2)) && is_day(pair(4))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_pin_verifies() {
+        let stored = hash("493817");
+        assert!(!is_legacy(&stored));
+        assert!(verify(&stored, "493817"));
+        assert!(!verify(&stored, "493818"));
+    }
+
+    #[test]
+    fn same_pin_gets_different_salts() {
+        assert_ne!(hash("493817"), hash("493817"));
+    }
+
+    #[test]
+    fn legacy_plaintext_pin_verifies() {
+        assert!(is_legacy("493817"));
+        assert!(verify("493817", "493817"));
+        assert!(!verify("493817", "49381"));
+        assert!(!verify("493817", "000000"));
+    }
+
+    #[test]
+    fn policy_accepts_strong_pins() {
+        let policy = PinPolicy::default();
+        for pin in ["493817", "705349", "836041"] {
+            assert_eq!(policy.check(pin), Ok(()), "{}", pin);
+        }
+    }
+
+    #[test]
+    fn policy_rejects_weak_pins() {
+        let policy = PinPolicy::default();
+        let weak = [
+            ("12345", WeakPin::WrongLength(6)),
+            ("12a456", WeakPin::NotDigits),
+            ("777777", WeakPin::Repeated),
+            ("121212", WeakPin::Repeated),
+            ("843843", WeakPin::Repeated),
+            ("123456", WeakPin::Sequence),
+            ("890123", WeakPin::Sequence),
+            ("654321", WeakPin::Sequence),
+            ("198574", WeakPin::BirthYear),
+            ("742003", WeakPin::BirthYear),
+            ("241290", WeakPin::BirthYear),
+            ("122490", WeakPin::BirthYear),
+            ("900415", WeakPin::BirthYear),
+        ];
+        for (pin, reason) in weak {
+            assert_eq!(policy.check(pin), Err(reason), "{}", pin);
+        }
+    }
+
+    #[test]
+    fn single_digits_are_neither_sequences_nor_repeated() {
+        for digits in [&[][..], &[7]] {
+            assert!(!is_sequence(digits));
+            assert!(!is_repeated(digits));
+        }
+        let policy = PinPolicy {
+            length: 1,
+            ..PinPolicy::default()
+        };
+        assert_eq!(policy.check("7"), Ok(()));
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Unlicense
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::Path;
+
+/// Reading a PIN without echoing it. On a terminal the PIN is typed at a
+/// hidden prompt; otherwise (pipes, scripts) it is the next line of `input`.
+pub fn prompt_pin(prompt: &str, input: &mut impl BufRead) -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password(format!("{} ", prompt));
+    }
+
+    eprintln!("{}", prompt);
+    read_pin_line(input)
+}
+
+fn read_pin_line(input: &mut impl BufRead) -> io::Result<String> {
+    let mut pin = String::new();
+    if input.read_line(&mut pin)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No PIN was given"));
+    }
+    Ok(pin.trim().to_string())
+}
+
+/// Where the CLI reads PINs from. PINs are never taken as arguments, since
+/// those end up in the shell history and in `ps` output.
+pub struct PinReader {
+    fd: Option<BufReader<File>>,
+}
+
+impl PinReader {
+    /// Reading from the hidden terminal prompt, or one PIN per line from an
+    /// already open file descriptor such as `3` in `bank ... --pin-fd 3 3<pin.txt`
+    pub fn new(pin_fd: Option<u32>) -> io::Result<Self> {
+        let fd = match pin_fd {
+            Some(fd) => Some(BufReader::new(File::open(format!("/dev/fd/{}", fd))?)),
+            None => None,
+        };
+        Ok(Self { fd })
+    }
+
+    /// Reading one PIN per line from a file, such as a menu script
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            fd: Some(BufReader::new(File::open(path)?)),
+        })
+    }
+
+    /// What is left of the file or descriptor after the PINs read so far
+    pub fn into_rest(self) -> Option<BufReader<File>> {
+        self.fd
+    }
+
+    /// Whether a person is typing the PINs at a terminal, in which case new
+    /// PINs are confirmed. PINs piped into the standard input are not.
+    pub fn is_interactive(&self) -> bool {
+        self.fd.is_none() && io::stdin().is_terminal()
+    }
+
+    pub fn read(&mut self, prompt: &str) -> io::Result<String> {
+        match self.fd.as_mut() {
+            Some(fd) => read_pin_line(fd),
+            None => prompt_pin(prompt, &mut io::stdin().lock()),
+        }
+    }
+
+    /// Reading a new PIN, asking for it twice when typed by a person
+    pub fn read_new(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let pin = self.read(prompt)?;
+        if self.is_interactive() && self.read("Please input the new pin again:")? != pin {
+            eprintln!("The new pins do not match. Try again...");
+            return Ok(None);
+        }
+        Ok(Some(pin))
+    }
+}
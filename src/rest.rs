@@ -0,0 +1,350 @@
+// SPDX-License-Identifier: Unlicense
+
+//! JSON API over HTTP, served by `bank serve` on localhost for web and mobile
+//! front ends. A customer logs in by posting the PIN, and any authenticator
+//! code, to `/accounts/{account}/tokens`, then sends the token as
+//! `Authorization: Bearer <token>`. Bodies are the objects `--output json`
+//! prints, and `/openapi.json` describes every route.
+
+use crate::database::{self, Auth, Bank};
+use crate::error::{BankError, BankResult};
+use crate::output::Report;
+use crate::storage::Storage;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// OpenAPI 3 description of the API, served at `/openapi.json`
+pub const OPENAPI: &str = include_str!("rest/openapi.json");
+
+/// Postings returned by the history when the query gives no `limit`
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+
+/// A bank shared by the handlers of every route
+pub type SharedBank<S> = Arc<Mutex<Bank<S>>>;
+
+/// How the API is served
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    /// How long login tokens stay valid
+    pub token_ttl: Duration,
+    /// Key that lets the GraphQL endpoint read every account, instead of
+    /// only the one a token was issued for
+    pub staff_key: Option<String>,
+}
+
+struct Api<S> {
+    bank: SharedBank<S>,
+    token_ttl: Duration,
+}
+
+type Shared<S> = State<Arc<Api<S>>>;
+
+/// A refusal or failure of the bank as an HTTP response, with the same body
+/// as errors printed with `--output json`, less the exit code
+#[derive(Debug)]
+pub struct ApiError(BankError);
+
+impl From<BankError> for ApiError {
+    fn from(e: BankError) -> Self {
+        ApiError(e)
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self.0 {
+            BankError::WrongPin { .. }
+            | BankError::WrongCode { .. }
+            | BankError::SecondFactorRequired
+            | BankError::InvalidToken => StatusCode::UNAUTHORIZED,
+            BankError::Locked => StatusCode::LOCKED,
+            BankError::AccountNotFound => StatusCode::NOT_FOUND,
+            BankError::AccountExists
+            | BankError::Unavailable(_)
+            | BankError::BalanceRemaining(_)
+            | BankError::StatusChangeNotAllowed
+            | BankError::TotpAlreadyEnabled
+            | BankError::TotpNotEnabled => StatusCode::CONFLICT,
+            BankError::SchemaTooNew { .. } | BankError::InvalidMigrationTarget(_) | BankError::Storage(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    result: &'static str,
+    error: &'static str,
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            result: "error",
+            error: self.0.name(),
+            message: self.0.to_string(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// Running `f` on the bank off the async workers, since SQLite and Argon2 block
+pub(crate) async fn blocking<S, T>(
+    bank: SharedBank<S>,
+    f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+) -> BankResult<T>
+where
+    S: Storage + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&mut bank.lock().unwrap_or_else(PoisonError::into_inner)))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+async fn with_bank<S, T>(
+    api: Arc<Api<S>>,
+    f: impl FnOnce(&mut Bank<S>) -> BankResult<T> + Send + 'static,
+) -> Result<T, ApiError>
+where
+    S: Storage + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(blocking(api.bank.clone(), f).await?)
+}
+
+/// Token from the `Authorization: Bearer` header
+pub(crate) fn bearer(headers: &HeaderMap) -> Result<String, ApiError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .ok_or(ApiError(BankError::InvalidToken))
+}
+
+#[derive(Deserialize)]
+struct NewAccount {
+    /// Chosen PIN; the bank draws one if it is left out
+    pin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    pin: String,
+    code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Amount {
+    amount: u64,
+}
+
+#[derive(Deserialize)]
+struct NewTransfer {
+    to: String,
+    amount: u64,
+    /// Needed from the TOTP threshold up on accounts with an authenticator
+    code: Option<String>,
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+async fn create_account<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Json(body): Json<NewAccount>,
+) -> Result<(StatusCode, Json<Report>), ApiError> {
+    let report = with_bank(api, move |bank| match body.pin {
+        Some(pin) => {
+            let (number, ()) = database::with_fresh_number(|number| bank.create_account_with_pin(number, 0, &pin))?;
+            Ok(Report::AccountCreated {
+                account: number.to_string(),
+                pin: None,
+            })
+        }
+        None => {
+            let (number, pin) = database::with_fresh_number(|number| bank.create_account(number, 0))?;
+            Ok(Report::AccountCreated {
+                account: number.to_string(),
+                pin: Some(pin),
+            })
+        }
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+async fn issue_token<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    Json(body): Json<Login>,
+) -> Result<(StatusCode, Json<Report>), ApiError> {
+    let ttl = api.token_ttl;
+    let report = with_bank(api, move |bank| {
+        let token = bank.issue_token(&account, &body.pin, body.code.as_deref(), ttl)?;
+        Ok(Report::Token {
+            account,
+            token,
+            expires_in: ttl.as_secs(),
+        })
+    })
+    .await?;
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+async fn account<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let report = with_bank(api, move |bank| {
+        let balance = bank.check_balance(&account, Auth::Token(&token), None)?;
+        let status = bank.fetch_account(&account)?.status;
+        Ok(Report::Account {
+            account,
+            status,
+            balance,
+        })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+async fn balance<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let report = with_bank(api, move |bank| {
+        let balance = bank.check_balance(&account, Auth::Token(&token), None)?;
+        Ok(Report::Balance { account, balance })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+async fn history<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    let report = with_bank(api, move |bank| {
+        let entries = bank.history(&account, Auth::Token(&token), None, limit)?;
+        Ok(Report::History { account, entries })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+async fn deposit<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<Amount>,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let report = with_bank(api, move |bank| {
+        let balance = bank.deposit(&account, body.amount, Auth::Token(&token))?;
+        Ok(Report::Balance { account, balance })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+async fn withdraw<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<Amount>,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let report = with_bank(api, move |bank| {
+        let balance = bank.withdraw(&account, body.amount, Auth::Token(&token))?;
+        Ok(Report::Balance { account, balance })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+async fn transfer<S: Storage + Send + 'static>(
+    State(api): Shared<S>,
+    Path(account): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<NewTransfer>,
+) -> Result<Json<Report>, ApiError> {
+    let token = bearer(&headers)?;
+    let report = with_bank(api, move |bank| {
+        let code = body.code.as_deref();
+        let balance = bank.transfer(&account, &body.to, body.amount, Auth::Token(&token), code)?;
+        Ok(Report::Balance { account, balance })
+    })
+    .await?;
+    Ok(Json(report))
+}
+
+/// Routes of the API on `bank`, GraphQL included if it was built in
+pub fn router<S: Storage + Send + 'static>(bank: Bank<S>, options: ApiOptions) -> Router {
+    let bank = Arc::new(Mutex::new(bank));
+    let api = Arc::new(Api {
+        bank: bank.clone(),
+        token_ttl: options.token_ttl,
+    });
+    let router = Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/accounts", post(create_account::<S>))
+        .route("/accounts/{account}", get(account::<S>))
+        .route("/accounts/{account}/tokens", post(issue_token::<S>))
+        .route("/accounts/{account}/balance", get(balance::<S>))
+        .route("/accounts/{account}/history", get(history::<S>))
+        .route("/accounts/{account}/deposits", post(deposit::<S>))
+        .route("/accounts/{account}/withdrawals", post(withdraw::<S>))
+        .route("/accounts/{account}/transfers", post(transfer::<S>))
+        .with_state(api);
+    #[cfg(feature = "graphql")]
+    let router = router.merge(crate::graphql::router(bank, options.staff_key));
+    router
+}
+
+/// Serving the API on `addr` until Ctrl-C. There is no TLS, so only
+/// loopback addresses are accepted.
+pub fn serve<S: Storage + Send + 'static>(bank: Bank<S>, addr: SocketAddr, options: ApiOptions) -> io::Result<()> {
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The API has no TLS and only listens on localhost, not on {}", addr.ip()),
+        ));
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!("Serving the bank API on http://{}", listener.local_addr()?);
+        axum::serve(listener, router(bank, options))
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+    })
+}
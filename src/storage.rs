@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Where accounts, the ledger and the authentication state are kept.
+//! `Bank` holds the rules and talks to one `Storage`; the backends only
+//! store and fetch, and assume the rules were checked.
+
+use crate::database::{Account, AccountStatus};
+use crate::error::BankResult;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+pub mod backup;
+mod memory;
+pub mod migrations;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::{open_existing, SqliteStorage};
+
+/// Kind of a ledger posting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum PostingKind {
+    Deposit,
+    Withdrawal,
+    TransferOut,
+    TransferIn,
+    /// Charged by the bank for a withdrawal or transfer
+    Fee,
+}
+
+impl PostingKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PostingKind::Deposit => "deposit",
+            PostingKind::Withdrawal => "withdrawal",
+            PostingKind::TransferOut => "transfer_out",
+            PostingKind::TransferIn => "transfer_in",
+            PostingKind::Fee => "fee",
+        }
+    }
+
+    /// Whether the posting adds money to the account
+    pub fn is_credit(&self) -> bool {
+        matches!(self, PostingKind::Deposit | PostingKind::TransferIn)
+    }
+}
+
+impl fmt::Display for PostingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PostingKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "deposit" => Ok(PostingKind::Deposit),
+            "withdrawal" => Ok(PostingKind::Withdrawal),
+            "transfer_out" => Ok(PostingKind::TransferOut),
+            "transfer_in" => Ok(PostingKind::TransferIn),
+            "fee" => Ok(PostingKind::Fee),
+            other => Err(format!("unknown posting kind `{}`", other)),
+        }
+    }
+}
+
+/// One side of a movement of money, changing the balance of
+/// `account_number` and leaving a line in its ledger
+#[derive(Debug, Clone, Copy)]
+pub struct Posting<'a> {
+    pub account_number: &'a str,
+    pub kind: PostingKind,
+    pub amount: u64,
+    pub counterparty: Option<&'a str>,
+}
+
+/// A line of the ledger of one account, as stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub kind: PostingKind,
+    pub amount: u64,
+    pub counterparty: Option<String>,
+    /// `YYYY-MM-DD HH:MM:SS` in UTC
+    pub created_at: String,
+}
+
+/// Which accounts a listing returns. Unset fields match every account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default, deny_unknown_fields))]
+pub struct AccountFilter {
+    pub status: Option<AccountStatus>,
+    pub min_balance: Option<u64>,
+    pub max_balance: Option<u64>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account: &Account) -> bool {
+        self.status.is_none_or(|status| account.status == status)
+            && self.min_balance.is_none_or(|min| account.balance >= min)
+            && self.max_balance.is_none_or(|max| account.balance <= max)
+    }
+}
+
+/// Everything needed to check a PIN and second factor
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub pin_hash: String,
+    /// Wrong PINs or codes since the last success or lockout
+    pub failed_attempts: u32,
+    /// Whether a lockout is in force right now
+    pub locked: bool,
+    /// Base32 TOTP secret, also set while enrollment is unconfirmed
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    /// Last time step a TOTP code was accepted for, so codes are not reused
+    pub totp_last_step: Option<u64>,
+}
+
+/// A recovery code that has not been used yet
+#[derive(Debug, Clone)]
+pub struct RecoveryCode {
+    pub id: u64,
+    pub code_hash: String,
+}
+
+/// Storage backend of a `Bank`. Every method is atomic on its own.
+/// Methods changing an account do nothing for unknown account numbers.
+pub trait Storage {
+    /// Starting a unit of work that nobody else using the same storage can
+    /// interleave with, until `commit` or `rollback`. Units do not nest.
+    /// Backends used by a single process at a time can keep the defaults.
+    fn begin(&mut self) -> BankResult<()> {
+        Ok(())
+    }
+    fn commit(&mut self) -> BankResult<()> {
+        Ok(())
+    }
+    fn rollback(&mut self) -> BankResult<()> {
+        Ok(())
+    }
+
+    /// Fetching a single account, including closed ones
+    fn account(&self, account_number: &str) -> BankResult<Option<Account>>;
+    /// Storing a new active account. Fails with `AccountExists` if the number is taken.
+    fn insert_account(&mut self, account_number: &str, pin_hash: &str, balance: u64) -> BankResult<()>;
+    fn set_pin_hash(&mut self, account_number: &str, pin_hash: &str) -> BankResult<()>;
+    /// Marking the account as used now, which keeps it from turning dormant
+    fn touch_activity(&mut self, account_number: &str) -> BankResult<()>;
+    /// Changing the status and keeping the change and its reason in the history
+    fn change_status(
+        &mut self,
+        account_number: &str,
+        old_status: AccountStatus,
+        new_status: AccountStatus,
+        reason: &str,
+    ) -> BankResult<()>;
+    /// Up to `limit` accounts matching `filter` after the one with the id
+    /// `after_id`, in the order they were opened
+    fn accounts(&self, filter: &AccountFilter, after_id: u64, limit: usize) -> BankResult<Vec<Account>>;
+    /// Active accounts without activity in the last `months` months
+    fn idle_accounts(&self, months: u32) -> BankResult<Vec<String>>;
+
+    /// Applying postings to balances and the ledger, all or none of them
+    fn post(&mut self, postings: &[Posting<'_>]) -> BankResult<()>;
+    /// Applying the payout postings and closing the account, all or nothing
+    fn close_account(&mut self, account_number: &str, payout: &[Posting<'_>]) -> BankResult<()>;
+    /// The last `limit` ledger lines of an account, newest first
+    fn history(&self, account_number: &str, limit: usize) -> BankResult<Vec<LedgerEntry>>;
+    /// Up to `limit` ledger lines of an account after the line `after_id`, oldest first
+    fn ledger_since(&self, account_number: &str, after_id: u64, limit: usize) -> BankResult<Vec<LedgerEntry>>;
+
+    fn credentials(&self, account_number: &str) -> BankResult<Option<Credentials>>;
+    /// Forgetting wrong attempts and any lockout
+    fn reset_failures(&mut self, account_number: &str) -> BankResult<()>;
+    fn count_failure(&mut self, account_number: &str) -> BankResult<()>;
+    /// Locking the account for `minutes`, or until unlocked if zero,
+    /// and starting the count of wrong attempts over
+    fn lock(&mut self, account_number: &str, minutes: u32) -> BankResult<()>;
+    /// Writing a PIN or code attempt to the audit trail
+    fn record_attempt(&mut self, account_number: &str, operation: &str, outcome: &str) -> BankResult<()>;
+
+    /// Storing a fresh TOTP secret that is not enforced yet
+    fn set_totp_secret(&mut self, account_number: &str, secret: &str) -> BankResult<()>;
+    /// Enforcing the stored TOTP secret, replacing any recovery codes
+    fn enable_totp(&mut self, account_number: &str, step: u64, recovery_code_hashes: &[String]) -> BankResult<()>;
+    /// Removing the TOTP secret and the recovery codes
+    fn disable_totp(&mut self, account_number: &str) -> BankResult<()>;
+    fn set_totp_last_step(&mut self, account_number: &str, step: u64) -> BankResult<()>;
+    fn unused_recovery_codes(&self, account_number: &str) -> BankResult<Vec<RecoveryCode>>;
+    fn use_recovery_code(&mut self, id: u64) -> BankResult<()>;
+}
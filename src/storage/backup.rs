@@ -0,0 +1,458 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Backups of the SQLite database. Copies are taken with SQLite's online
+//! backup API, so tellers and servers keep working meanwhile, and are only
+//! restored after they pass the same checks as `verify`.
+
+use super::migrations::{self, Migration};
+use super::open_existing;
+use super::sqlite::BUSY_TIMEOUT;
+use crate::error::{BankError, BankResult};
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, Result as SqlResult};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Pages copied at once. Writers wait for a step, not for the whole copy.
+const PAGES_PER_STEP: i32 = 1024;
+/// Pause between steps, to let writers in
+const PAUSE: Duration = Duration::from_millis(5);
+
+/// Timestamped copies are named `bank-YYYYMMDD-HHMMSS.s3db`
+const PREFIX: &str = "bank-";
+const EXTENSION: &str = ".s3db";
+
+/// Why a backup, check or restore did not go through
+#[derive(Debug)]
+pub enum BackupError {
+    /// The database failed, or has a schema this bank does not know
+    Bank(BankError),
+    Io(io::Error),
+    /// A file is already there; backups never overwrite
+    Exists(PathBuf),
+    /// The copy failed its checks, for the reasons given
+    Invalid(Vec<String>),
+}
+
+impl BackupError {
+    /// Exit code of the command line, see `BankError::exit_code`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BackupError::Bank(e) => e.exit_code(),
+            BackupError::Io(_) => 1,
+            BackupError::Exists(_) => 8,
+            BackupError::Invalid(_) => 9,
+        }
+    }
+
+    /// Stable name of the error for machine-readable output
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackupError::Bank(e) => e.name(),
+            BackupError::Io(_) => "failure",
+            BackupError::Exists(_) => "backup_exists",
+            BackupError::Invalid(_) => "backup_invalid",
+        }
+    }
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            BackupError::Bank(e) => e.fmt(f),
+            BackupError::Io(e) => write!(f, "Backup failed: {}", e),
+            BackupError::Exists(path) => write!(f, "`{}` already exists and is not overwritten", path.display()),
+            BackupError::Invalid(problems) => {
+                write!(f, "The backup failed its checks: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackupError::Bank(e) => Some(e),
+            BackupError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BankError> for BackupError {
+    fn from(e: BankError) -> Self {
+        BackupError::Bank(e)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(e: rusqlite::Error) -> Self {
+        BackupError::Bank(e.into())
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+/// What a backup that passed its checks holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// Schema version of the copy, before any upgrade
+    pub schema_version: u32,
+    pub accounts: u64,
+    pub postings: u64,
+}
+
+/// Opening the database at `path` to restore into, which may be missing
+pub fn open_for_restore(path: &Path) -> BankResult<Connection> {
+    let db = Connection::open(path)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(db)
+}
+
+fn copy(from: &Connection, to: &mut Connection) -> SqlResult<()> {
+    Backup::new(from, to)?.run_to_completion(PAGES_PER_STEP, PAUSE, None)
+}
+
+/// Copying `db` to a new file at `target`. The copy is written next to it
+/// first, so a failed backup leaves nothing at `target`.
+pub fn backup(db: &Connection, target: &Path) -> Result<(), BackupError> {
+    if target.exists() {
+        return Err(BackupError::Exists(target.to_path_buf()));
+    }
+    let mut partial = target.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let written = (|| -> SqlResult<()> {
+        let mut copy_db = Connection::open(&partial)?;
+        copy(db, &mut copy_db)?;
+        // A copy of a WAL database would need its -wal and -shm files
+        copy_db.pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get::<_, String>(0))?;
+        Ok(())
+    })();
+    match written {
+        Ok(()) => Ok(fs::rename(&partial, target)?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e.into())
+        }
+    }
+}
+
+/// Copying `db` into `dir` under a timestamped name, then deleting all
+/// but the newest `keep` timestamped copies there. Returns the new copy and
+/// the ones deleted.
+pub fn backup_into(db: &Connection, dir: &Path, keep: Option<usize>) -> Result<(PathBuf, Vec<PathBuf>), BackupError> {
+    let stamp: String = db.query_row("SELECT strftime('%Y%m%d-%H%M%S', 'now')", [], |row| row.get(0))?;
+    let target = dir.join(format!("{}{}{}", PREFIX, stamp, EXTENSION));
+    backup(db, &target)?;
+
+    let mut removed = Vec::new();
+    if let Some(keep) = keep {
+        let mut copies: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(is_timestamped)
+            })
+            .collect();
+        // The timestamps sort by name, newest last
+        copies.sort();
+        let old = copies.len().saturating_sub(keep);
+        for path in copies.into_iter().take(old) {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok((target, removed))
+}
+
+/// Whether `name` is one of the timestamped copies `backup_into` writes
+fn is_timestamped(name: &str) -> bool {
+    let Some(stamp) = name.strip_prefix(PREFIX).and_then(|rest| rest.strip_suffix(EXTENSION)) else {
+        return false;
+    };
+    stamp.len() == 15
+        && stamp
+            .char_indices()
+            .all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() })
+}
+
+/// Checking the backup at `path`: the integrity of the file, a schema this
+/// bank can read or upgrade, and the invariants of the ledger. The file is
+/// only read; the checks run on an upgraded copy in memory.
+pub fn verify(path: &Path) -> Result<Verification, BackupError> {
+    let (verification, _) = check(&mut snapshot(path)?)?;
+    Ok(verification)
+}
+
+/// Private copy in memory of the backup at `path`, taken in one read
+/// transaction, so later changes to the file do not reach it
+fn snapshot(path: &Path) -> Result<Connection, BackupError> {
+    let file = open_existing(path, true)?;
+    let mut db = Connection::open_in_memory()?;
+    copy(&file, &mut db)?;
+    Ok(db)
+}
+
+/// The checks of `verify` on a snapshot, which is upgraded on the way.
+/// Returns the migrations applied as well.
+fn check(db: &mut Connection) -> Result<(Verification, Vec<&'static Migration>), BackupError> {
+    let integrity: Vec<String> = db
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get(0))?
+        .collect::<SqlResult<_>>()?;
+    if integrity != ["ok"] {
+        return Err(BackupError::Invalid(integrity));
+    }
+
+    let schema_version = migrations::current_version(db)?;
+    let applied = migrations::migrate(db, migrations::latest_version())?;
+
+    let problems = ledger_problems(db)?;
+    if !problems.is_empty() {
+        return Err(BackupError::Invalid(problems));
+    }
+    let verification = Verification {
+        schema_version,
+        accounts: db.query_row("SELECT COUNT(*) FROM account", [], |row| row.get(0))?,
+        postings: db.query_row("SELECT COUNT(*) FROM ledger", [], |row| row.get(0))?,
+    };
+    Ok((verification, applied))
+}
+
+/// Invariants of the accounts and the ledger, each query returning one
+/// row per offending account or line
+const INVARIANTS: &[(&str, &str)] = &[
+    (
+        "negative balance on account",
+        "SELECT account_number FROM account WHERE balance < 0",
+    ),
+    (
+        "closed account still holds money",
+        "SELECT account_number FROM account WHERE status = 'closed' AND balance <> 0",
+    ),
+    (
+        "ledger line without an account",
+        "SELECT id FROM ledger WHERE account_number NOT IN (SELECT account_number FROM account)",
+    ),
+    (
+        "ledger line with an invalid kind or amount",
+        "SELECT id FROM ledger WHERE amount <= 0
+        OR kind NOT IN ('deposit', 'withdrawal', 'transfer_out', 'transfer_in', 'fee')",
+    ),
+    (
+        "transfer without its other half, from account",
+        "WITH sent AS (
+            SELECT account_number AS sender, counterparty AS receiver, amount, COUNT(*) AS lines
+            FROM ledger WHERE kind = 'transfer_out' GROUP BY 1, 2, 3
+        ), received AS (
+            SELECT counterparty AS sender, account_number AS receiver, amount, COUNT(*) AS lines
+            FROM ledger WHERE kind = 'transfer_in' GROUP BY 1, 2, 3
+        )
+        SELECT sender FROM sent LEFT JOIN received USING (sender, receiver, amount)
+        WHERE received.lines IS NOT sent.lines
+        UNION
+        SELECT sender FROM received LEFT JOIN sent USING (sender, receiver, amount)
+        WHERE sent.lines IS NULL",
+    ),
+    // Opening balances are not in the ledger, but cannot be negative
+    (
+        "balance below what the ledger paid in on account",
+        "SELECT account_number FROM account JOIN (
+            SELECT account_number,
+                SUM(CASE WHEN kind IN ('deposit', 'transfer_in') THEN amount ELSE -amount END) AS net
+            FROM ledger GROUP BY account_number
+        ) USING (account_number)
+        WHERE balance < net",
+    ),
+];
+
+fn ledger_problems(db: &Connection) -> SqlResult<Vec<String>> {
+    let mut problems = Vec::new();
+    for (problem, query) in INVARIANTS {
+        let offenders: Vec<String> = db
+            .prepare(query)?
+            .query_map([], |row| row.get::<_, rusqlite::types::Value>(0))?
+            .map(|value| {
+                value.map(|value| match value {
+                    rusqlite::types::Value::Text(text) => text,
+                    rusqlite::types::Value::Integer(id) => id.to_string(),
+                    _ => "?".to_string(),
+                })
+            })
+            .collect::<SqlResult<_>>()?;
+        problems.extend(
+            offenders
+                .into_iter()
+                .map(|offender| format!("{} {}", problem, offender)),
+        );
+    }
+    Ok(problems)
+}
+
+/// Replacing the contents of `db` with the backup at `path`. A snapshot of
+/// the file is checked like `verify` does and upgraded, and that snapshot is
+/// what gets restored. Returns what was checked and the migrations applied.
+pub fn restore(db: &mut Connection, path: &Path) -> Result<(Verification, Vec<&'static Migration>), BackupError> {
+    let mut checked = snapshot(path)?;
+    let (verification, applied) = check(&mut checked)?;
+    copy(&checked, db)?;
+    Ok((verification, applied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Bank;
+    use crate::luhn::AccountNumber;
+    use std::str::FromStr;
+
+    const PIN: &str = "591732";
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("bank-backup-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A bank with two accounts and a transfer between them
+    fn bank(dir: &TestDir) -> Bank {
+        let mut bank = Bank::open(dir.0.join("bank.s3db")).unwrap();
+        for number in ["8536276945", "35001576202"] {
+            bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+                .unwrap();
+        }
+        bank.transfer("8536276945", "35001576202", 30, PIN, None).unwrap();
+        bank
+    }
+
+    #[test]
+    fn backups_are_verified_and_restored() {
+        let dir = TestDir::new("restore");
+        let mut bank = bank(&dir);
+        let target = dir.0.join("copy.s3db");
+        backup(bank.storage().connection(), &target).unwrap();
+        assert!(matches!(
+            backup(bank.storage().connection(), &target),
+            Err(BackupError::Exists(_))
+        ));
+
+        let verification = verify(&target).unwrap();
+        assert_eq!((verification.accounts, verification.postings), (2, 2));
+        assert_eq!(verification.schema_version, migrations::latest_version());
+
+        bank.deposit("8536276945", 500, PIN).unwrap();
+        let mut db = open_existing(&dir.0.join("bank.s3db"), false).unwrap();
+        let (_, applied) = restore(&mut db, &target).unwrap();
+        assert!(applied.is_empty());
+        assert_eq!(bank.balance("8536276945").unwrap(), 70);
+
+        // A copy failing its checks leaves the database alone
+        let broken = Connection::open(&target).unwrap();
+        broken.execute_batch("UPDATE account SET balance = 0").unwrap();
+        drop(broken);
+        bank.deposit("8536276945", 5, PIN).unwrap();
+        assert!(matches!(restore(&mut db, &target), Err(BackupError::Invalid(_))));
+        assert_eq!(bank.balance("8536276945").unwrap(), 75);
+    }
+
+    #[test]
+    fn older_backups_are_upgraded_on_restore() {
+        let dir = TestDir::new("upgrade");
+        let target = dir.0.join("old.s3db");
+        let mut old = Connection::open(&target).unwrap();
+        migrations::migrate(&mut old, 1).unwrap();
+        drop(old);
+
+        let mut db = open_for_restore(&dir.0.join("bank.s3db")).unwrap();
+        let (verification, applied) = restore(&mut db, &target).unwrap();
+        assert_eq!(verification.schema_version, 1);
+        assert_eq!(applied.len(), migrations::MIGRATIONS.len() - 1);
+        assert_eq!(migrations::current_version(&db).unwrap(), migrations::latest_version());
+        // The backup itself stays as it was
+        let old = open_existing(&target, true).unwrap();
+        assert_eq!(migrations::current_version(&old).unwrap(), 1);
+    }
+
+    #[test]
+    fn broken_invariants_are_found() {
+        let dir = TestDir::new("verify");
+        let bank = bank(&dir);
+        let target = dir.0.join("copy.s3db");
+        backup(bank.storage().connection(), &target).unwrap();
+
+        let db = Connection::open(&target).unwrap();
+        db.execute_batch(
+            "DELETE FROM ledger WHERE kind = 'transfer_out';
+            UPDATE account SET balance = 10 WHERE account_number = '35001576202';",
+        )
+        .unwrap();
+        drop(db);
+        let Err(BackupError::Invalid(problems)) = verify(&target) else {
+            panic!("the copy passed");
+        };
+        assert_eq!(
+            problems,
+            [
+                "transfer without its other half, from account 8536276945",
+                "balance below what the ledger paid in on account 35001576202"
+            ]
+        );
+
+        let db = Connection::open(&target).unwrap();
+        db.execute_batch("PRAGMA user_version = 99").unwrap();
+        drop(db);
+        assert!(matches!(
+            verify(&target),
+            Err(BackupError::Bank(BankError::SchemaTooNew { found: 99, .. }))
+        ));
+        assert!(matches!(verify(&dir.0.join("missing.s3db")), Err(BackupError::Bank(_))));
+    }
+
+    #[test]
+    fn only_the_newest_copies_are_kept() {
+        let dir = TestDir::new("rotate");
+        let bank = bank(&dir);
+        let copies = dir.0.join("copies");
+        fs::create_dir(&copies).unwrap();
+        for stamp in ["20250101-000000", "20250102-000000", "20250103-000000"] {
+            fs::write(copies.join(format!("bank-{}.s3db", stamp)), "").unwrap();
+        }
+        fs::write(copies.join("notes.txt"), "").unwrap();
+
+        let (copy, removed) = backup_into(bank.storage().connection(), &copies, Some(2)).unwrap();
+        assert!(is_timestamped(copy.file_name().unwrap().to_str().unwrap()));
+        assert_eq!(
+            removed,
+            [
+                copies.join("bank-20250101-000000.s3db"),
+                copies.join("bank-20250102-000000.s3db")
+            ]
+        );
+        assert!(copies.join("notes.txt").exists());
+        assert!(!is_timestamped("bank-2025010-0000000.s3db"));
+    }
+}
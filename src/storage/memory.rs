@@ -0,0 +1,523 @@
+// SPDX-License-Identifier: Unlicense
+
+use super::{AccountFilter, Credentials, LedgerEntry, Posting, PostingKind, RecoveryCode, Storage};
+use crate::database::{Account, AccountStatus};
+use crate::error::{BankError, BankResult};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lockout end of accounts locked until an admin unlocks them
+const LOCKED_FOR_GOOD: u64 = u64::MAX;
+
+#[derive(Debug, Clone)]
+struct StoredAccount {
+    id: u64,
+    pin_hash: String,
+    balance: u64,
+    status: AccountStatus,
+    /// Unix timestamps, like everything kept here
+    closed_at: Option<u64>,
+    last_activity_at: Option<u64>,
+    failed_attempts: u32,
+    locked_until: Option<u64>,
+    totp_secret: Option<String>,
+    totp_enabled: bool,
+    totp_last_step: Option<u64>,
+}
+
+impl StoredAccount {
+    fn account(&self, account_number: &str) -> Account {
+        Account {
+            id: self.id,
+            account_number: account_number.to_string(),
+            balance: self.balance,
+            pin_hash: self.pin_hash.clone(),
+            status: self.status,
+            closed_at: self.closed_at.map(format_timestamp),
+            last_activity_at: self.last_activity_at.map(format_timestamp),
+        }
+    }
+}
+
+/// One line of the ledger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerLine {
+    pub account_number: String,
+    pub kind: PostingKind,
+    pub amount: u64,
+    pub counterparty: Option<String>,
+    pub created_at: u64,
+}
+
+impl LedgerLine {
+    fn entry(&self, id: u64) -> LedgerEntry {
+        LedgerEntry {
+            id,
+            kind: self.kind,
+            amount: self.amount,
+            counterparty: self.counterparty.clone(),
+            created_at: format_timestamp(self.created_at),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StoredRecoveryCode {
+    id: u64,
+    account_number: String,
+    code_hash: String,
+    used: bool,
+}
+
+/// Storage that keeps everything in memory and forgets it when dropped.
+/// Useful for tests and for embedding the bank in simulations.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    accounts: HashMap<String, StoredAccount>,
+    ledger: Vec<LedgerLine>,
+    /// Account, operation and outcome of every PIN or code attempt
+    attempts: Vec<(String, String, String)>,
+    /// Account, old status, new status and reason of every status change
+    status_history: Vec<(String, AccountStatus, AccountStatus, String)>,
+    recovery_codes: Vec<StoredRecoveryCode>,
+    next_recovery_code_id: u64,
+    next_account_id: u64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every ledger line so far, oldest first
+    pub fn ledger(&self) -> &[LedgerLine] {
+        &self.ledger
+    }
+
+    fn stored(&mut self, account_number: &str) -> Option<&mut StoredAccount> {
+        self.accounts.get_mut(account_number)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn account(&self, account_number: &str) -> BankResult<Option<Account>> {
+        Ok(self.accounts.get(account_number).map(|stored| stored.account(account_number)))
+    }
+
+    fn insert_account(&mut self, account_number: &str, pin_hash: &str, balance: u64) -> BankResult<()> {
+        if self.accounts.contains_key(account_number) {
+            return Err(BankError::AccountExists);
+        }
+        self.next_account_id += 1;
+        let id = self.next_account_id;
+        self.accounts.insert(
+            account_number.to_string(),
+            StoredAccount {
+                id,
+                pin_hash: pin_hash.to_string(),
+                balance,
+                status: AccountStatus::Active,
+                closed_at: None,
+                last_activity_at: Some(now()),
+                failed_attempts: 0,
+                locked_until: None,
+                totp_secret: None,
+                totp_enabled: false,
+                totp_last_step: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn set_pin_hash(&mut self, account_number: &str, pin_hash: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.pin_hash = pin_hash.to_string();
+        }
+        Ok(())
+    }
+
+    fn touch_activity(&mut self, account_number: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.last_activity_at = Some(now());
+        }
+        Ok(())
+    }
+
+    fn change_status(
+        &mut self,
+        account_number: &str,
+        old_status: AccountStatus,
+        new_status: AccountStatus,
+        reason: &str,
+    ) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.status = new_status;
+            self.status_history.push((
+                account_number.to_string(),
+                old_status,
+                new_status,
+                reason.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn accounts(&self, filter: &AccountFilter, after_id: u64, limit: usize) -> BankResult<Vec<Account>> {
+        let mut accounts: Vec<Account> = self
+            .accounts
+            .iter()
+            .filter(|(_, stored)| stored.id > after_id)
+            .map(|(number, stored)| stored.account(number))
+            .filter(|account| filter.matches(account))
+            .collect();
+        accounts.sort_unstable_by_key(|account| account.id);
+        accounts.truncate(limit);
+        Ok(accounts)
+    }
+
+    fn idle_accounts(&self, months: u32) -> BankResult<Vec<String>> {
+        let cutoff = months_before(now(), months);
+        Ok(self
+            .accounts
+            .iter()
+            .filter(|(_, a)| a.status == AccountStatus::Active)
+            .filter(|(_, a)| a.last_activity_at.is_some_and(|at| at < cutoff))
+            .map(|(number, _)| number.clone())
+            .collect())
+    }
+
+    fn post(&mut self, postings: &[Posting<'_>]) -> BankResult<()> {
+        // Work on copies of the balances so a bad posting leaves nothing half done
+        let mut balances: HashMap<&str, u64> = HashMap::new();
+        for posting in postings {
+            let Some(stored) = self.accounts.get(posting.account_number) else {
+                continue;
+            };
+            let balance = balances.entry(posting.account_number).or_insert(stored.balance);
+            *balance = if posting.kind.is_credit() {
+                balance.checked_add(posting.amount).ok_or(BankError::InvalidAmount)?
+            } else {
+                balance.checked_sub(posting.amount).ok_or(BankError::InsufficientFunds)?
+            };
+        }
+
+        for (account_number, balance) in balances {
+            if let Some(stored) = self.accounts.get_mut(account_number) {
+                stored.balance = balance;
+            }
+        }
+        let created_at = now();
+        self.ledger.extend(
+            postings
+                .iter()
+                .filter(|p| self.accounts.contains_key(p.account_number))
+                .map(|p| LedgerLine {
+                    account_number: p.account_number.to_string(),
+                    kind: p.kind,
+                    amount: p.amount,
+                    counterparty: p.counterparty.map(str::to_string),
+                    created_at,
+                }),
+        );
+        Ok(())
+    }
+
+    fn close_account(&mut self, account_number: &str, payout: &[Posting<'_>]) -> BankResult<()> {
+        self.post(payout)?;
+        if let Some(stored) = self.stored(account_number) {
+            stored.status = AccountStatus::Closed;
+            stored.closed_at = Some(now());
+        }
+        Ok(())
+    }
+
+    fn history(&self, account_number: &str, limit: usize) -> BankResult<Vec<LedgerEntry>> {
+        Ok(self
+            .ledger
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, line)| line.account_number == account_number)
+            .take(limit)
+            .map(|(i, line)| line.entry(i as u64 + 1))
+            .collect())
+    }
+
+    fn ledger_since(&self, account_number: &str, after_id: u64, limit: usize) -> BankResult<Vec<LedgerEntry>> {
+        Ok(self
+            .ledger
+            .iter()
+            .enumerate()
+            .skip(after_id as usize)
+            .filter(|(_, line)| line.account_number == account_number)
+            .take(limit)
+            .map(|(i, line)| line.entry(i as u64 + 1))
+            .collect())
+    }
+
+    fn credentials(&self, account_number: &str) -> BankResult<Option<Credentials>> {
+        let now = now();
+        Ok(self.accounts.get(account_number).map(|stored| Credentials {
+            pin_hash: stored.pin_hash.clone(),
+            failed_attempts: stored.failed_attempts,
+            locked: stored.locked_until.is_some_and(|until| until > now),
+            totp_secret: stored.totp_secret.clone(),
+            totp_enabled: stored.totp_enabled,
+            totp_last_step: stored.totp_last_step,
+        }))
+    }
+
+    fn reset_failures(&mut self, account_number: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.failed_attempts = 0;
+            stored.locked_until = None;
+        }
+        Ok(())
+    }
+
+    fn count_failure(&mut self, account_number: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.failed_attempts += 1;
+        }
+        Ok(())
+    }
+
+    fn lock(&mut self, account_number: &str, minutes: u32) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.failed_attempts = 0;
+            stored.locked_until = Some(if minutes == 0 {
+                LOCKED_FOR_GOOD
+            } else {
+                now() + u64::from(minutes) * 60
+            });
+        }
+        Ok(())
+    }
+
+    fn record_attempt(&mut self, account_number: &str, operation: &str, outcome: &str) -> BankResult<()> {
+        self.attempts.push((
+            account_number.to_string(),
+            operation.to_string(),
+            outcome.to_string(),
+        ));
+        Ok(())
+    }
+
+    fn set_totp_secret(&mut self, account_number: &str, secret: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.totp_secret = Some(secret.to_string());
+            stored.totp_last_step = None;
+        }
+        Ok(())
+    }
+
+    fn enable_totp(&mut self, account_number: &str, step: u64, recovery_code_hashes: &[String]) -> BankResult<()> {
+        let Some(stored) = self.stored(account_number) else {
+            return Ok(());
+        };
+        stored.totp_enabled = true;
+        stored.totp_last_step = Some(step);
+
+        self.recovery_codes.retain(|c| c.account_number != account_number);
+        for code_hash in recovery_code_hashes {
+            self.next_recovery_code_id += 1;
+            self.recovery_codes.push(StoredRecoveryCode {
+                id: self.next_recovery_code_id,
+                account_number: account_number.to_string(),
+                code_hash: code_hash.clone(),
+                used: false,
+            });
+        }
+        Ok(())
+    }
+
+    fn disable_totp(&mut self, account_number: &str) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.totp_secret = None;
+            stored.totp_enabled = false;
+            stored.totp_last_step = None;
+        }
+        self.recovery_codes.retain(|c| c.account_number != account_number);
+        Ok(())
+    }
+
+    fn set_totp_last_step(&mut self, account_number: &str, step: u64) -> BankResult<()> {
+        if let Some(stored) = self.stored(account_number) {
+            stored.totp_last_step = Some(step);
+        }
+        Ok(())
+    }
+
+    fn unused_recovery_codes(&self, account_number: &str) -> BankResult<Vec<RecoveryCode>> {
+        Ok(self
+            .recovery_codes
+            .iter()
+            .filter(|c| c.account_number == account_number && !c.used)
+            .map(|c| RecoveryCode {
+                id: c.id,
+                code_hash: c.code_hash.clone(),
+            })
+            .collect())
+    }
+
+    fn use_recovery_code(&mut self, id: u64) -> BankResult<()> {
+        if let Some(code) = self.recovery_codes.iter_mut().find(|c| c.id == id) {
+            code.used = true;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The clock is set before 1970")
+        .as_secs()
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date. Days past the end of
+/// the month roll over into the next one, as in SQLite's date functions.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Year, month and day of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Timestamp in the `YYYY-MM-DD HH:MM:SS` UTC form SQLite uses
+fn format_timestamp(unix_time: u64) -> String {
+    if unix_time == LOCKED_FOR_GOOD {
+        return "9999-12-31 23:59:59".to_string();
+    }
+    let (year, month, day) = civil_from_days((unix_time / 86400) as i64);
+    let seconds = unix_time % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// The same time of day `months` calendar months before `unix_time`
+fn months_before(unix_time: u64, months: u32) -> u64 {
+    let (year, month, day) = civil_from_days((unix_time / 86400) as i64);
+    let total_months = year * 12 + i64::from(month) - 1 - i64::from(months);
+    let days = days_from_civil(total_months.div_euclid(12), total_months.rem_euclid(12) as u32 + 1, day);
+    (days * 86400).max(0) as u64 + unix_time % 86400
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Bank;
+    use crate::error::BankError;
+    use crate::luhn::AccountNumber;
+
+    fn new_account(bank: &mut Bank<MemoryStorage>, balance: u64) -> BankResult<(String, String)> {
+        let number = AccountNumber::default();
+        let pin = bank.create_account(&number, balance)?;
+        Ok((number.to_string(), pin))
+    }
+
+    #[test]
+    fn timestamps_match_sqlite() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1234567890), "2009-02-13 23:31:30");
+        // 2024-03-31 minus one month rolls over past the end of February
+        let march_31 = days_from_civil(2024, 3, 31) as u64 * 86400;
+        assert_eq!(format_timestamp(months_before(march_31, 1)), "2024-03-02 00:00:00");
+        assert_eq!(format_timestamp(months_before(march_31, 13)), "2023-03-03 00:00:00");
+    }
+
+    #[test]
+    fn postings_move_money_and_fill_the_ledger() -> BankResult<()> {
+        let mut bank = Bank::in_memory();
+        let (from, pin) = new_account(&mut bank, 100)?;
+        let (to, _) = new_account(&mut bank, 0)?;
+
+        assert_eq!(bank.deposit(&from, 20, &pin)?, 120);
+        assert_eq!(bank.withdraw(&from, 70, &pin)?, 50);
+        assert_eq!(bank.transfer(&from, &to, 30, &pin, None)?, 20);
+        assert!(matches!(
+            bank.transfer(&from, &to, 30, &pin, None),
+            Err(BankError::InsufficientFunds)
+        ));
+        assert_eq!(bank.balance(&to)?, 30);
+
+        let kinds: Vec<_> = bank.storage().ledger().iter().map(|l| l.kind.as_str()).collect();
+        assert_eq!(kinds, ["deposit", "withdrawal", "transfer_out", "transfer_in"]);
+
+        let since: Vec<_> = bank.storage().ledger_since(&from, 1, 10)?.iter().map(|e| e.id).collect();
+        assert_eq!(since, [2, 3]);
+        assert_eq!(bank.storage().ledger_since(&to, 0, 10)?[0].id, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn overdrawing_postings_change_nothing() -> BankResult<()> {
+        let mut bank = Bank::in_memory();
+        let (from, _) = new_account(&mut bank, 100)?;
+        let (to, _) = new_account(&mut bank, 0)?;
+        bank.storage_mut().stored(&to).unwrap().balance = u64::MAX;
+        let transfer = |amount| {
+            [
+                Posting {
+                    account_number: &from,
+                    kind: PostingKind::TransferOut,
+                    amount,
+                    counterparty: Some(&to),
+                },
+                Posting {
+                    account_number: &to,
+                    kind: PostingKind::TransferIn,
+                    amount,
+                    counterparty: Some(&from),
+                },
+            ]
+        };
+        let storage = bank.storage_mut();
+        assert!(matches!(storage.post(&transfer(150)), Err(BankError::InsufficientFunds)));
+        assert!(matches!(storage.post(&transfer(50)), Err(BankError::InvalidAmount)));
+        assert_eq!(bank.balance(&from)?, 100);
+        assert!(bank.storage().ledger().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn lockouts_and_dormancy_follow_the_clock() -> BankResult<()> {
+        let mut bank = Bank::in_memory();
+        let (number, pin) = new_account(&mut bank, 0)?;
+        for _ in 0..3 {
+            assert!(bank.login(&number, "wrong", None).is_err());
+        }
+        assert!(matches!(bank.login(&number, &pin, None), Err(BankError::Locked)));
+        bank.storage_mut().stored(&number).unwrap().locked_until = Some(now() - 60);
+        bank.login(&number, &pin, None)?;
+
+        bank.storage_mut().stored(&number).unwrap().last_activity_at = Some(months_before(now(), 13));
+        assert_eq!(bank.mark_dormant(12)?, 1);
+        assert_eq!(bank.fetch_account(&number)?.status, AccountStatus::Dormant);
+        Ok(())
+    }
+}
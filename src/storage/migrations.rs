@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Schema migrations of the SQLite database. The schema version is kept in
+//! `PRAGMA user_version`, and each migration runs in its own transaction
+//! together with the version bump, so a failed one leaves nothing behind.
+//!
+//! Databases from before versioning are at version 0 with some of the
+//! columns already in place, so the early migrations only add what is missing.
+
+use crate::error::{BankError, BankResult};
+use rusqlite::{Connection, Result as SqlResult, TransactionBehavior};
+
+/// One step of the schema history. Only upgrades exist: a bank does not
+/// drop columns holding money and audit records.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    up: fn(&Connection) -> SqlResult<()>,
+}
+
+/// Every migration, in order. Versions count up from 1 without gaps.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "account table",
+        up: |db| {
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS account(
+                    id INTEGER PRIMARY KEY,
+                    account_number TEXT,
+                    pin TEXT DEFAULT '000000',
+                    balance INTEGER DEFAULT 0
+                )",
+            )
+        },
+    },
+    Migration {
+        version: 2,
+        description: "account closure and ledger",
+        up: |db| {
+            add_column_if_missing(db, "account", "status", "TEXT NOT NULL DEFAULT 'active'")?;
+            add_column_if_missing(db, "account", "closed_at", "TEXT")?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS ledger(
+                    id INTEGER PRIMARY KEY,
+                    account_number TEXT NOT NULL,
+                    kind TEXT NOT NULL,
+                    amount INTEGER NOT NULL,
+                    counterparty TEXT,
+                    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                )",
+            )
+        },
+    },
+    Migration {
+        version: 3,
+        description: "status history and dormancy",
+        up: |db| {
+            if add_column_if_missing(db, "account", "last_activity_at", "TEXT")? {
+                // Start the dormancy clock of existing accounts at the upgrade
+                db.execute("UPDATE account SET last_activity_at = CURRENT_TIMESTAMP", ())?;
+            }
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS status_history(
+                    id INTEGER PRIMARY KEY,
+                    account_number TEXT NOT NULL,
+                    old_status TEXT NOT NULL,
+                    new_status TEXT NOT NULL,
+                    reason TEXT NOT NULL,
+                    changed_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                )",
+            )
+        },
+    },
+    Migration {
+        version: 4,
+        description: "PIN lockout and audit trail",
+        up: |db| {
+            add_column_if_missing(db, "account", "failed_attempts", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column_if_missing(db, "account", "locked_until", "TEXT")?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS auth_attempts(
+                    id INTEGER PRIMARY KEY,
+                    account_number TEXT NOT NULL,
+                    operation TEXT NOT NULL,
+                    outcome TEXT NOT NULL,
+                    attempted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                )",
+            )
+        },
+    },
+    Migration {
+        version: 5,
+        description: "TOTP second factor and recovery codes",
+        up: |db| {
+            add_column_if_missing(db, "account", "totp_secret", "TEXT")?;
+            add_column_if_missing(db, "account", "totp_enabled", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column_if_missing(db, "account", "totp_last_step", "INTEGER")?;
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS recovery_codes(
+                    id INTEGER PRIMARY KEY,
+                    account_number TEXT NOT NULL,
+                    code_hash TEXT NOT NULL,
+                    used_at TEXT
+                )",
+            )
+        },
+    },
+    Migration {
+        version: 6,
+        description: "indexes on account numbers",
+        up: |db| {
+            // Fails on databases holding the same number twice, which have to be fixed by hand
+            db.execute_batch(
+                "CREATE UNIQUE INDEX IF NOT EXISTS account_number_unique ON account(account_number);
+                CREATE INDEX IF NOT EXISTS ledger_account_number ON ledger(account_number);
+                CREATE INDEX IF NOT EXISTS recovery_codes_account_number ON recovery_codes(account_number);",
+            )
+        },
+    },
+];
+
+/// Schema version this build of the bank works with
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Schema version of the database, 0 for new or unversioned databases
+pub fn current_version(db: &Connection) -> SqlResult<u32> {
+    db.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Applying the pending migrations up to `target`. Refuses databases with a
+/// newer schema than this build, and targets below the current version.
+/// Returns the migrations applied.
+pub fn migrate(db: &mut Connection, target: u32) -> BankResult<Vec<&'static Migration>> {
+    migrate_with(db, MIGRATIONS, target)
+}
+
+fn migrate_with<'a>(
+    db: &mut Connection,
+    migrations: &'a [Migration],
+    target: u32,
+) -> BankResult<Vec<&'a Migration>> {
+    let current = current_version(db)?;
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(BankError::SchemaTooNew {
+            found: current,
+            supported: latest,
+        });
+    }
+    if target < current || target > latest {
+        return Err(BankError::InvalidMigrationTarget(target));
+    }
+
+    let mut applied = Vec::new();
+    for migration in migrations
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+    {
+        let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another process opening the database may have been first
+        if current_version(&tx)? >= migration.version {
+            continue;
+        }
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// Adding a column to an existing table unless it is already there.
+/// Returns whether the column was added.
+fn add_column_if_missing(db: &Connection, table: &str, column: &str, decl: &str) -> SqlResult<bool> {
+    let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<usize, String>(1))?
+        .flatten()
+        .any(|name| name == column);
+
+    if !exists {
+        db.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), ())?;
+    }
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(db: &Connection, table: &str) -> Vec<String> {
+        db.prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap()
+            .query_map([], |row| row.get(1))
+            .unwrap()
+            .collect::<SqlResult<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn versions_count_up_without_gaps() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1);
+        }
+    }
+
+    #[test]
+    fn migrates_step_by_step() -> BankResult<()> {
+        let mut db = Connection::open_in_memory()?;
+        assert_eq!(migrate(&mut db, 2)?.len(), 2);
+        assert_eq!(current_version(&db)?, 2);
+        assert!(columns(&db, "account").contains(&"closed_at".to_string()));
+        assert!(!columns(&db, "account").contains(&"locked_until".to_string()));
+
+        assert!(matches!(migrate(&mut db, 1), Err(BankError::InvalidMigrationTarget(1))));
+        assert_eq!(migrate(&mut db, latest_version())?.len(), MIGRATIONS.len() - 2);
+        assert!(migrate(&mut db, latest_version())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn adopts_unversioned_databases() -> BankResult<()> {
+        let mut db = Connection::open_in_memory()?;
+        // The schema before versioning, with one of the later columns already added
+        db.execute_batch(
+            "CREATE TABLE account(id INTEGER PRIMARY KEY, account_number TEXT,
+                pin TEXT DEFAULT '000000', balance INTEGER DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'active');
+            INSERT INTO account (id, account_number, pin, balance) VALUES (1, '2334841596', '123456', 50);",
+        )?;
+
+        migrate(&mut db, latest_version())?;
+        let (status, balance, last_activity): (String, u64, Option<String>) = db.query_row(
+            "SELECT status, balance, last_activity_at FROM account WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!((status.as_str(), balance), ("active", 50));
+        assert!(last_activity.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_schemas() -> BankResult<()> {
+        let mut db = Connection::open_in_memory()?;
+        db.pragma_update(None, "user_version", latest_version() + 1)?;
+        assert!(matches!(
+            migrate(&mut db, latest_version()),
+            Err(BankError::SchemaTooNew { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_migrations_roll_back() -> BankResult<()> {
+        let broken = [
+            Migration {
+                version: 1,
+                description: "works",
+                up: |db| db.execute_batch("CREATE TABLE a(x)"),
+            },
+            Migration {
+                version: 2,
+                description: "fails halfway",
+                up: |db| db.execute_batch("CREATE TABLE b(x); CREATE TABLE a(x)"),
+            },
+        ];
+        let mut db = Connection::open_in_memory()?;
+        assert!(migrate_with(&mut db, &broken, 2).is_err());
+        assert_eq!(current_version(&db)?, 1);
+        assert!(columns(&db, "b").is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+// SPDX-License-Identifier: Unlicense
+
+use super::{migrations, AccountFilter, Credentials, LedgerEntry, Posting, PostingKind, RecoveryCode, Storage};
+use crate::database::{Account, AccountStatus};
+use crate::error::{BankError, BankResult};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result as SqlResult, Row};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for another process holding the write lock
+pub(super) const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ACCOUNT_COLUMNS: &str =
+    "id, account_number, balance, pin, status, closed_at, last_activity_at";
+
+fn account_from_row(row: &Row<'_>) -> SqlResult<Account> {
+    Ok(Account {
+        id: row.get(0)?,
+        account_number: row.get(1)?,
+        balance: row.get(2)?,
+        pin_hash: row.get(3)?,
+        status: row.get(4)?,
+        closed_at: row.get(5)?,
+        last_activity_at: row.get(6)?,
+    })
+}
+
+fn ledger_entry(row: &Row<'_>) -> SqlResult<LedgerEntry> {
+    Ok(LedgerEntry {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        amount: row.get(2)?,
+        counterparty: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+impl FromSql for PostingKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+/// Connection to the database file at `path` for maintenance such as
+/// migrations and backups. The file has to exist, and its schema is left
+/// alone. Waits for other processes like `SqliteStorage` does.
+pub fn open_existing(path: &Path, read_only: bool) -> BankResult<Connection> {
+    let flags = if read_only {
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX
+    } else {
+        OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_CREATE)
+    };
+    let db = Connection::open_with_flags(path, flags)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(db)
+}
+
+/// SQLite database file, the default storage. Owns one connection for its
+/// whole lifetime, and every query goes through a cached prepared statement
+/// with bound parameters. Several processes can share the file: it is kept in
+/// WAL mode, so reads do not wait for writes, and units of work take the
+/// write lock up front with `BEGIN IMMEDIATE`.
+pub struct SqliteStorage {
+    db: Connection,
+}
+
+impl SqliteStorage {
+    /// Opening the database at `path`, creating or upgrading the tables as needed
+    pub fn open(path: impl AsRef<Path>) -> BankResult<Self> {
+        let db = Connection::open(path)?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        db.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        Self::with_connection(db)
+    }
+
+    /// Database that lives in memory and is gone once dropped
+    pub fn open_in_memory() -> BankResult<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    /// Bringing the schema up to date. Databases written by a newer
+    /// version of the bank are refused.
+    fn with_connection(mut db: Connection) -> BankResult<Self> {
+        migrations::migrate(&mut db, migrations::latest_version())?;
+        Ok(Self { db })
+    }
+
+    /// The underlying connection, for reports and maintenance
+    pub fn connection(&self) -> &Connection {
+        &self.db
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn begin(&mut self) -> BankResult<()> {
+        self.db.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> BankResult<()> {
+        self.db.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> BankResult<()> {
+        self.db.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn account(&self, account_number: &str) -> BankResult<Option<Account>> {
+        Ok(self
+            .db
+            .prepare_cached(&format!(
+                "SELECT {} FROM account WHERE account_number=?1",
+                ACCOUNT_COLUMNS
+            ))?
+            .query_row([account_number], account_from_row)
+            .optional()?)
+    }
+
+    fn insert_account(&mut self, account_number: &str, pin_hash: &str, balance: u64) -> BankResult<()> {
+        let inserted = self
+            .db
+            .prepare_cached(
+                "INSERT INTO account (account_number, pin, balance, last_activity_at)
+                VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP)",
+            )?
+            .execute((account_number, pin_hash, balance));
+        match inserted {
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                Err(BankError::AccountExists)
+            }
+            other => other.map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    fn set_pin_hash(&mut self, account_number: &str, pin_hash: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached("UPDATE account SET pin=?1 WHERE account_number=?2")?
+            .execute((pin_hash, account_number))?;
+        Ok(())
+    }
+
+    fn touch_activity(&mut self, account_number: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached(
+                "UPDATE account SET last_activity_at = CURRENT_TIMESTAMP WHERE account_number=?1",
+            )?
+            .execute([account_number])?;
+        Ok(())
+    }
+
+    fn change_status(
+        &mut self,
+        account_number: &str,
+        old_status: AccountStatus,
+        new_status: AccountStatus,
+        reason: &str,
+    ) -> BankResult<()> {
+        let tx = self.db.savepoint()?;
+        tx.prepare_cached("UPDATE account SET status=?1 WHERE account_number=?2")?
+            .execute((new_status, account_number))?;
+        tx.prepare_cached(
+            "INSERT INTO status_history (account_number, old_status, new_status, reason)
+            VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute((account_number, old_status, new_status, reason))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn accounts(&self, filter: &AccountFilter, after_id: u64, limit: usize) -> BankResult<Vec<Account>> {
+        Ok(self
+            .db
+            .prepare_cached(&format!(
+                "SELECT {} FROM account
+                WHERE id>?1 AND (?2 IS NULL OR status=?2)
+                    AND (?3 IS NULL OR balance>=?3) AND (?4 IS NULL OR balance<=?4)
+                ORDER BY id LIMIT ?5",
+                ACCOUNT_COLUMNS
+            ))?
+            .query_map(
+                (
+                    after_id as i64,
+                    filter.status,
+                    filter.min_balance.map(|min| min.min(i64::MAX as u64) as i64),
+                    filter.max_balance.map(|max| max.min(i64::MAX as u64) as i64),
+                    limit as i64,
+                ),
+                account_from_row,
+            )?
+            .collect::<SqlResult<_>>()?)
+    }
+
+    fn idle_accounts(&self, months: u32) -> BankResult<Vec<String>> {
+        Ok(self
+            .db
+            .prepare_cached(
+                "SELECT account_number FROM account
+                WHERE status = ?1 AND last_activity_at < datetime('now', ?2)",
+            )?
+            .query_map(
+                (AccountStatus::Active, format!("-{} months", months)),
+                |row| row.get(0),
+            )?
+            .collect::<SqlResult<_>>()?)
+    }
+
+    fn post(&mut self, postings: &[Posting<'_>]) -> BankResult<()> {
+        let tx = self.db.savepoint()?;
+        apply_postings(&tx, postings)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn close_account(&mut self, account_number: &str, payout: &[Posting<'_>]) -> BankResult<()> {
+        let tx = self.db.savepoint()?;
+        apply_postings(&tx, payout)?;
+        tx.prepare_cached(
+            "UPDATE account SET status=?1, closed_at=CURRENT_TIMESTAMP WHERE account_number=?2",
+        )?
+        .execute((AccountStatus::Closed, account_number))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn history(&self, account_number: &str, limit: usize) -> BankResult<Vec<LedgerEntry>> {
+        Ok(self
+            .db
+            .prepare_cached(
+                "SELECT id, kind, amount, counterparty, created_at FROM ledger
+                WHERE account_number=?1 ORDER BY id DESC LIMIT ?2",
+            )?
+            .query_map((account_number, limit as i64), ledger_entry)?
+            .collect::<SqlResult<_>>()?)
+    }
+
+    fn ledger_since(&self, account_number: &str, after_id: u64, limit: usize) -> BankResult<Vec<LedgerEntry>> {
+        Ok(self
+            .db
+            .prepare_cached(
+                "SELECT id, kind, amount, counterparty, created_at FROM ledger
+                WHERE account_number=?1 AND id>?2 ORDER BY id LIMIT ?3",
+            )?
+            .query_map((account_number, after_id as i64, limit as i64), ledger_entry)?
+            .collect::<SqlResult<_>>()?)
+    }
+
+    fn credentials(&self, account_number: &str) -> BankResult<Option<Credentials>> {
+        Ok(self
+            .db
+            .prepare_cached(
+                "SELECT pin, failed_attempts, COALESCE(locked_until > CURRENT_TIMESTAMP, 0),
+                    totp_secret, totp_enabled, totp_last_step
+                FROM account WHERE account_number=?1",
+            )?
+            .query_row([account_number], |row| {
+                Ok(Credentials {
+                    pin_hash: row.get(0)?,
+                    failed_attempts: row.get(1)?,
+                    locked: row.get(2)?,
+                    totp_secret: row.get(3)?,
+                    totp_enabled: row.get(4)?,
+                    totp_last_step: row.get(5)?,
+                })
+            })
+            .optional()?)
+    }
+
+    fn reset_failures(&mut self, account_number: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached(
+                "UPDATE account SET failed_attempts = 0, locked_until = NULL WHERE account_number=?1",
+            )?
+            .execute([account_number])?;
+        Ok(())
+    }
+
+    fn count_failure(&mut self, account_number: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached(
+                "UPDATE account SET failed_attempts = failed_attempts + 1 WHERE account_number=?1",
+            )?
+            .execute([account_number])?;
+        Ok(())
+    }
+
+    fn lock(&mut self, account_number: &str, minutes: u32) -> BankResult<()> {
+        let lock_until = if minutes == 0 {
+            "9999-12-31 23:59:59".to_string()
+        } else {
+            self.db
+                .prepare_cached("SELECT datetime('now', ?1)")?
+                .query_row([format!("+{} minutes", minutes)], |row| row.get(0))?
+        };
+        self.db
+            .prepare_cached(
+                "UPDATE account SET failed_attempts = 0, locked_until = ?1 WHERE account_number=?2",
+            )?
+            .execute((lock_until, account_number))?;
+        Ok(())
+    }
+
+    fn record_attempt(&mut self, account_number: &str, operation: &str, outcome: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached(
+                "INSERT INTO auth_attempts (account_number, operation, outcome) VALUES (?1, ?2, ?3)",
+            )?
+            .execute((account_number, operation, outcome))?;
+        Ok(())
+    }
+
+    fn set_totp_secret(&mut self, account_number: &str, secret: &str) -> BankResult<()> {
+        self.db
+            .prepare_cached(
+                "UPDATE account SET totp_secret=?1, totp_last_step=NULL WHERE account_number=?2",
+            )?
+            .execute((secret, account_number))?;
+        Ok(())
+    }
+
+    fn enable_totp(&mut self, account_number: &str, step: u64, recovery_code_hashes: &[String]) -> BankResult<()> {
+        let tx = self.db.savepoint()?;
+        tx.prepare_cached(
+            "UPDATE account SET totp_enabled=1, totp_last_step=?1 WHERE account_number=?2",
+        )?
+        .execute((step, account_number))?;
+        tx.prepare_cached("DELETE FROM recovery_codes WHERE account_number=?1")?
+            .execute([account_number])?;
+        for code_hash in recovery_code_hashes {
+            tx.prepare_cached("INSERT INTO recovery_codes (account_number, code_hash) VALUES (?1, ?2)")?
+                .execute((account_number, code_hash))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn disable_totp(&mut self, account_number: &str) -> BankResult<()> {
+        let tx = self.db.savepoint()?;
+        tx.prepare_cached(
+            "UPDATE account SET totp_secret=NULL, totp_enabled=0, totp_last_step=NULL
+            WHERE account_number=?1",
+        )?
+        .execute([account_number])?;
+        tx.prepare_cached("DELETE FROM recovery_codes WHERE account_number=?1")?
+            .execute([account_number])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn set_totp_last_step(&mut self, account_number: &str, step: u64) -> BankResult<()> {
+        self.db
+            .prepare_cached("UPDATE account SET totp_last_step=?1 WHERE account_number=?2")?
+            .execute((step, account_number))?;
+        Ok(())
+    }
+
+    fn unused_recovery_codes(&self, account_number: &str) -> BankResult<Vec<RecoveryCode>> {
+        Ok(self
+            .db
+            .prepare_cached(
+                "SELECT id, code_hash FROM recovery_codes WHERE account_number=?1 AND used_at IS NULL",
+            )?
+            .query_map([account_number], |row| {
+                Ok(RecoveryCode {
+                    id: row.get(0)?,
+                    code_hash: row.get(1)?,
+                })
+            })?
+            .collect::<SqlResult<_>>()?)
+    }
+
+    fn use_recovery_code(&mut self, id: u64) -> BankResult<()> {
+        self.db
+            .prepare_cached("UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE id=?1")?
+            .execute([id])?;
+        Ok(())
+    }
+}
+
+fn apply_postings(db: &Connection, postings: &[Posting<'_>]) -> SqlResult<()> {
+    for posting in postings {
+        let sql = if posting.kind.is_credit() {
+            "UPDATE account SET balance = balance + ?1 WHERE account_number=?2"
+        } else {
+            "UPDATE account SET balance = balance - ?1 WHERE account_number=?2"
+        };
+        db.prepare_cached(sql)?
+            .execute((posting.amount, posting.account_number))?;
+        db.prepare_cached(
+            "INSERT INTO ledger (account_number, kind, amount, counterparty) VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute((
+            posting.account_number,
+            posting.kind.as_str(),
+            posting.amount,
+            posting.counterparty,
+        ))?;
+    }
+    Ok(())
+}
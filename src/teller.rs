@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The operations of a teller, on the bank database itself or through the
+//! daemon that owns it, so commands and the menu run the same on either
+
+use crate::config::SESSION_LIFETIME;
+#[cfg(unix)]
+use crate::daemon::{self, ClientResult};
+use crate::database::{self, AccountStatus, Auth, Bank};
+use crate::error::{BankError, BankResult};
+use crate::storage::{LedgerEntry, Storage};
+use std::error::Error;
+
+/// What the account and admin subcommands and the menu need from the bank,
+/// which is either the database or the daemon that owns it
+pub trait Teller {
+    type Error: Error + Send + Sync + 'static;
+
+    /// New account with a fresh number, and the PIN if the bank chose it
+    fn open_account(&mut self, pin: Option<&str>) -> Result<(String, Option<String>), Self::Error>;
+    /// Logging in and getting a token that stands in for the PIN for
+    /// `SESSION_LIFETIME`
+    fn issue_token(&mut self, account: &str, pin: &str, code: Option<&str>) -> Result<String, Self::Error>;
+    fn needs_code(&mut self, account: &str, transfer: Option<u64>) -> Result<bool, Self::Error>;
+    fn check_balance<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> Result<u64, Self::Error>;
+    fn deposit<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> Result<u64, Self::Error>;
+    fn withdraw<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> Result<u64, Self::Error>;
+    fn transfer<'a>(
+        &mut self,
+        account: &str,
+        to: &str,
+        amount: u64,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> Result<u64, Self::Error>;
+    fn history<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<LedgerEntry>, Self::Error>;
+    fn close_account(&mut self, account: &str, payout: Option<&str>, pin: &str) -> Result<u64, Self::Error>;
+    fn change_pin(&mut self, account: &str, old_pin: &str, new_pin: &str) -> Result<(), Self::Error>;
+    fn begin_totp_enrollment(&mut self, account: &str, pin: &str) -> Result<String, Self::Error>;
+    fn confirm_totp_enrollment(&mut self, account: &str, code: &str) -> Result<Vec<String>, Self::Error>;
+    fn disable_totp(&mut self, account: &str, pin: &str, code: &str) -> Result<(), Self::Error>;
+    fn set_status(&mut self, account: &str, status: AccountStatus, reason: &str) -> Result<AccountStatus, Self::Error>;
+    fn unlock_account(&mut self, account: &str) -> Result<(), Self::Error>;
+    fn mark_dormant(&mut self, months: u32) -> Result<usize, Self::Error>;
+}
+
+impl<S: Storage> Teller for Bank<S> {
+    type Error = BankError;
+
+    fn open_account(&mut self, pin: Option<&str>) -> BankResult<(String, Option<String>)> {
+        match pin {
+            Some(pin) => {
+                let (number, ()) = database::with_fresh_number(|number| self.create_account_with_pin(number, 0, pin))?;
+                Ok((number.to_string(), None))
+            }
+            None => {
+                let (number, pin) = database::with_fresh_number(|number| self.create_account(number, 0))?;
+                Ok((number.to_string(), Some(pin)))
+            }
+        }
+    }
+
+    fn issue_token(&mut self, account: &str, pin: &str, code: Option<&str>) -> BankResult<String> {
+        Bank::issue_token(self, account, pin, code, SESSION_LIFETIME)
+    }
+
+    fn needs_code(&mut self, account: &str, transfer: Option<u64>) -> BankResult<bool> {
+        Bank::needs_code(self, account, transfer)
+    }
+
+    fn check_balance<'a>(&mut self, account: &str, auth: impl Into<Auth<'a>>, code: Option<&str>) -> BankResult<u64> {
+        Bank::check_balance(self, account, auth, code)
+    }
+
+    fn deposit<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> BankResult<u64> {
+        Bank::deposit(self, account, amount, auth)
+    }
+
+    fn withdraw<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> BankResult<u64> {
+        Bank::withdraw(self, account, amount, auth)
+    }
+
+    fn transfer<'a>(
+        &mut self,
+        account: &str,
+        to: &str,
+        amount: u64,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> BankResult<u64> {
+        Bank::transfer(self, account, to, amount, auth, code)
+    }
+
+    fn history<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+        limit: usize,
+    ) -> BankResult<Vec<LedgerEntry>> {
+        Bank::history(self, account, auth, code, limit)
+    }
+
+    fn close_account(&mut self, account: &str, payout: Option<&str>, pin: &str) -> BankResult<u64> {
+        Bank::close_account(self, account, payout, pin)
+    }
+
+    fn change_pin(&mut self, account: &str, old_pin: &str, new_pin: &str) -> BankResult<()> {
+        Bank::change_pin(self, account, old_pin, new_pin)
+    }
+
+    fn begin_totp_enrollment(&mut self, account: &str, pin: &str) -> BankResult<String> {
+        Bank::begin_totp_enrollment(self, account, pin)
+    }
+
+    fn confirm_totp_enrollment(&mut self, account: &str, code: &str) -> BankResult<Vec<String>> {
+        Bank::confirm_totp_enrollment(self, account, code)
+    }
+
+    fn disable_totp(&mut self, account: &str, pin: &str, code: &str) -> BankResult<()> {
+        Bank::disable_totp(self, account, pin, code)
+    }
+
+    fn set_status(&mut self, account: &str, status: AccountStatus, reason: &str) -> BankResult<AccountStatus> {
+        Bank::set_status(self, account, status, reason)
+    }
+
+    fn unlock_account(&mut self, account: &str) -> BankResult<()> {
+        Bank::unlock_account(self, account)
+    }
+
+    fn mark_dormant(&mut self, months: u32) -> BankResult<usize> {
+        Bank::mark_dormant(self, months)
+    }
+}
+
+#[cfg(unix)]
+impl Teller for daemon::Client {
+    type Error = daemon::ClientError;
+
+    fn open_account(&mut self, pin: Option<&str>) -> ClientResult<(String, Option<String>)> {
+        let created = self.create_account(pin)?;
+        Ok((created.account, created.pin))
+    }
+
+    fn issue_token(&mut self, account: &str, pin: &str, code: Option<&str>) -> ClientResult<String> {
+        daemon::Client::issue_token(self, account, pin, code)
+    }
+
+    fn needs_code(&mut self, account: &str, transfer: Option<u64>) -> ClientResult<bool> {
+        daemon::Client::needs_code(self, account, transfer)
+    }
+
+    fn check_balance<'a>(&mut self, account: &str, auth: impl Into<Auth<'a>>, code: Option<&str>) -> ClientResult<u64> {
+        daemon::Client::check_balance(self, account, auth, code)
+    }
+
+    fn deposit<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> ClientResult<u64> {
+        daemon::Client::deposit(self, account, amount, auth)
+    }
+
+    fn withdraw<'a>(&mut self, account: &str, amount: u64, auth: impl Into<Auth<'a>>) -> ClientResult<u64> {
+        daemon::Client::withdraw(self, account, amount, auth)
+    }
+
+    fn transfer<'a>(
+        &mut self,
+        account: &str,
+        to: &str,
+        amount: u64,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+    ) -> ClientResult<u64> {
+        daemon::Client::transfer(self, account, to, amount, auth, code)
+    }
+
+    fn history<'a>(
+        &mut self,
+        account: &str,
+        auth: impl Into<Auth<'a>>,
+        code: Option<&str>,
+        limit: usize,
+    ) -> ClientResult<Vec<LedgerEntry>> {
+        daemon::Client::history(self, account, auth, code, limit)
+    }
+
+    fn close_account(&mut self, account: &str, payout: Option<&str>, pin: &str) -> ClientResult<u64> {
+        daemon::Client::close_account(self, account, payout, pin)
+    }
+
+    fn change_pin(&mut self, account: &str, old_pin: &str, new_pin: &str) -> ClientResult<()> {
+        daemon::Client::change_pin(self, account, old_pin, new_pin)
+    }
+
+    fn begin_totp_enrollment(&mut self, account: &str, pin: &str) -> ClientResult<String> {
+        daemon::Client::begin_totp_enrollment(self, account, pin)
+    }
+
+    fn confirm_totp_enrollment(&mut self, account: &str, code: &str) -> ClientResult<Vec<String>> {
+        daemon::Client::confirm_totp_enrollment(self, account, code)
+    }
+
+    fn disable_totp(&mut self, account: &str, pin: &str, code: &str) -> ClientResult<()> {
+        daemon::Client::disable_totp(self, account, pin, code)
+    }
+
+    fn set_status(&mut self, account: &str, status: AccountStatus, reason: &str) -> ClientResult<AccountStatus> {
+        daemon::Client::set_status(self, account, status, reason)
+    }
+
+    fn unlock_account(&mut self, account: &str) -> ClientResult<()> {
+        daemon::Client::unlock_account(self, account)
+    }
+
+    fn mark_dormant(&mut self, months: u32) -> ClientResult<usize> {
+        daemon::Client::mark_dormant(self, months)
+    }
+}
+
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Bearer tokens for an account, handed out after a login. A token is
+//! `<account>.<expiry>.<mac>`, where the MAC covers the account, the expiry
+//! and the stored PIN hash, so changing the PIN revokes every token issued
+//! before. Nothing is stored; the key only lives as long as the bank.
+
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Secret the tokens of one bank are signed with
+#[derive(Clone)]
+pub struct TokenKey([u8; 32]);
+
+impl TokenKey {
+    /// Fresh random key. Tokens signed with another key are refused.
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    fn mac(&self, account_number: &str, expires_at: u64, pin_hash: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        for part in [account_number.as_bytes(), &expires_at.to_be_bytes(), pin_hash.as_bytes()] {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Token for `account_number`, valid until the Unix time `expires_at`
+    pub fn issue(&self, account_number: &str, expires_at: u64, pin_hash: &str) -> String {
+        format!(
+            "{}.{}.{}",
+            account_number,
+            expires_at,
+            self.mac(account_number, expires_at, pin_hash)
+        )
+    }
+
+    /// Checking that `token` was issued for `account_number` with its current
+    /// `pin_hash` and has not expired at `unix_time`
+    pub fn verify(&self, token: &str, account_number: &str, pin_hash: &str, unix_time: u64) -> bool {
+        let mut parts = token.splitn(3, '.');
+        let (Some(account), Some(expires_at), Some(mac)) = (parts.next(), parts.next(), parts.next()) else {
+            return false;
+        };
+        let Ok(expires_at) = expires_at.parse::<u64>() else {
+            return false;
+        };
+        let expected = self.mac(account_number, expires_at, pin_hash);
+        account == account_number && unix_time < expires_at && bool::from(expected.as_bytes().ct_eq(mac.as_bytes()))
+    }
+}
+
+impl std::fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_expire_and_follow_the_pin() {
+        let key = TokenKey::generate();
+        let token = key.issue("8536276945", 1000, "hash");
+        assert!(key.verify(&token, "8536276945", "hash", 999));
+
+        assert!(!key.verify(&token, "8536276945", "hash", 1000));
+        assert!(!key.verify(&token, "8536276945", "new hash", 999));
+        assert!(!key.verify(&token, "35001576202", "hash", 999));
+        assert!(!TokenKey::generate().verify(&token, "8536276945", "hash", 999));
+
+        let longer = token.replacen(".1000.", ".2000.", 1);
+        assert!(!key.verify(&longer, "8536276945", "hash", 999));
+        assert!(!key.verify("8536276945", "8536276945", "hash", 999));
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: Unlicense
+
+use hmac::{Hmac, Mac};
+use rand::prelude::*;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+/// Length of a time step in seconds (RFC 6238 `X`)
+pub const STEP: u64 = 30;
+/// Digits of the codes shown by authenticator apps
+pub const DIGITS: u32 = 6;
+/// Steps before and after the current one that are still accepted,
+/// to allow for clock drift between the bank and the phone
+pub const DRIFT: u64 = 1;
+
+/// HMAC hash function used to derive the codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// One-time password for a counter, RFC 4226 section 5.3
+pub fn hotp(algorithm: Algorithm, secret: &[u8], counter: u64, digits: u32) -> String {
+    let message = counter.to_be_bytes();
+    let hash = match algorithm {
+        Algorithm::Sha1 => hmac::<Hmac<Sha1>>(secret, &message),
+        Algorithm::Sha256 => hmac::<Hmac<Sha256>>(secret, &message),
+        Algorithm::Sha512 => hmac::<Hmac<Sha512>>(secret, &message),
+    };
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary as u64 % 10_u64.pow(digits),
+        width = digits as usize
+    )
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Time step a Unix timestamp falls into
+pub fn step_at(unix_time: u64) -> u64 {
+    unix_time / STEP
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("The clock is set before 1970")
+        .as_secs()
+}
+
+/// Checking a code typed by the customer against the steps around
+/// `unix_time`. Returns the matching step, so callers can refuse a code
+/// that was already used.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let current = step_at(unix_time);
+    (current.saturating_sub(DRIFT)..=current + DRIFT).find(|step| {
+        let expected = hotp(Algorithm::Sha1, secret, *step, DIGITS);
+        bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes()))
+    })
+}
+
+/// Fresh random secret of 160 bits, the size RFC 4226 recommends
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// `otpauth://` URI that authenticator apps read, usually from a QR code
+pub fn provisioning_uri(secret: &[u8], account_number: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = issuer,
+        account = account_number,
+        secret = base32_encode(secret),
+        digits = DIGITS,
+        period = STEP,
+    )
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, as used in provisioning URIs
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut bit_count = 0;
+    let mut decoded = Vec::new();
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// One-time recovery codes like `k7m2p-x9c4t`, for customers who lost their phone
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = thread_rng();
+    (0..count)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED_SHA1: &[u8] = b"12345678901234567890";
+    const SEED_SHA256: &[u8] = b"12345678901234567890123456789012";
+    const SEED_SHA512: &[u8] =
+        b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    #[test]
+    fn rfc4226_hotp_vectors() {
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(Algorithm::Sha1, SEED_SHA1, counter as u64, 6), *code);
+        }
+    }
+
+    #[test]
+    fn rfc6238_totp_vectors() {
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        for (time, sha1, sha256, sha512) in vectors {
+            let step = step_at(time);
+            assert_eq!(hotp(Algorithm::Sha1, SEED_SHA1, step, 8), sha1);
+            assert_eq!(hotp(Algorithm::Sha256, SEED_SHA256, step, 8), sha256);
+            assert_eq!(hotp(Algorithm::Sha512, SEED_SHA512, step, 8), sha512);
+        }
+    }
+
+    #[test]
+    fn codes_within_drift_window_verify() {
+        let time = 1234567890;
+        let code = hotp(Algorithm::Sha1, SEED_SHA1, step_at(time), DIGITS);
+        assert_eq!(verify(SEED_SHA1, &code, time), Some(step_at(time)));
+        assert!(verify(SEED_SHA1, &code, time + STEP).is_some());
+        assert!(verify(SEED_SHA1, &code, time - STEP).is_some());
+        assert_eq!(verify(SEED_SHA1, &code, time + 2 * STEP), None);
+    }
+
+    #[test]
+    fn rfc4648_base32_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(base32_encode(plain.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn provisioning_uri_carries_secret() {
+        let uri = provisioning_uri(SEED_SHA1, "2334841596", "Bank");
+        assert!(uri.starts_with("otpauth://totp/Bank:2334841596?"));
+        assert!(uri.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+    }
+}
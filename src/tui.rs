@@ -0,0 +1,704 @@
+// SPDX-License-Identifier: Unlicense
+
+//! Full-screen front end for branch staff. Several customers' accounts can be
+//! logged in at once; the selected one shows its balance and a scrollable
+//! history, and deposits, withdrawals and transfers are filled in as forms
+//! that check account numbers and amounts while they are typed.
+
+use crate::config::SESSION_LIFETIME;
+use crate::database::{AccountStatus, Auth, Bank};
+use crate::error::{BankError, BankResult};
+use crate::luhn;
+use crate::storage::{LedgerEntry, PostingKind, Storage};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Cell, Clear, List, ListItem, ListState, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Postings loaded for the history of the selected account
+const HISTORY_LEN: usize = 500;
+/// How often the session timeout is checked while no key is pressed
+const TICK: Duration = Duration::from_millis(500);
+
+/// Checking a typed account number: digits only, with a matching check digit
+fn check_account_number(input: &str) -> Result<(), &'static str> {
+    if input.is_empty() {
+        Err("Type the account number")
+    } else if !input.chars().all(|c| c.is_ascii_digit()) {
+        Err("Digits only")
+    } else if input.len() > luhn::MAX_LENGTH {
+        Err("Too many digits")
+    } else if !luhn::verify(input) {
+        Err("The check digit does not match")
+    } else {
+        Ok(())
+    }
+}
+
+/// Parsing a typed amount of money
+fn parse_amount(input: &str) -> Result<u64, &'static str> {
+    if input.is_empty() {
+        return Err("Type the amount");
+    }
+    if !input.chars().all(|c| c.is_ascii_digit()) {
+        return Err("Digits only");
+    }
+    match input.parse() {
+        Ok(0) => Err("The amount has to be above zero"),
+        Ok(amount) => Ok(amount),
+        Err(_) => Err("The amount is too large"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    AccountNumber,
+    Amount,
+    Pin,
+    /// Authenticator or recovery code, left empty when none is needed
+    Code,
+}
+
+#[derive(Debug, Clone)]
+struct Field {
+    label: &'static str,
+    kind: FieldKind,
+    value: String,
+}
+
+impl Field {
+    fn new(label: &'static str, kind: FieldKind) -> Self {
+        Self {
+            label,
+            kind,
+            value: String::new(),
+        }
+    }
+
+    /// What is wrong with the value, or what it will be taken as
+    fn check(&self) -> Result<String, &'static str> {
+        match self.kind {
+            FieldKind::AccountNumber => check_account_number(&self.value).map(|()| "Valid account number".to_string()),
+            FieldKind::Amount => parse_amount(&self.value).map(|amount| format!("Amount {}", amount)),
+            FieldKind::Pin if self.value.is_empty() => Err("Type the PIN"),
+            FieldKind::Pin => Ok(String::new()),
+            FieldKind::Code if self.value.is_empty() => Ok("Only for accounts with an authenticator".to_string()),
+            FieldKind::Code => Ok(String::new()),
+        }
+    }
+
+    /// The value as shown, with PINs and codes hidden
+    fn shown(&self) -> String {
+        match self.kind {
+            FieldKind::Pin | FieldKind::Code => "*".repeat(self.value.chars().count()),
+            _ => self.value.clone(),
+        }
+    }
+
+    fn accepts(&self, c: char) -> bool {
+        match self.kind {
+            // Recovery codes have letters and dashes
+            FieldKind::Code => c.is_ascii_alphanumeric() || c == '-',
+            _ => c.is_ascii_digit(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FormKind {
+    Login,
+    Deposit,
+    Withdraw,
+    Transfer,
+}
+
+/// A form being filled in, one field focused at a time
+#[derive(Debug, Clone)]
+struct Form {
+    kind: FormKind,
+    fields: Vec<Field>,
+    focus: usize,
+}
+
+impl Form {
+    fn new(kind: FormKind) -> Self {
+        let fields = match kind {
+            FormKind::Login => vec![
+                Field::new("Account number", FieldKind::AccountNumber),
+                Field::new("PIN", FieldKind::Pin),
+                Field::new("Code", FieldKind::Code),
+            ],
+            FormKind::Deposit => vec![Field::new("Amount", FieldKind::Amount)],
+            FormKind::Withdraw => vec![
+                Field::new("Amount", FieldKind::Amount),
+                Field::new("PIN", FieldKind::Pin),
+            ],
+            FormKind::Transfer => vec![
+                Field::new("Amount", FieldKind::Amount),
+                Field::new("Recipient", FieldKind::AccountNumber),
+                Field::new("PIN", FieldKind::Pin),
+                Field::new("Code", FieldKind::Code),
+            ],
+        };
+        Self { kind, fields, focus: 0 }
+    }
+
+    fn title(&self) -> &'static str {
+        match self.kind {
+            FormKind::Login => " Log in ",
+            FormKind::Deposit => " Deposit ",
+            FormKind::Withdraw => " Withdraw ",
+            FormKind::Transfer => " Transfer ",
+        }
+    }
+
+    fn value(&self, kind: FieldKind) -> &str {
+        self.fields
+            .iter()
+            .find(|f| f.kind == kind)
+            .map_or("", |f| f.value.as_str())
+    }
+
+    /// The first problem of the form, if any
+    fn problem(&self) -> Option<&'static str> {
+        self.fields.iter().find_map(|f| f.check().err())
+    }
+}
+
+/// A logged in account. The token from the login stands in for the PIN
+/// for deposits, like in the menu; withdrawals and transfers ask for it again.
+#[derive(Debug, Clone)]
+struct Session {
+    account_number: String,
+    token: String,
+    balance: u64,
+    status: AccountStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    text: String,
+    error: bool,
+}
+
+/// State of the screen, changed by keys and drawn on every frame
+struct App {
+    sessions: Vec<Session>,
+    selected: usize,
+    history: Vec<LedgerEntry>,
+    /// Index of the topmost history line shown
+    scroll: usize,
+    form: Option<Form>,
+    message: Option<Message>,
+    last_input: Instant,
+    timeout: Duration,
+    quit: bool,
+}
+
+impl App {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            sessions: Vec::new(),
+            selected: 0,
+            history: Vec::new(),
+            scroll: 0,
+            form: Some(Form::new(FormKind::Login)),
+            message: None,
+            last_input: Instant::now(),
+            timeout,
+            quit: false,
+        }
+    }
+
+    fn session(&self) -> Option<&Session> {
+        self.sessions.get(self.selected)
+    }
+
+    fn tell(&mut self, text: impl Into<String>, error: bool) {
+        self.message = Some(Message {
+            text: text.into(),
+            error,
+        });
+    }
+
+    /// Showing why an operation was refused. Database failures end the app.
+    fn report<T>(&mut self, result: BankResult<T>) -> io::Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(BankError::Storage(e)) => Err(io::Error::other(e)),
+            Err(e) => {
+                self.tell(e.to_string(), true);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reloading balances, statuses and the history of the selected account
+    fn refresh<S: Storage>(&mut self, bank: &Bank<S>) -> io::Result<()> {
+        for i in 0..self.sessions.len() {
+            let account = bank.fetch_account(&self.sessions[i].account_number);
+            if let Some(account) = self.report(account)? {
+                self.sessions[i].balance = account.balance;
+                self.sessions[i].status = account.status;
+            }
+        }
+        let history = match self.session() {
+            Some(session) => bank.storage().history(&session.account_number, HISTORY_LEN),
+            None => Ok(Vec::new()),
+        };
+        self.history = self.report(history)?.unwrap_or_default();
+        self.scroll = self.scroll.min(self.history.len().saturating_sub(1));
+        Ok(())
+    }
+
+    /// Logging every account out after inactivity, like the menu does
+    fn tick(&mut self) {
+        if self.last_input.elapsed() > self.timeout && !self.sessions.is_empty() {
+            self.sessions.clear();
+            self.history.clear();
+            self.selected = 0;
+            self.form = Some(Form::new(FormKind::Login));
+            self.tell("Session timed out after inactivity. Please log in again...", true);
+        }
+    }
+
+    fn on_key<S: Storage>(&mut self, bank: &mut Bank<S>, key: KeyEvent) -> io::Result<()> {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.quit = true;
+            return Ok(());
+        }
+        self.tick();
+        self.last_input = Instant::now();
+
+        if self.form.is_some() {
+            return self.on_form_key(bank, key);
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Up if self.selected > 0 => {
+                self.selected -= 1;
+                self.scroll = 0;
+                self.refresh(bank)?;
+            }
+            KeyCode::Down if self.selected + 1 < self.sessions.len() => {
+                self.selected += 1;
+                self.scroll = 0;
+                self.refresh(bank)?;
+            }
+            KeyCode::Char('k') => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::Char('j') => self.scroll_by(1),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::PageDown => self.scroll_by(10),
+            KeyCode::Char('d') => self.form = Some(Form::new(FormKind::Deposit)),
+            KeyCode::Char('w') => self.form = Some(Form::new(FormKind::Withdraw)),
+            KeyCode::Char('t') => self.form = Some(Form::new(FormKind::Transfer)),
+            KeyCode::Char('l') => self.form = Some(Form::new(FormKind::Login)),
+            KeyCode::Char('x') => {
+                if let Some(session) = self.session() {
+                    let account_number = session.account_number.clone();
+                    self.sessions.remove(self.selected);
+                    self.selected = self.selected.min(self.sessions.len().saturating_sub(1));
+                    self.tell(format!("Logged out of `{}`.", account_number), false);
+                    if self.sessions.is_empty() {
+                        self.form = Some(Form::new(FormKind::Login));
+                    }
+                    self.refresh(bank)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn scroll_by(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.history.len().saturating_sub(1));
+    }
+
+    fn on_form_key<S: Storage>(&mut self, bank: &mut Bank<S>, key: KeyEvent) -> io::Result<()> {
+        let Some(form) = self.form.as_mut() else {
+            return Ok(());
+        };
+        match key.code {
+            KeyCode::Esc if form.kind == FormKind::Login && self.sessions.is_empty() => self.quit = true,
+            KeyCode::Esc => self.form = None,
+            KeyCode::Tab | KeyCode::Down => form.focus = (form.focus + 1) % form.fields.len(),
+            KeyCode::BackTab | KeyCode::Up => form.focus = (form.focus + form.fields.len() - 1) % form.fields.len(),
+            KeyCode::Backspace => {
+                form.fields[form.focus].value.pop();
+            }
+            KeyCode::Char(c) => {
+                let field = &mut form.fields[form.focus];
+                if field.accepts(c) {
+                    field.value.push(c);
+                }
+            }
+            KeyCode::Enter if form.focus + 1 < form.fields.len() => form.focus += 1,
+            KeyCode::Enter => match form.problem() {
+                Some(problem) => self.tell(problem, true),
+                None => {
+                    let form = form.clone();
+                    self.submit(bank, &form)?;
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Carrying out a filled in form. The form stays open when the bank refuses.
+    fn submit<S: Storage>(&mut self, bank: &mut Bank<S>, form: &Form) -> io::Result<()> {
+        let code = Some(form.value(FieldKind::Code)).filter(|c| !c.is_empty());
+        let amount = parse_amount(form.value(FieldKind::Amount)).unwrap_or_default();
+
+        let done = if form.kind == FormKind::Login {
+            let account_number = form.value(FieldKind::AccountNumber);
+            let pin = form.value(FieldKind::Pin);
+            let login = bank.issue_token(account_number, pin, code, SESSION_LIFETIME);
+            let token = self.report(login)?;
+            let done = token.is_some();
+            if let Some(token) = token {
+                let session = Session {
+                    account_number: account_number.to_string(),
+                    token,
+                    balance: 0,
+                    status: AccountStatus::Active,
+                };
+                match self.sessions.iter().position(|s| s.account_number == account_number) {
+                    Some(i) => {
+                        self.sessions[i] = session;
+                        self.selected = i;
+                    }
+                    None => {
+                        self.sessions.push(session);
+                        self.selected = self.sessions.len() - 1;
+                    }
+                }
+                self.scroll = 0;
+                self.tell(format!("Logged into `{}`.", account_number), false);
+            }
+            done
+        } else {
+            let Some(session) = self.session().cloned() else {
+                return Ok(());
+            };
+            let account_number = session.account_number.as_str();
+            let pin = form.value(FieldKind::Pin);
+            let (result, verb) = match form.kind {
+                FormKind::Deposit => (
+                    bank.deposit(account_number, amount, Auth::Token(&session.token)),
+                    "Deposited",
+                ),
+                FormKind::Withdraw => (bank.withdraw(account_number, amount, pin), "Withdrew"),
+                _ => {
+                    let to = form.value(FieldKind::AccountNumber);
+                    (bank.transfer(account_number, to, amount, pin, code), "Transferred")
+                }
+            };
+            let done = self.report(result)?.is_some();
+            if done {
+                self.tell(format!("{} `{}`.", verb, amount), false);
+            }
+            done
+        };
+
+        if done {
+            self.form = None;
+        } else if let Some(open) = self.form.as_mut() {
+            // PINs and codes are typed again after a refusal
+            for field in &mut open.fields {
+                if matches!(field.kind, FieldKind::Pin | FieldKind::Code) {
+                    field.value.clear();
+                }
+            }
+            if let Some(pin) = open.fields.iter().position(|f| f.kind == FieldKind::Pin) {
+                open.focus = pin;
+            }
+        }
+        self.refresh(bank)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, message, help] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+        let [accounts, details] = Layout::horizontal([Constraint::Length(24), Constraint::Min(0)]).areas(main);
+
+        self.draw_accounts(frame, accounts);
+        self.draw_details(frame, details);
+        if let Some(Message { text, error }) = &self.message {
+            let color = if *error { Color::Red } else { Color::Green };
+            frame.render_widget(Paragraph::new(text.as_str()).fg(color), message);
+        }
+        let keys = if self.form.is_some() {
+            "Tab next field · Enter confirm · Esc cancel"
+        } else {
+            "↑↓ account · j/k PgUp/PgDn history · d deposit · w withdraw · t transfer · l log in · x log out · q quit"
+        };
+        frame.render_widget(Paragraph::new(keys).add_modifier(Modifier::DIM), help);
+
+        if let Some(form) = &self.form {
+            self.draw_form(frame, form);
+        }
+    }
+
+    fn draw_accounts(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .sessions
+            .iter()
+            .map(|s| ListItem::new(vec![Line::from(s.account_number.as_str()), Line::from(format!("  {}", s.balance)).dim()]))
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(" Accounts "))
+            .highlight_style(Style::new().reversed());
+        let mut state = ListState::default().with_selected(self.session().map(|_| self.selected));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let [summary, history] = Layout::vertical([Constraint::Length(4), Constraint::Min(0)]).areas(area);
+        let Some(session) = self.session() else {
+            frame.render_widget(Block::bordered(), summary);
+            frame.render_widget(Block::bordered().title(" History "), history);
+            return;
+        };
+
+        let status = match session.status {
+            AccountStatus::Active => Span::from(session.status.as_str()).green(),
+            _ => Span::from(session.status.as_str()).red(),
+        };
+        let lines = vec![
+            Line::from(vec![Span::from("Balance  "), Span::from(session.balance.to_string()).bold()]),
+            Line::from(vec![Span::from("Status   "), status]),
+        ];
+        let title = format!(" {} ", session.account_number);
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), summary);
+
+        let rows = self.history.iter().skip(self.scroll).map(|entry| {
+            let (sign, color) = if entry.kind.is_credit() { ('+', Color::Green) } else { ('-', Color::Red) };
+            let counterparty = match (entry.kind, &entry.counterparty) {
+                (PostingKind::TransferIn, Some(from)) => format!("from {}", from),
+                (_, Some(to)) => format!("to {}", to),
+                (_, None) => String::new(),
+            };
+            Row::new(vec![
+                Cell::from(entry.created_at.as_str()),
+                Cell::from(entry.kind.as_str()),
+                Cell::from(Line::from(format!("{}{}", sign, entry.amount)).right_aligned().fg(color)),
+                Cell::from(counterparty),
+            ])
+        });
+        let widths = [Constraint::Length(19), Constraint::Length(12), Constraint::Length(14), Constraint::Min(0)];
+        let title = format!(" History {}/{} ", (self.scroll + 1).min(self.history.len()), self.history.len());
+        let table = Table::new(rows, widths)
+            .header(Row::new(["Date", "Kind", "Amount", "Counterparty"]).bold())
+            .block(Block::bordered().title(title));
+        frame.render_widget(table, history);
+    }
+
+    fn draw_form(&self, frame: &mut Frame, form: &Form) {
+        let area = centered(frame.area(), 56, form.fields.len() as u16 * 2 + 2);
+        let mut lines = Vec::new();
+        let mut cursor = None;
+        for (i, field) in form.fields.iter().enumerate() {
+            let focused = i == form.focus;
+            let label = format!("{:<16}", field.label);
+            let label = if focused { Span::from(label).bold() } else { Span::from(label) };
+            let shown = field.shown();
+            if focused {
+                let x = area.x + 1 + 16 + shown.chars().count() as u16;
+                cursor = Some(Position::new(x, area.y + 1 + lines.len() as u16));
+            }
+            lines.push(Line::from(vec![label, Span::from(shown)]));
+            let hint = match field.check() {
+                Ok(hint) => Span::from(hint).dim(),
+                // Nothing typed yet is not worth a red warning
+                Err(problem) if field.value.is_empty() => Span::from(problem).dim(),
+                Err(problem) => Span::from(problem).red(),
+            };
+            lines.push(Line::from(vec![Span::from(" ".repeat(16)), hint]));
+        }
+
+        frame.render_widget(Clear, area);
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(form.title())), area);
+        if let Some(cursor) = cursor {
+            frame.set_cursor_position(cursor);
+        }
+    }
+
+    fn event_loop<S: Storage>(&mut self, bank: &mut Bank<S>, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(TICK)? {
+                self.tick();
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.on_key(bank, key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Area of `width` by `height` in the middle of `area`, as far as it fits
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Running the full-screen front end until it is quit
+pub fn run<S: Storage>(bank: &mut Bank<S>) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = App::new(bank.settings().session_timeout()).event_loop(bank, &mut terminal);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::luhn::AccountNumber;
+    use crate::storage::MemoryStorage;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::str::FromStr;
+
+    const ACCOUNT: &str = "8536276945";
+    const OTHER: &str = "35001576202";
+    const PIN: &str = "591732";
+
+    fn open_bank() -> BankResult<Bank<MemoryStorage>> {
+        let mut bank = Bank::in_memory();
+        for number in [ACCOUNT, OTHER] {
+            bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)?;
+        }
+        Ok(bank)
+    }
+
+    fn press(app: &mut App, bank: &mut Bank<MemoryStorage>, code: KeyCode) {
+        app.on_key(bank, KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
+    }
+
+    /// Typing `keys`, where `\t` moves to the next field and `\n` confirms
+    fn type_keys(app: &mut App, bank: &mut Bank<MemoryStorage>, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\t' => KeyCode::Tab,
+                '\n' => KeyCode::Enter,
+                c => KeyCode::Char(c),
+            };
+            press(app, bank, code);
+        }
+    }
+
+    fn screen(app: &App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn typed_input_is_checked() {
+        assert_eq!(check_account_number(""), Err("Type the account number"));
+        assert_eq!(check_account_number("853627694"), Err("The check digit does not match"));
+        assert_eq!(check_account_number(ACCOUNT), Ok(()));
+        assert_eq!(check_account_number(&"9".repeat(29)), Err("Too many digits"));
+        for _ in 0..1000 {
+            let generated = AccountNumber::default().to_string();
+            assert_eq!(check_account_number(&generated), Ok(()), "{generated}");
+        }
+        assert_eq!(parse_amount("150"), Ok(150));
+        assert_eq!(parse_amount("0"), Err("The amount has to be above zero"));
+        assert_eq!(parse_amount("99999999999999999999"), Err("The amount is too large"));
+
+        let mut field = Field::new("Recipient", FieldKind::AccountNumber);
+        field.value = "8536276946".to_string();
+        assert_eq!(field.check(), Err("The check digit does not match"));
+        assert!(!field.accepts('a'));
+        let pin = Field {
+            value: PIN.to_string(),
+            ..Field::new("PIN", FieldKind::Pin)
+        };
+        assert_eq!(pin.shown(), "******");
+    }
+
+    #[test]
+    fn forms_move_money() -> BankResult<()> {
+        let mut bank = open_bank()?;
+        let mut app = App::new(Duration::from_secs(60));
+
+        type_keys(&mut app, &mut bank, &format!("{}\t{}\n\n", ACCOUNT, PIN));
+        assert!(app.form.is_none());
+        assert!(screen(&app).contains("Balance  100"));
+
+        type_keys(&mut app, &mut bank, "d50\n");
+        type_keys(&mut app, &mut bank, "w20\t000000\n");
+        assert_eq!(app.form.as_ref().unwrap().focus, 1);
+        type_keys(&mut app, &mut bank, &format!("{}\n", PIN));
+        assert_eq!(bank.balance(ACCOUNT)?, 130);
+
+        // A wrong recipient is caught before the bank is asked
+        type_keys(&mut app, &mut bank, "t30\t85362769\n");
+        assert_eq!(app.form.as_ref().unwrap().focus, 2);
+        type_keys(&mut app, &mut bank, "\t\n");
+        assert_eq!(app.message.as_ref().unwrap().text, "The check digit does not match");
+        assert_eq!(bank.balance(ACCOUNT)?, 130);
+
+        let form = app.form.as_mut().unwrap();
+        form.fields[1].value.clear();
+        form.focus = 1;
+        type_keys(&mut app, &mut bank, &format!("{}\t000000\t\n", OTHER));
+        assert!(matches!(app.message, Some(Message { error: true, .. })));
+        type_keys(&mut app, &mut bank, &format!("{}\t\n", PIN));
+        assert!(app.form.is_none());
+        assert_eq!(bank.balance(OTHER)?, 130);
+
+        let shown = screen(&app);
+        assert!(shown.contains("Balance  100"));
+        assert!(shown.contains("transfer_out"));
+        assert!(shown.contains(&format!("to {}", OTHER)));
+        assert!(shown.contains("History 1/3"));
+        Ok(())
+    }
+
+    #[test]
+    fn sessions_end() -> BankResult<()> {
+        let mut bank = open_bank()?;
+        let mut app = App::new(Duration::from_secs(60));
+        for account in [ACCOUNT, OTHER] {
+            type_keys(&mut app, &mut bank, &format!("{}\t{}\n\n", account, PIN));
+            press(&mut app, &mut bank, KeyCode::Char('l'));
+        }
+        press(&mut app, &mut bank, KeyCode::Esc);
+        assert_eq!(app.sessions.len(), 2);
+        assert_eq!(app.session().unwrap().account_number, OTHER);
+
+        press(&mut app, &mut bank, KeyCode::Up);
+        press(&mut app, &mut bank, KeyCode::Char('x'));
+        assert_eq!(app.session().unwrap().account_number, OTHER);
+
+        app.timeout = Duration::ZERO;
+        app.tick();
+        assert!(app.sessions.is_empty());
+        assert_eq!(app.form.as_ref().unwrap().kind, FormKind::Login);
+        press(&mut app, &mut bank, KeyCode::Esc);
+        assert!(app.quit);
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The `bank` binary on a database file of its own, checked by its JSON
+//! output and exit codes
+
+#![cfg(feature = "cli")]
+
+use banking_system::database::Bank;
+use banking_system::luhn::AccountNumber;
+use banking_system::storage::migrations;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+const ACCOUNT: &str = "8536276945";
+const OTHER: &str = "35001576202";
+const PIN: &str = "591732";
+
+/// A directory of its own for each test, holding the database
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bank-cli-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    fn db(&self) -> PathBuf {
+        self.0.join("bank.s3db")
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A database in `dir` with two accounts of 100
+fn setup(dir: &TestDir) {
+    let mut bank = Bank::open(dir.db()).unwrap();
+    for number in [ACCOUNT, OTHER] {
+        bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+            .unwrap();
+    }
+}
+
+/// `bank` on the database of `dir`, without settings from the environment
+fn command(dir: &TestDir) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_banking-system"));
+    command
+        .current_dir(&dir.0)
+        .env_remove("BANK_CONFIG")
+        .env_remove("BANK_PROFILE")
+        .env_remove("BANK_SOCKET")
+        .env("BANK_DB", dir.db())
+        .args(["--output", "json"]);
+    command
+}
+
+/// Running `bank` on the database of `dir` with `pins` on its standard input
+fn bank(dir: &TestDir, args: &[&str], pins: &str) -> io::Result<(i32, Value)> {
+    let mut child = command(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(pins.as_bytes())?;
+    let output = child.wait_with_output()?;
+    let report = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    Ok((output.status.code().unwrap_or(-1), report))
+}
+
+#[test]
+fn unknown_recipients_exit_like_unknown_accounts() -> io::Result<()> {
+    let dir = TestDir::new("recipient");
+    setup(&dir);
+    let unknown = "1234567830";
+
+    let (code, report) = bank(
+        &dir,
+        &["account", "transfer", ACCOUNT, unknown, "10", "--pin-fd", "0"],
+        "591732\n",
+    )?;
+    assert_eq!((code, &report["error"]), (6, &json!("recipient_not_found")));
+
+    let (code, report) = bank(&dir, &["admin", "set-status", OTHER, "blocked-credit", "--reason", "test"], "")?;
+    assert_eq!(code, 0, "{report}");
+    let (code, report) = bank(
+        &dir,
+        &["account", "transfer", ACCOUNT, OTHER, "10", "--pin-fd", "0"],
+        "591732\n",
+    )?;
+    assert_eq!((code, &report["error"]), (8, &json!("invalid_recipient")));
+    Ok(())
+}
+
+#[test]
+fn migrations_wait_for_other_writers_and_create_nothing() -> io::Result<()> {
+    let dir = TestDir::new("migrate");
+    let (code, report) = bank(&dir, &["admin", "migrate", "--status"], "")?;
+    assert_eq!((code, &report["error"]), (1, &json!("storage")));
+    assert!(!dir.db().exists());
+
+    // An old schema, so the migration needs the write lock
+    let mut writer = rusqlite::Connection::open(dir.db()).unwrap();
+    migrations::migrate(&mut writer, 1).unwrap();
+    writer.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        writer.execute_batch("COMMIT").unwrap();
+    });
+    let (code, report) = bank(&dir, &["admin", "migrate"], "")?;
+    release.join().unwrap();
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["version"], migrations::latest_version());
+    Ok(())
+}
+
+#[test]
+fn piped_pins_are_not_confirmed() -> io::Result<()> {
+    let dir = TestDir::new("piped");
+    setup(&dir);
+    let (code, report) = bank(&dir, &["account", "change-pin", ACCOUNT], "591732\n642853\n")?;
+    assert_eq!(code, 0, "{report}");
+    let (code, report) = bank(&dir, &["account", "create", "--choose-pin"], "735194\n")?;
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["pin"], Value::Null);
+    Ok(())
+}
+
+#[test]
+fn unparsable_environment_settings_are_refused() -> io::Result<()> {
+    let dir = TestDir::new("env");
+    setup(&dir);
+    let output = command(&dir)
+        .env("BANK_LOCKOUT_MINUTES", "ten")
+        .args(["admin", "migrate", "--status"])
+        .output()?;
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(report["message"].as_str().unwrap_or_default().contains("BANK_LOCKOUT_MINUTES"), "{report}");
+    Ok(())
+}
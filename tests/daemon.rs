@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Unlicense
+
+//! A daemon serving an in-memory bank on a socket of its own, called by the
+//! client and by the `bank` binary
+
+#![cfg(all(unix, feature = "cli"))]
+
+use banking_system::daemon::{self, Client, ClientError};
+use banking_system::database::{Auth, Bank};
+use banking_system::luhn::AccountNumber;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+const ACCOUNT: &str = "8536276945";
+const OTHER: &str = "35001576202";
+const PIN: &str = "591732";
+
+/// A directory of its own for each test, holding the socket
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bank-daemon-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.0.join("bank.sock")
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Serving two accounts of 100 on the socket of `dir`
+fn start(dir: &TestDir) {
+    let mut bank = Bank::in_memory();
+    for number in [ACCOUNT, OTHER] {
+        bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+            .unwrap();
+    }
+    let listener = daemon::bind(&dir.socket()).unwrap();
+    std::thread::spawn(move || daemon::serve(bank, listener));
+}
+
+/// Running `bank` in `dir` with `pins` on its standard input. The database
+/// it is given does not exist, so everything has to go through the daemon.
+fn bank(dir: &TestDir, args: &[&str], pins: &str) -> io::Result<(i32, Value)> {
+    let (code, stdout) = bank_text(dir, args, pins)?;
+    Ok((code, serde_json::from_str(&stdout).unwrap_or_default()))
+}
+
+/// Running `bank` like `bank`, with its output as text
+fn bank_text(dir: &TestDir, args: &[&str], pins: &str) -> io::Result<(i32, String)> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_banking-system"))
+        .current_dir(&dir.0)
+        .env_remove("BANK_CONFIG")
+        .env_remove("BANK_PROFILE")
+        .env("BANK_DB", dir.0.join("missing").join("bank.s3db"))
+        .env("BANK_SOCKET", dir.socket())
+        .args(["--output", "json"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(pins.as_bytes())?;
+    let output = child.wait_with_output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    Ok((output.status.code().unwrap_or(-1), stdout))
+}
+
+#[test]
+fn the_client_calls_the_bank() {
+    let dir = TestDir::new("client");
+    start(&dir);
+    let mut client = Client::connect(dir.socket()).unwrap();
+
+    assert_eq!(client.deposit(ACCOUNT, 50, PIN).unwrap(), 150);
+    assert_eq!(client.transfer(ACCOUNT, OTHER, 30, PIN, None).unwrap(), 120);
+    let history = client.history(OTHER, PIN, None, 5).unwrap();
+    assert_eq!(
+        (history[0].amount, history[0].counterparty.as_deref()),
+        (30, Some(ACCOUNT))
+    );
+
+    let created = client.create_account(None).unwrap();
+    assert_eq!(
+        client
+            .check_balance(&created.account, &created.pin.unwrap(), None)
+            .unwrap(),
+        0
+    );
+
+    match client.withdraw(ACCOUNT, 500, PIN).unwrap_err() {
+        ClientError::Refused { error, exit_code, .. } => {
+            assert_eq!((error.as_str(), exit_code), ("insufficient_funds", 5))
+        }
+        e => panic!("not a refusal: {}", e),
+    }
+    // Another connection sees the same bank, and takes tokens for the PIN
+    let mut other = Client::connect(dir.socket()).unwrap();
+    let token = other.issue_token(ACCOUNT, PIN, None).unwrap();
+    assert_eq!(other.check_balance(ACCOUNT, Auth::Token(&token), None).unwrap(), 120);
+}
+
+#[test]
+fn the_command_line_goes_through_the_daemon() -> io::Result<()> {
+    let dir = TestDir::new("cli");
+    start(&dir);
+
+    let (code, report) = bank(
+        &dir,
+        &["account", "deposit", ACCOUNT, "25", "--pin-fd", "0"],
+        "591732\n",
+    )?;
+    assert_eq!(code, 0);
+    assert_eq!(report, json!({"result": "balance", "account": ACCOUNT, "balance": 125}));
+
+    let (code, report) = bank(&dir, &["account", "balance", ACCOUNT, "--pin-fd", "0"], "000000\n")?;
+    assert_eq!((code, &report["error"]), (3, &json!("wrong_pin")));
+
+    let (code, report) = bank(&dir, &["admin", "mark-dormant"], "")?;
+    assert_eq!((code, report), (0, json!({"result": "marked_dormant", "accounts": 0})));
+
+    // The menu runs on the daemon too, with the PIN asked again for withdrawals
+    let script = dir.0.join("session.txt");
+    std::fs::write(&script, "591732\n1\n50\n3\n20\n591732\n0\n5\n")?;
+    let script = script.to_str().unwrap();
+    let (code, transcript) = bank_text(&dir, &["account", "login", ACCOUNT, "--script", script], "")?;
+    assert_eq!(code, 0, "{transcript}");
+    assert!(transcript.contains("balance of `175`"), "{transcript}");
+    assert!(transcript.contains("balance of `155`"), "{transcript}");
+    assert!(transcript.ends_with("Exiting bank machine...\n"), "{transcript}");
+
+    // A restore would swap the database under the daemon
+    let (code, report) = bank(&dir, &["admin", "restore", "backup.s3db"], "")?;
+    assert_eq!(code, 1);
+    assert!(report["message"].as_str().unwrap().contains("daemon owns the database"), "{report}");
+    Ok(())
+}
+
+#[test]
+fn one_daemon_per_socket() {
+    let dir = TestDir::new("bind");
+    start(&dir);
+    let taken = daemon::bind(&dir.socket()).unwrap_err();
+    assert_eq!(taken.kind(), io::ErrorKind::AddrInUse);
+
+    // A socket file left behind by a stopped daemon is taken over
+    let stale = dir.0.join("stale.sock");
+    drop(std::os::unix::net::UnixListener::bind(&stale).unwrap());
+    assert!(daemon::bind(&stale).is_ok());
+
+    let file = dir.0.join("bank.s3db");
+    std::fs::write(&file, "").unwrap();
+    assert!(daemon::bind(&file).is_err());
+    assert!(Path::new(&file).exists());
+}
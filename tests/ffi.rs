@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The C API: its header against src/ffi.rs, and the C example built with
+//! the system compiler and linked to the shared library
+
+#![cfg(all(unix, feature = "ffi"))]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const HEADER: &str = "include/bank.h";
+
+/// The deps directory of this test, where cargo built the shared library
+/// it was linked with
+fn library_dir() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    exe.parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn the_header_is_current() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(root.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(root.join("src/ffi.rs"))
+        .generate()
+        .unwrap()
+        .write(&mut generated);
+
+    let path = root.join(HEADER);
+    if std::env::var_os("UPDATE_HEADER").is_some() {
+        std::fs::write(&path, &generated).unwrap();
+    }
+    let header = std::fs::read(&path).unwrap_or_default();
+    assert!(
+        header == generated,
+        "{} is out of date; run `UPDATE_HEADER=1 cargo test --test ffi`",
+        HEADER
+    );
+}
+
+#[test]
+fn the_c_example_runs() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = std::env::temp_dir().join(format!("bank-ffi-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let libraries = library_dir();
+
+    let teller = dir.join("teller");
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args(["-std=c99", "-Wall", "-Werror", "-I"])
+        .arg(root.join("include"))
+        .arg(root.join("examples/teller.c"))
+        .arg("-o")
+        .arg(&teller)
+        .arg("-L")
+        .arg(&libraries)
+        .arg("-lbanking_system")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(&teller)
+        .arg(dir.join("bank.s3db"))
+        .env("LD_LIBRARY_PATH", &libraries)
+        .env("DYLD_LIBRARY_PATH", &libraries)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("balance after deposit: 150"), "{stdout}");
+    assert!(stdout.contains("balance after transfer: 110"), "{stdout}");
+    assert!(stdout.contains("withdrawal refused: insufficient_funds"), "{stdout}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
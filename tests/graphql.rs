@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The GraphQL endpoint served in-process on a free port, next to the REST
+//! API whose tokens it takes
+
+#![cfg(feature = "graphql")]
+
+use banking_system::database::{self, Bank};
+use banking_system::luhn::AccountNumber;
+use banking_system::rest;
+use serde_json::{json, Value};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const ACCOUNT: &str = "8536276945";
+const OTHER: &str = "35001576202";
+const PIN: &str = "591732";
+const STAFF_KEY: &str = "staff-key-of-the-test-which-is-long-enough";
+
+/// Serving a bank with two accounts of 100 each and `empty` more without
+/// money, until the runtime is dropped
+fn start(empty: usize) -> (tokio::runtime::Runtime, SocketAddr) {
+    let mut bank = Bank::in_memory();
+    for number in [ACCOUNT, OTHER] {
+        bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+            .unwrap();
+    }
+    for _ in 0..empty {
+        database::with_fresh_number(|number| bank.create_account(number, 0)).unwrap();
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = rest::ApiOptions {
+        token_ttl: Duration::from_secs(60),
+        staff_key: Some(STAFF_KEY.to_string()),
+    };
+    let app = rest::router(bank, options);
+    runtime.spawn(async move { axum::serve(listener, app).await });
+    (runtime, addr)
+}
+
+/// The request posting `body` to `path`
+fn request(addr: SocketAddr, path: &str, bearer: Option<&str>, accept: &str, body: &Value) -> String {
+    let body = body.to_string();
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nAccept: {}\r\nContent-Length: {}\r\n",
+        path,
+        addr,
+        accept,
+        body.len()
+    );
+    if let Some(bearer) = bearer {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", bearer));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+    request
+}
+
+/// Sending one request and returning the status and the JSON body
+fn post(addr: SocketAddr, path: &str, bearer: Option<&str>, body: Value) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = request(addr, path, bearer, "application/json", &body);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap_or(Value::Null))
+}
+
+/// Running a GraphQL document and returning the response
+fn graphql(addr: SocketAddr, bearer: Option<&str>, query: &str) -> Value {
+    let (status, body) = post(addr, "/graphql", bearer, json!({ "query": query }));
+    assert_eq!(status, 200, "{body}");
+    body
+}
+
+fn login(addr: SocketAddr, account: &str) -> String {
+    let path = format!("/accounts/{}/tokens", account);
+    let (status, body) = post(addr, &path, None, json!({"pin": PIN}));
+    assert_eq!(status, 201, "{body}");
+    body["token"].as_str().unwrap().to_string()
+}
+
+/// The `error` extension of the first error of a response
+fn refusal(response: &Value) -> &Value {
+    &response["errors"][0]["extensions"]["error"]
+}
+
+#[test]
+fn a_token_reads_and_moves_its_own_account() {
+    let (_runtime, addr) = start(0);
+    let token = login(addr, ACCOUNT);
+    let token = Some(token.as_str());
+
+    let response = graphql(
+        addr,
+        token,
+        &format!(r#"mutation {{ transfer(account: "{ACCOUNT}", to: "{OTHER}", amount: 30) {{ balance }} }}"#),
+    );
+    assert_eq!(response, json!({"data": {"transfer": {"balance": 70}}}));
+    let response = graphql(
+        addr,
+        token,
+        &format!(r#"mutation {{ deposit(account: "{ACCOUNT}", amount: 5) {{ balance }} }}"#),
+    );
+    assert_eq!(response["data"]["deposit"]["balance"], 75);
+
+    let response = graphql(
+        addr,
+        token,
+        &format!(
+            r#"{{ account(number: "{ACCOUNT}") {{
+                number status balance
+                transactions {{ edges {{ node {{ kind amount counterparty }} }} pageInfo {{ hasNextPage }} }}
+            }} }}"#
+        ),
+    );
+    assert_eq!(
+        response["data"]["account"],
+        json!({
+            "number": ACCOUNT,
+            "status": "ACTIVE",
+            "balance": 75,
+            "transactions": {
+                "edges": [
+                    {"node": {"kind": "TRANSFER_OUT", "amount": 30, "counterparty": OTHER}},
+                    {"node": {"kind": "DEPOSIT", "amount": 5, "counterparty": null}},
+                ],
+                "pageInfo": {"hasNextPage": false},
+            },
+        })
+    );
+
+    let response = graphql(
+        addr,
+        token,
+        &format!(
+            r#"{{ account(number: "{ACCOUNT}") {{
+                first: transactions(first: 1) {{ edges {{ node {{ amount }} }} pageInfo {{ hasNextPage }} }}
+                deposits: transactions(kind: DEPOSIT) {{ edges {{ node {{ amount }} }} pageInfo {{ hasNextPage }} }}
+            }} }}"#
+        ),
+    );
+    assert_eq!(
+        response["data"]["account"],
+        json!({
+            "first": {"edges": [{"node": {"amount": 30}}], "pageInfo": {"hasNextPage": true}},
+            "deposits": {"edges": [{"node": {"amount": 5}}], "pageInfo": {"hasNextPage": false}},
+        })
+    );
+
+    let response = graphql(
+        addr,
+        token,
+        &format!(r#"mutation {{ withdraw(account: "{ACCOUNT}", amount: 500) {{ balance }} }}"#),
+    );
+    assert_eq!(refusal(&response), "insufficient_funds");
+}
+
+#[test]
+fn only_the_staff_key_reads_other_accounts() {
+    let (_runtime, addr) = start(0);
+    let token = login(addr, ACCOUNT);
+    let query = format!(r#"{{ account(number: "{OTHER}") {{ balance }} }}"#);
+
+    assert_eq!(refusal(&graphql(addr, Some(&token), &query)), "invalid_token");
+    assert_eq!(refusal(&graphql(addr, None, &query)), "invalid_token");
+    let response = graphql(addr, Some(STAFF_KEY), &query);
+    assert_eq!(response, json!({"data": {"account": {"balance": 100}}}));
+
+    let listing = "{ accounts { edges { node { number } } } }";
+    assert!(graphql(addr, Some(&token), listing)["errors"].is_array());
+
+    // The staff key reads, but moves no money
+    let deposit = format!(r#"mutation {{ deposit(account: "{OTHER}", amount: 5) {{ balance }} }}"#);
+    assert_eq!(refusal(&graphql(addr, Some(STAFF_KEY), &deposit)), "invalid_token");
+}
+
+#[test]
+fn accounts_are_listed_in_pages() {
+    let (_runtime, addr) = start(25);
+    let page = |filter: &str, first: usize, after: Option<&str>| {
+        let after = after.map(|a| format!(r#", after: "{}""#, a)).unwrap_or_default();
+        let query = format!(
+            "{{ accounts({filter} first: {first}{after}) {{ edges {{ cursor node {{ number balance }} }} pageInfo {{ hasNextPage endCursor }} }} }}"
+        );
+        let response = graphql(addr, Some(STAFF_KEY), &query);
+        assert!(response["errors"].is_null(), "{response}");
+        response["data"]["accounts"].clone()
+    };
+
+    let mut numbers = Vec::new();
+    let mut after = None;
+    loop {
+        let accounts = page("", 10, after.as_deref());
+        for edge in accounts["edges"].as_array().unwrap() {
+            numbers.push(edge["node"]["number"].as_str().unwrap().to_string());
+        }
+        if accounts["pageInfo"]["hasNextPage"] == false {
+            break;
+        }
+        after = Some(accounts["pageInfo"]["endCursor"].as_str().unwrap().to_string());
+    }
+    assert_eq!(numbers.len(), 27);
+    assert_eq!(numbers[..2], [ACCOUNT, OTHER]);
+
+    let funded = page("filter: { minBalance: 1 }", 10, None);
+    assert_eq!(
+        funded["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| edge["node"]["number"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        [ACCOUNT, OTHER]
+    );
+    assert_eq!(funded["pageInfo"]["hasNextPage"], false);
+
+    let response = graphql(addr, Some(STAFF_KEY), r#"{ accounts(after: "not a cursor") { edges { cursor } } }"#);
+    assert!(response["errors"].is_array());
+}
+
+#[test]
+fn subscriptions_are_sent_as_events() {
+    let (_runtime, addr) = start(0);
+    let token = login(addr, ACCOUNT);
+    let subscription = json!({
+        "query": format!(r#"subscription {{ balanceChanged(account: "{ACCOUNT}") {{ account balance transactions {{ kind }} }} }}"#)
+    });
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = request(addr, "/graphql", Some(&token), "text/event-stream", &subscription);
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+    // The subscription only reports what is posted after it started, so
+    // deposits go on until one is seen
+    let deposit = format!(r#"mutation {{ deposit(account: "{ACCOUNT}", amount: 1) {{ balance }} }}"#);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut received = String::new();
+    while !received.contains("event: next") {
+        assert!(Instant::now() < deadline, "no event in {received}");
+        graphql(addr, Some(&token), &deposit);
+        let mut buffer = [0; 4096];
+        match stream.read(&mut buffer) {
+            Ok(read) => received.push_str(&String::from_utf8_lossy(&buffer[..read])),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => panic!("{e}"),
+        }
+    }
+    assert!(received.contains("text/event-stream"), "{received}");
+    assert!(received.contains(r#""kind":"DEPOSIT""#), "{received}");
+    assert!(received.contains(&format!(r#""account":"{ACCOUNT}""#)), "{received}");
+}
+
+#[test]
+fn the_schema_is_published() {
+    let (_runtime, addr) = start(0);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!(
+        "GET /graphql/schema.graphql HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    for definition in ["type Query", "type Mutation", "type Subscription", "balanceChanged", "enum PostingKind"] {
+        assert!(response.contains(definition), "{definition} missing");
+    }
+}
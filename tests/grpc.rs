@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The gRPC service served in-process on a free port, called with the
+//! generated client
+
+#![cfg(feature = "grpc")]
+
+use banking_system::database::Bank;
+use banking_system::grpc::proto::bank_service_client::BankServiceClient;
+use banking_system::grpc::proto::*;
+use banking_system::grpc::GrpcBank;
+use banking_system::luhn::AccountNumber;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::StreamExt;
+use tonic::transport::{Channel, Server};
+use tonic::Code;
+
+const ACCOUNT: &str = "8536276945";
+const OTHER: &str = "35001576202";
+const PIN: &str = "591732";
+
+/// A database file of its own for each test, with two accounts of 100
+struct TestDb(PathBuf);
+
+impl TestDb {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bank-grpc-{}-{}.s3db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut bank = Bank::open(&path).unwrap();
+        for number in [ACCOUNT, OTHER] {
+            bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+                .unwrap();
+        }
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.0.display(), suffix));
+        }
+    }
+}
+
+/// Serving the database in the runtime and connecting a client to it
+async fn connect(db: &Path) -> BankServiceClient<Channel> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = GrpcBank::new(Bank::open(db).unwrap()).into_service();
+    tokio::spawn(
+        Server::builder()
+            .add_service(service)
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    BankServiceClient::connect(format!("http://{}", addr)).await.unwrap()
+}
+
+fn run<F: std::future::Future>(test: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(test)
+}
+
+#[test]
+fn calls_move_money() {
+    let db = TestDb::new("calls");
+    run(async {
+        let mut client = connect(db.path()).await;
+        let created = client
+            .create_account(CreateAccountRequest { pin: String::new() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.pin.len(), 6);
+        let opened = GetBalanceRequest {
+            account: created.account,
+            pin: created.pin,
+            code: String::new(),
+        };
+        assert_eq!(client.get_balance(opened).await.unwrap().into_inner().balance, 0);
+
+        let deposit = DepositRequest {
+            account: ACCOUNT.to_string(),
+            amount: 50,
+            pin: PIN.to_string(),
+        };
+        assert_eq!(client.deposit(deposit).await.unwrap().into_inner().balance, 150);
+
+        let withdraw = WithdrawRequest {
+            account: ACCOUNT.to_string(),
+            amount: 500,
+            pin: PIN.to_string(),
+        };
+        let refused = client.withdraw(withdraw).await.unwrap_err();
+        assert_eq!(refused.code(), Code::FailedPrecondition);
+        assert_eq!(refused.metadata().get("bank-error").unwrap(), "insufficient_funds");
+
+        let transfer = TransferRequest {
+            account: ACCOUNT.to_string(),
+            to: OTHER.to_string(),
+            amount: 30,
+            pin: PIN.to_string(),
+            code: String::new(),
+        };
+        assert_eq!(client.transfer(transfer).await.unwrap().into_inner().balance, 120);
+
+        let mut balance = GetBalanceRequest {
+            account: OTHER.to_string(),
+            pin: PIN.to_string(),
+            code: String::new(),
+        };
+        assert_eq!(client.get_balance(balance.clone()).await.unwrap().into_inner().balance, 130);
+        balance.pin = "000000".to_string();
+        assert_eq!(client.get_balance(balance).await.unwrap_err().code(), Code::Unauthenticated);
+    });
+}
+
+#[test]
+fn streams_follow_the_ledger() {
+    let db = TestDb::new("stream");
+    run(async {
+        let mut client = connect(db.path()).await;
+        let request = |after_id| StreamTransactionsRequest {
+            account: ACCOUNT.to_string(),
+            pin: PIN.to_string(),
+            code: String::new(),
+            after_id,
+        };
+        let mut live = client.stream_transactions(request(None)).await.unwrap().into_inner();
+
+        let deposit = DepositRequest {
+            account: ACCOUNT.to_string(),
+            amount: 50,
+            pin: PIN.to_string(),
+        };
+        client.deposit(deposit).await.unwrap();
+        // Another process posting to the same database
+        Bank::open(db.path())
+            .unwrap()
+            .transfer(OTHER, ACCOUNT, 25, PIN, None)
+            .unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), live.next())
+                .await
+                .expect("the posting is streamed")
+                .unwrap()
+                .unwrap();
+            events.push(event);
+        }
+        assert_eq!(events[0].kind(), PostingKind::Deposit);
+        assert_eq!(events[0].amount, 50);
+        assert_eq!(events[1].kind(), PostingKind::TransferIn);
+        assert_eq!(events[1].counterparty, OTHER);
+
+        // Continuing after the first event replays the second
+        let mut resumed = client
+            .stream_transactions(request(Some(events[0].id)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(resumed.next().await.unwrap().unwrap(), events[1]);
+
+        let mut wrong = request(None);
+        wrong.pin = "000000".to_string();
+        assert_eq!(client.stream_transactions(wrong).await.unwrap_err().code(), Code::Unauthenticated);
+    });
+}
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: Unlicense
+
+//! The REST API served in-process on a free port, spoken to over plain HTTP
+
+#![cfg(feature = "rest")]
+
+use banking_system::database::Bank;
+use banking_system::luhn::AccountNumber;
+use banking_system::rest;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::time::Duration;
+
+const ACCOUNT: &str = "8536276945";
+const OTHER: &str = "35001576202";
+const PIN: &str = "591732";
+
+/// Serving a bank with two accounts of 100 each until the runtime is dropped
+fn start() -> (tokio::runtime::Runtime, SocketAddr) {
+    let mut bank = Bank::in_memory();
+    for number in [ACCOUNT, OTHER] {
+        bank.create_account_with_pin(&AccountNumber::from_str(number).unwrap(), 100, PIN)
+            .unwrap();
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = rest::ApiOptions {
+        token_ttl: Duration::from_secs(60),
+        staff_key: None,
+    };
+    let app = rest::router(bank, options);
+    runtime.spawn(async move { axum::serve(listener, app).await });
+    (runtime, addr)
+}
+
+/// Sending one request and returning the status and the JSON body
+fn call(addr: SocketAddr, method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> (u16, Value) {
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap_or(Value::Null))
+}
+
+fn login(addr: SocketAddr, account: &str) -> String {
+    let (status, body) = call(addr, "POST", &format!("/accounts/{}/tokens", account), None, Some(json!({"pin": PIN})));
+    assert_eq!(status, 201, "{body}");
+    body["token"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn money_moves_with_a_token() {
+    let (_runtime, addr) = start();
+    let token = login(addr, ACCOUNT);
+    let token = Some(token.as_str());
+
+    let path = format!("/accounts/{}/deposits", ACCOUNT);
+    let (status, body) = call(addr, "POST", &path, token, Some(json!({"amount": 50})));
+    assert_eq!((status, body), (200, json!({"result": "balance", "account": ACCOUNT, "balance": 150})));
+
+    let path = format!("/accounts/{}/withdrawals", ACCOUNT);
+    let (status, body) = call(addr, "POST", &path, token, Some(json!({"amount": 500})));
+    assert_eq!((status, &body["error"]), (422, &json!("insufficient_funds")));
+
+    let path = format!("/accounts/{}/transfers", ACCOUNT);
+    let (status, body) = call(addr, "POST", &path, token, Some(json!({"to": OTHER, "amount": 30})));
+    assert_eq!((status, &body["balance"]), (200, &json!(120)));
+
+    let (status, body) = call(addr, "GET", &format!("/accounts/{}", ACCOUNT), token, None);
+    assert_eq!(
+        (status, body),
+        (200, json!({"result": "account", "account": ACCOUNT, "status": "active", "balance": 120}))
+    );
+
+    let (status, body) = call(addr, "GET", &format!("/accounts/{}/history?limit=1", ACCOUNT), token, None);
+    assert_eq!(status, 200);
+    assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+    assert_eq!(body["entries"][0]["kind"], "transfer_out");
+    assert_eq!(body["entries"][0]["counterparty"], OTHER);
+
+    let other = login(addr, OTHER);
+    let (_, body) = call(addr, "GET", &format!("/accounts/{}/balance", OTHER), Some(&other), None);
+    assert_eq!(body["balance"], 130);
+}
+
+#[test]
+fn tokens_are_checked() {
+    let (_runtime, addr) = start();
+    let path = format!("/accounts/{}/balance", ACCOUNT);
+    let (status, body) = call(addr, "GET", &path, None, None);
+    assert_eq!((status, &body["error"]), (401, &json!("invalid_token")));
+
+    // A token only opens the account it was issued for
+    let other = login(addr, OTHER);
+    let (status, _) = call(addr, "GET", &path, Some(&other), None);
+    assert_eq!(status, 401);
+    let (status, _) = call(addr, "GET", &path, Some("8536276945.99999999999.00"), None);
+    assert_eq!(status, 401);
+
+    let tokens = format!("/accounts/{}/tokens", ACCOUNT);
+    let (status, body) = call(addr, "POST", &tokens, None, Some(json!({"pin": "000000"})));
+    assert_eq!((status, &body["error"]), (401, &json!("wrong_pin")));
+    let (status, _) = call(addr, "POST", "/accounts/1234567890/tokens", None, Some(json!({"pin": PIN})));
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn accounts_can_be_opened() {
+    let (_runtime, addr) = start();
+    let (status, body) = call(addr, "POST", "/accounts", None, Some(json!({})));
+    assert_eq!(status, 201);
+    let account = body["account"].as_str().unwrap();
+    assert!(banking_system::luhn::verify(account));
+    let pin = body["pin"].as_str().unwrap();
+    let tokens = format!("/accounts/{}/tokens", account);
+    let (status, _) = call(addr, "POST", &tokens, None, Some(json!({"pin": pin})));
+    assert_eq!(status, 201);
+
+    let (status, body) = call(addr, "POST", "/accounts", None, Some(json!({"pin": "111111"})));
+    assert_eq!((status, &body["error"]), (422, &json!("weak_pin")));
+}
+
+#[test]
+fn openapi_lists_every_route() {
+    let (_runtime, addr) = start();
+    let (status, document) = call(addr, "GET", "/openapi.json", None, None);
+    assert_eq!(status, 200);
+    assert_eq!(document, serde_json::from_str::<Value>(rest::OPENAPI).unwrap());
+
+    let mut paths: Vec<&str> = document["paths"].as_object().unwrap().keys().map(String::as_str).collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        [
+            "/accounts",
+            "/accounts/{account}",
+            "/accounts/{account}/balance",
+            "/accounts/{account}/deposits",
+            "/accounts/{account}/history",
+            "/accounts/{account}/tokens",
+            "/accounts/{account}/transfers",
+            "/accounts/{account}/withdrawals",
+            "/openapi.json",
+        ]
+    );
+}